@@ -0,0 +1,124 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Measures the throughput of downloads and uploads over loopback for a range
+//! of block sizes, with the server running in the same process:
+//!
+//! ```bash
+//! $ cargo run --release --example bench [SIZE_IN_MB]
+//! ```
+//!
+//! Every transfer is checked to arrive unchanged. The windowsize option
+//! (RFC 7440) is not implemented, so each block waits for its ACK and the
+//! window is always 1.
+//!
+//! Afterwards, the heap allocations of client and server for a download of
+//! a single block are counted, along with those of cloning the configuration,
+//! which each request cost before the workers shared it.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::UdpSocket;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rtftp::server::{ConfigurationBuilder, Tftpd};
+
+const BLKSIZES: [usize; 5] = [512, 1428, 4096, 8192, 65464];
+const WINDOWSIZE: usize = 1;
+const SMALL_REQUESTS: u64 = 1000;
+
+/// Counts the allocations of all threads.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns the number of allocations made while running `f`.
+fn allocations(f: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    f();
+    ALLOCATIONS.load(Ordering::SeqCst) - before
+}
+
+fn main() {
+    let size_mb: usize = match env::args().nth(1).map(|arg| arg.parse()) {
+        None => 16,
+        Some(Ok(size)) if size > 0 => size,
+        Some(_) => {
+            eprintln!("Usage: bench [SIZE_IN_MB]");
+            process::exit(2);
+        }
+    };
+
+    let dir = env::temp_dir().join(format!("rtftp-bench-{}", process::id()));
+    fs::create_dir_all(&dir).expect("creating the served directory failed");
+    /* not compressible and not all zeros */
+    let data: Vec<u8> = (0..size_mb * 1024 * 1024).map(|i| (i * 7 + i / 251) as u8).collect();
+    fs::write(dir.join("bench.bin"), &data).expect("writing the test file failed");
+    fs::write(dir.join("small.bin"), &data[..100]).expect("writing the test file failed");
+
+    let conf = ConfigurationBuilder::new().dir(&dir).quiet(true).build().expect("invalid configuration");
+    let cloned = allocations(|| drop(conf.clone()));
+    let socket = UdpSocket::bind("[::1]:0").expect("binding failed");
+    let server = socket.local_addr().unwrap();
+    let mut tftpd = Tftpd::new(conf);
+    thread::spawn(move || tftpd.serve(socket));
+
+    println!("{} MB per transfer", size_mb);
+    println!("{:>8} {:>10} {:>9} {:>9}", "blksize", "windowsize", "get MB/s", "put MB/s");
+    for blksize in BLKSIZES {
+        let mut options = HashMap::new();
+        options.insert(String::from("blksize"), blksize.to_string());
+
+        let start = Instant::now();
+        let mut received = Vec::with_capacity(data.len());
+        rtftp::Tftp::new().get(server, "bench.bin", &mut received, &options).expect("download failed");
+        let get = start.elapsed();
+        assert!(received == data, "download with blksize {} differs", blksize);
+
+        let name = format!("upload-{}.bin", blksize);
+        let start = Instant::now();
+        rtftp::Tftp::new().put(server, &name, &data[..], &options).expect("upload failed");
+        let put = start.elapsed();
+        assert!(fs::read(dir.join(&name)).unwrap() == data, "upload with blksize {} differs", blksize);
+
+        let rate = |secs: f64| size_mb as f64 / secs;
+        println!("{:>8} {:>10} {:>9.1} {:>9.1}", blksize, WINDOWSIZE, rate(get.as_secs_f64()), rate(put.as_secs_f64()));
+    }
+
+    let get_small = || {
+        let mut received = Vec::new();
+        rtftp::Tftp::new().get(server, "small.bin", &mut received, &HashMap::new()).expect("download failed");
+    };
+    let small = allocations(|| {
+        for _ in 0..SMALL_REQUESTS {
+            get_small();
+        }
+        /* the server may still be finishing the last request */
+        thread::sleep(Duration::from_millis(100));
+    });
+    println!("{:.1} allocations per download of one block (client and server)", small as f64 / SMALL_REQUESTS as f64);
+    println!("{} allocations for cloning the configuration", cloned);
+
+    fs::remove_dir_all(&dir).expect("removing the served directory failed");
+}
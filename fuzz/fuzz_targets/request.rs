@@ -0,0 +1,44 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Feeds arbitrary datagrams to the request handling of the server, as if
+//! they were sent by a client on the loopback interface that is already gone.
+
+#![no_main]
+
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use rtftp::packet;
+use rtftp::server::{Configuration, ConfigurationBuilder, Tftpd};
+
+/// Serves a directory with one file, giving up on clients at once. Uploads
+/// are refused, so that the fuzzed names don't pile up in the directory.
+fn configuration() -> &'static Configuration {
+    static CONF: OnceLock<Configuration> = OnceLock::new();
+    CONF.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("rtftp-fuzz-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"content").unwrap();
+        ConfigurationBuilder::new().dir(&dir).read_only(true).handshake_timeout(Duration::from_millis(1))
+                                   .build().unwrap()
+    })
+}
+
+/// An address nothing listens on, so the replies of the server are refused.
+fn client() -> SocketAddr {
+    static CLIENT: OnceLock<SocketAddr> = OnceLock::new();
+    *CLIENT.get_or_init(|| UdpSocket::bind("[::1]:0").unwrap().local_addr().unwrap())
+}
+
+fuzz_target!(|data: &[u8]| {
+    /* also covers the other packets a client could send */
+    let _ = packet::parse_packet(data);
+
+    let _ = Tftpd::new(configuration().clone()).handle_client(&client(), data);
+});
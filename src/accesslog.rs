@@ -0,0 +1,148 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Machine-readable log of transfers, see `--access-log`.
+//!
+//! Every transfer is appended as one JSON object per line, with the fields
+//! `timestamp` (UTC, RFC 3339), `client`, `port`, `direction` (`read` or
+//! `write`), `filename`, `bytes`, `duration_ms`, `result` (`ok` or `error`),
+//! `error_code` (TFTP error code sent to the client, or `null`) and `error`
+//! (message or `null`).
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) struct Record<'a> {
+    pub(crate) time: SystemTime,
+    pub(crate) client: SocketAddr,
+    pub(crate) direction: &'static str,
+    pub(crate) filename: &'a str,
+    pub(crate) bytes: u64,
+    pub(crate) duration: Duration,
+    pub(crate) error_code: Option<u16>,
+    pub(crate) error: Option<String>,
+}
+
+pub(crate) struct AccessLog {
+    file: Mutex<File>,
+}
+
+fn json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Formats `time` as UTC timestamp with millisecond precision.
+pub(crate) fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+
+    /* civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html */
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day,
+            rem / 3600, rem / 60 % 60, rem % 60, since_epoch.subsec_millis())
+}
+
+impl Record<'_> {
+    fn to_json(&self) -> String {
+        let mut out = String::from("{\"timestamp\":");
+        json_string(&mut out, &timestamp(self.time));
+        out.push_str(",\"client\":");
+        json_string(&mut out, &self.client.ip().to_string());
+        let _ = write!(out, ",\"port\":{},\"direction\":", self.client.port());
+        json_string(&mut out, self.direction);
+        out.push_str(",\"filename\":");
+        json_string(&mut out, self.filename);
+        let _ = write!(out, ",\"bytes\":{},\"duration_ms\":{},\"result\":", self.bytes, self.duration.as_millis());
+        json_string(&mut out, if self.error.is_none() { "ok" } else { "error" });
+        match self.error_code {
+            Some(code) => { let _ = write!(out, ",\"error_code\":{}", code); }
+            None => out.push_str(",\"error_code\":null"),
+        }
+        out.push_str(",\"error\":");
+        match &self.error {
+            Some(error) => json_string(&mut out, error),
+            None => out.push_str("null"),
+        }
+        out.push('}');
+        out
+    }
+}
+
+impl AccessLog {
+    pub(crate) fn open(path: &Path) -> Result<AccessLog, io::Error> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AccessLog { file: Mutex::new(file) })
+    }
+
+    pub(crate) fn record(&self, record: &Record) -> Result<(), io::Error> {
+        let mut line = record.to_json();
+        line.push('\n');
+        /* one write per record, so lines of concurrent transfers don't mix */
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_millis(951_782_400_123)), "2000-02-29T00:00:00.123Z");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(1_791_979_199)), "2026-10-14T11:59:59.000Z");
+    }
+
+    #[test]
+    fn test_record() {
+        let mut record = Record {
+            time: UNIX_EPOCH,
+            client: "[::1]:1234".parse().unwrap(),
+            direction: "read",
+            filename: "pxe/\"boot\".bin\n",
+            bytes: 1024,
+            duration: Duration::from_millis(1500),
+            error_code: None,
+            error: None,
+        };
+        assert_eq!(record.to_json(),
+                   "{\"timestamp\":\"1970-01-01T00:00:00.000Z\",\"client\":\"::1\",\"port\":1234,\"direction\":\"read\",\
+                    \"filename\":\"pxe/\\\"boot\\\".bin\\n\",\"bytes\":1024,\"duration_ms\":1500,\"result\":\"ok\",\
+                    \"error_code\":null,\"error\":null}");
+
+        record.error_code = Some(1);
+        record.error = Some(String::from("not found"));
+        assert!(record.to_json().ends_with(",\"result\":\"error\",\"error_code\":1,\"error\":\"not found\"}"));
+    }
+}
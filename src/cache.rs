@@ -0,0 +1,121 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Cache for the contents of served files, see `--cache-size`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+struct Entry {
+    modified: SystemTime,
+    data: Arc<[u8]>,
+    /* value of the clock on last access */
+    used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    size: usize,
+    clock: u64,
+}
+
+/// Least recently used cache of file contents, holding up to `capacity` bytes.
+/// Entries are only valid as long as mtime and size of the file are unchanged.
+pub(crate) struct FileCache {
+    capacity: usize,
+    state: Mutex<State>,
+}
+
+impl FileCache {
+    pub(crate) fn new(capacity: usize) -> FileCache {
+        FileCache {
+            capacity,
+            state: Default::default(),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(crate) fn get(&self, path: &Path, modified: SystemTime, len: u64) -> Option<Arc<[u8]>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let entry = state.entries.get_mut(path)?;
+        if entry.modified != modified || entry.data.len() as u64 != len {
+            /* outdated; replaced by the following insert */
+            return None;
+        }
+        entry.used = clock;
+        Some(Arc::clone(&entry.data))
+    }
+
+    pub(crate) fn insert(&self, path: &Path, modified: SystemTime, data: Arc<[u8]>) {
+        if data.len() > self.capacity {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.entries.remove(path) {
+            state.size -= old.data.len();
+        }
+        while state.size + data.len() > self.capacity {
+            let oldest = state.entries.iter().min_by_key(|(_, entry)| entry.used).map(|(path, _)| path.clone());
+            match oldest.and_then(|path| state.entries.remove(&path)) {
+                Some(entry) => state.size -= entry.data.len(),
+                None => break,
+            }
+        }
+
+        state.clock += 1;
+        state.size += data.len();
+        let used = state.clock;
+        state.entries.insert(path.to_path_buf(), Entry { modified, data, used });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_cache() {
+        let cache = FileCache::new(10);
+        let time = SystemTime::UNIX_EPOCH;
+        let data = |len| -> Arc<[u8]> { vec![0; len].into() };
+
+        cache.insert(Path::new("a"), time, data(4));
+        cache.insert(Path::new("b"), time, data(4));
+        assert!(cache.get(Path::new("a"), time, 4).is_some());
+        assert!(cache.get(Path::new("c"), time, 4).is_none());
+
+        /* evicts b, which was used least recently */
+        cache.insert(Path::new("c"), time, data(4));
+        assert!(cache.get(Path::new("b"), time, 4).is_none());
+        assert!(cache.get(Path::new("a"), time, 4).is_some());
+        assert!(cache.get(Path::new("c"), time, 4).is_some());
+
+        /* changed files are not served from the cache */
+        assert!(cache.get(Path::new("a"), time + Duration::from_secs(1), 4).is_none());
+        assert!(cache.get(Path::new("a"), time, 5).is_none());
+
+        /* too large to cache at all */
+        cache.insert(Path::new("d"), time, data(11));
+        assert!(cache.get(Path::new("d"), time, 11).is_none());
+        assert!(cache.get(Path::new("c"), time, 4).is_some());
+
+        /* replacing an entry frees its space */
+        cache.insert(Path::new("c"), time, data(6));
+        assert!(cache.get(Path::new("c"), time, 6).is_some());
+        assert!(cache.get(Path::new("a"), time, 4).is_some());
+        assert_eq!(cache.state.lock().unwrap().size, 10);
+    }
+}
@@ -0,0 +1,157 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Commands for a running server over a Unix socket, see `--control-socket`.
+//!
+//! A client sends one command per connection and gets a one-line reply:
+//! `drain` stops accepting new transfers, `resume` accepts them again and
+//! `status` reports whether requests are accepted, along with the counters.
+
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use nix::libc;
+
+use crate::metrics::Metrics;
+
+/// The bound control socket, removed from the filesystem when dropped.
+#[derive(Debug)]
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    /* still reachable after changing the working directory or root */
+    dir: File,
+    name: CString,
+}
+
+impl ControlSocket {
+    /// Binds the control socket at `path`. A leftover socket of a server that is no
+    /// longer running is replaced; one still in use makes this fail.
+    pub(crate) fn bind(path: &Path) -> Result<ControlSocket, io::Error> {
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?,
+            _ => File::open(".")?,
+        };
+        let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+        let name = CString::new(name.as_bytes())?;
+        let listener = match UnixListener::bind(path) {
+            Err(ref err) if err.kind() == io::ErrorKind::AddrInUse && UnixStream::connect(path).is_err() => {
+                fs::remove_file(path)?;
+                UnixListener::bind(path)
+            }
+            result => result,
+        }?;
+        Ok(ControlSocket { listener, dir, name })
+    }
+
+    /// Answers the commands sent to the socket in a background thread, each
+    /// connection in a thread of its own.
+    pub(crate) fn serve(&self, draining: Arc<AtomicBool>, metrics: Arc<Metrics>) -> Result<(), io::Error> {
+        let listener = self.listener.try_clone()?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let draining = Arc::clone(&draining);
+                        let metrics = Arc::clone(&metrics);
+                        thread::spawn(move || match respond(stream, &draining, &metrics) {
+                            Ok(command) if command == "drain" || command == "resume" => println!("Control command: {}", command),
+                            Ok(_) => {}
+                            Err(err) => println!("Answering control command failed: {}", err),
+                        });
+                    }
+                    Err(err) => println!("Accepting control connection failed: {}", err),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        unsafe { libc::unlinkat(self.dir.as_raw_fd(), self.name.as_ptr(), 0) };
+    }
+}
+
+fn execute(command: &str, draining: &AtomicBool, metrics: &Metrics) -> String {
+    match command {
+        "drain" => {
+            draining.store(true, Ordering::SeqCst);
+            String::from("ok draining")
+        }
+        "resume" => {
+            draining.store(false, Ordering::SeqCst);
+            String::from("ok accepting")
+        }
+        "status" => {
+            let state = if draining.load(Ordering::SeqCst) { "draining" } else { "accepting" };
+            format!("{} {}", state, metrics.summary())
+        }
+        _ => format!("error unknown command {:?}", command),
+    }
+}
+
+fn respond(stream: UnixStream, draining: &AtomicBool, metrics: &Metrics) -> Result<String, io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut command = String::new();
+    BufReader::new(&stream).take(1024).read_line(&mut command)?;
+    let command = command.trim();
+    writeln!(&stream, "{}", execute(command, draining, metrics))?;
+    Ok(command.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_serve() {
+        let path = env::temp_dir().join(format!("rtftp-control-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        /* left behind by a server that is gone */
+        drop(UnixListener::bind(&path).unwrap());
+        let control = ControlSocket::bind(&path).unwrap();
+        assert_eq!(ControlSocket::bind(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+        let draining = Arc::new(AtomicBool::new(false));
+        let metrics = Arc::new(Metrics::default());
+        metrics.sent(42);
+        control.serve(Arc::clone(&draining), Arc::clone(&metrics)).unwrap();
+
+        let command = |command: &str| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(command.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        assert_eq!(command("drain\n"), "ok draining\n");
+        assert!(draining.load(Ordering::SeqCst));
+        assert_eq!(command("status\n"), format!("draining {}\n", metrics.summary()));
+        assert_eq!(command("resume\n"), "ok accepting\n");
+        assert!(!draining.load(Ordering::SeqCst));
+        assert_eq!(command("stop\n"), "error unknown command \"stop\"\n");
+
+        /* a client that sends nothing does not hold up the others */
+        let idle = UnixStream::connect(&path).unwrap();
+        assert_eq!(command("status\n"), format!("accepting {}\n", metrics.summary()));
+        drop(idle);
+
+        drop(control);
+        assert!(!path.exists());
+    }
+}
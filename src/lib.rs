@@ -3,22 +3,83 @@
  * License: GPL-3+
  */
 
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::File;
-use std::io::{self, BufReader};
+use std::io::{self, BufReader, BufWriter};
 use std::io::prelude::*;
 use std::net::{SocketAddr, UdpSocket};
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "server")]
+mod accesslog;
+#[cfg(feature = "server")]
+mod cache;
+#[cfg(feature = "server")]
+mod control;
+#[cfg(feature = "server")]
+mod logformat;
+#[cfg(feature = "server")]
+mod manifest;
+#[cfg(feature = "server")]
+mod metrics;
+pub mod packet;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod storage;
+mod throttle;
+
+pub use throttle::Throttle;
 
 pub static VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
 
+/// Options that can be negotiated (RFC 2347), see `Tftp::init_tftp_options`.
+pub static SUPPORTED_OPTIONS: &[&str] = &["blksize", "blksize2", "timeout", "utimeout", "tsize", "rollover"];
+
+/* file I/O is buffered in chunks of this many blocks */
+const IO_BUFFER_BLOCKS: usize = 16;
+
 /// * `cur` - current number of bytes
 /// * `total` - total number of bytes; 0 if unknown
 /// * `state` - state that was returned in previous call
+///
 /// Returns state that should get passed in next invocation
-type ProgressCallback = Box<dyn Fn(u64, u64, u64) -> u64>;
+type ProgressCallback = fn(u64, u64, u64) -> u64;
+
+/// The connection to the peer of a transfer, with the semantics of a
+/// connected UDP socket.
+///
+/// Implemented for `UdpSocket`; other implementations can for example
+/// run several transfers over one shared socket.
+pub trait Transport {
+    fn send(&self, buf: &[u8]) -> Result<usize, io::Error>;
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error>;
+}
+
+impl Transport for UdpSocket {
+    fn send(&self, buf: &[u8]) -> Result<usize, io::Error> {
+        UdpSocket::send(self, buf)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        UdpSocket::recv(self, buf)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        UdpSocket::set_read_timeout(self, timeout)
+    }
+}
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u16)]
 pub enum Opcode {
     RRQ   = 0x01,
@@ -29,6 +90,36 @@ pub enum Opcode {
     OACK  = 0x06,
 }
 
+impl TryFrom<u16> for Opcode {
+    type Error = io::Error;
+
+    fn try_from(opcode: u16) -> Result<Self, Self::Error> {
+        match opcode {
+            0x01 => Ok(Opcode::RRQ),
+            0x02 => Ok(Opcode::WRQ),
+            0x03 => Ok(Opcode::DATA),
+            0x04 => Ok(Opcode::ACK),
+            0x05 => Ok(Opcode::ERROR),
+            0x06 => Ok(Opcode::OACK),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown opcode {}", opcode))),
+        }
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Opcode::RRQ => "RRQ",
+            Opcode::WRQ => "WRQ",
+            Opcode::DATA => "DATA",
+            Opcode::ACK => "ACK",
+            Opcode::ERROR => "ERROR",
+            Opcode::OACK => "OACK",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Clone, Copy, Default)]
 #[repr(u8)]
 pub enum Mode {
@@ -37,11 +128,105 @@ pub enum Mode {
     NETASCII,
 }
 
+/// Answer of the peer to a sent packet.
+enum Reply {
+    /// the expected ACK
+    Ack,
+    /// nothing yet, or a packet to be ignored (e.g. a duplicate ACK)
+    Other,
+    /// an ERROR packet, ending the transfer
+    Error(PeerError),
+}
+
+/// An ERROR packet received from the peer, which ended the transfer. It is
+/// the inner error of the `io::Error` returned by the transfer; the error kind
+/// corresponds to the error code. An ERROR must not be answered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerError {
+    pub code: u16,
+    pub message: String,
+    /// it answered an OACK: the peer refused the negotiated options
+    pub options_rejected: bool,
+}
+
+impl PeerError {
+    /// Decodes an ERROR packet; `None` if `buf` is not a valid one.
+    pub fn parse(buf: &[u8]) -> Option<PeerError> {
+        match packet::parse_packet(buf) {
+            Ok(packet::Packet::Error { code, message }) => Some(PeerError { code, message, options_rejected: false }),
+            _ => None,
+        }
+    }
+
+    /// Returns the ERROR of the peer that ended a transfer with `err`, if any.
+    pub fn of(err: &io::Error) -> Option<&PeerError> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.options_rejected {
+            write!(f, "client rejected the negotiated options: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for PeerError {}
+
+/// A transfer stopped through its cancellation token (see
+/// [`Tftp::set_cancel_token`]), after telling the peer with an ERROR. It is the
+/// inner error of the `io::Error` of kind `ConnectionAborted` returned then.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl Cancelled {
+    /// Returns the cancellation that ended a transfer with `err`, if any.
+    pub fn of(err: &io::Error) -> Option<&Cancelled> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transfer cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Returns whether the transfer ended with `err` already exchanged an ERROR
+/// with the peer, which must not be followed by another one.
+pub(crate) fn error_exchanged(err: &io::Error) -> bool {
+    PeerError::of(err).is_some() || Cancelled::of(err).is_some()
+}
+
+impl From<PeerError> for io::Error {
+    fn from(err: PeerError) -> io::Error {
+        let kind = match err.code {
+            1 => std::io::ErrorKind::NotFound,
+            2 => std::io::ErrorKind::PermissionDenied,
+            3 => std::io::ErrorKind::UnexpectedEof,
+            4 => std::io::ErrorKind::InvalidData,
+            5 => std::io::ErrorKind::InvalidInput,
+            6 => std::io::ErrorKind::AlreadyExists,
+            7 => std::io::ErrorKind::NotFound,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct TftpOptions {
     blksize: usize,
     timeout: Duration,
     tsize: u64,
+    /* block number following 65535 */
+    rollover: u16,
+    /* where a resumed download starts (non-standard, acknowledged by rtftpd --allow-offset) */
+    offset: u64,
 }
 
 impl Default for TftpOptions {
@@ -50,15 +235,35 @@ impl Default for TftpOptions {
             blksize: 512,
             timeout: Duration::from_secs(3),
             tsize: 0,
+            rollover: 0,
+            offset: 0,
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct Tftp {
     options: TftpOptions,
     mode: Mode,
     progress_cb: Option<ProgressCallback>,
+    transfer_timeout: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    /* the read timeout is still the handshake timeout */
+    in_handshake: Cell<bool>,
+    /* factor by which the timeout grows with each retransmission, and its limit */
+    backoff_multiplier: u32,
+    max_backoff: Option<Duration>,
+    /* the read timeout was increased since the last progress */
+    backed_off: Cell<bool>,
+    throttle: Option<Arc<Throttle>>,
+    /* code of the last ERROR packet sent */
+    last_error: Cell<Option<u16>>,
+    /* OACK of a WRQ, which takes the place of the ACK of block 0 */
+    oack: RefCell<Option<Vec<u8>>>,
+    /* set from elsewhere to stop the transfer */
+    cancel: Option<Arc<AtomicBool>>,
+    /* assumed for requests without a usable mode */
+    default_mode: Option<Mode>,
 }
 
 fn netascii_to_octet(buf: &[u8], previous_cr: bool) -> (Vec<u8>, bool) {
@@ -96,6 +301,9 @@ fn octet_to_netascii(buf: &[u8]) -> Vec<u8> {
     out
 }
 
+/* largest file size (off_t) */
+const MAX_TSIZE: u64 = i64::MAX as u64;
+
 fn blksize2(size: usize) -> usize {
     (size + 1).next_power_of_two() >> 1
 }
@@ -107,12 +315,21 @@ impl Tftp {
     }
 
     pub fn transfersize(&self, file: &mut File) -> Result<u64, io::Error> {
+        let len = file.metadata()?.len();
+        self.transfersize_of(file, len)
+    }
+
+    /// Returns the transfer size of the next `len` bytes of `file`, the
+    /// part sent by [`send_file_part`](Tftp::send_file_part). Leaves `file`
+    /// at its current position.
+    pub fn transfersize_of<F: Read + Seek + ?Sized>(&self, file: &mut F, len: u64) -> Result<u64, io::Error> {
         match self.mode {
-            Mode::OCTET => return Ok(file.metadata().expect("failed to get metadata").len()),
+            Mode::OCTET => return Ok(len),
             Mode::NETASCII => {},
         }
 
-        let mut reader = BufReader::new(file);
+        let start = file.stream_position()?;
+        let mut reader = BufReader::new((&mut *file).take(len));
         let mut total_size = 0;
         loop {
             let mut buf = [0; 4096];
@@ -128,7 +345,7 @@ impl Tftp {
                 .filter(|&x| *x == b'\r' || *x == b'\n')
                 .count() as u64;
         }
-        reader.seek(io::SeekFrom::Start(0))?;
+        file.seek(io::SeekFrom::Start(start))?;
 
         Ok(total_size)
     }
@@ -137,17 +354,6 @@ impl Tftp {
         self.mode = mode;
     }
 
-    fn get_tftp_str(&self, buf: &[u8]) -> Option<String> {
-        /* make sure the null-terminator exists */
-        buf.iter().find(|&x| *x == 0)?;
-
-        /* build string from buffer */
-        String::from_utf8(buf.iter()
-                             .take_while(|&x| *x != 0)
-                             .cloned()
-                             .collect()).ok()
-    }
-
     /// Read::read can possibly return less bytes than the requested buffer size,
     /// which can for example be observed when using a BufReader.
     /// Read::read_exact does not work well with EOF, as the content of the buffer
@@ -186,59 +392,124 @@ impl Tftp {
         self.progress_cb = Some(cb);
     }
 
-    fn transfer_size(&self, file: &File) -> u64 {
-        match file.metadata() {
-            Ok(ref m) if m.len() > 0 => m.len(),
-            _ => self.options.tsize,
+    /// Limits the total duration of a transfer, independent of the timeout
+    /// for single packets, so a peer responding just in time to each packet
+    /// can't keep it running indefinitely.
+    pub fn set_transfer_timeout(&mut self, timeout: Option<Duration>) {
+        self.transfer_timeout = timeout;
+    }
+
+    /// Uses `timeout` instead of the negotiated one while waiting for the first
+    /// answer of the peer (the ACK of the OACK or first block, or the first
+    /// block of an upload), so peers that vanish after the request are given up
+    /// on early.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Multiplies the time waited for the peer by `multiplier` with each
+    /// retransmission of a packet, up to `max` (if given), so congested links
+    /// are not flooded with retransmissions. A multiplier of 1 keeps the
+    /// timeout fixed.
+    pub fn set_backoff(&mut self, multiplier: u32, max: Option<Duration>) {
+        self.backoff_multiplier = multiplier;
+        self.max_backoff = max;
+    }
+
+    /// Switches to the negotiated timeout once the peer answered, also
+    /// undoing any backoff of previous retransmissions.
+    fn handshake_done(&self, sock: &dyn Transport) -> Result<(), io::Error> {
+        if self.in_handshake.replace(false) | self.backed_off.replace(false) {
+            sock.set_read_timeout(Some(self.options.timeout))?;
         }
+        Ok(())
     }
 
-    pub fn append_option(&self, buf: &mut Vec<u8>, key: &str, val: &str) {
-        buf.extend(key.bytes());
-        buf.push(0x00);
-        buf.extend(val.bytes());
-        buf.push(0x00);
+    /// Sets the read timeout for the given attempt at getting an answer
+    /// (0 for the first one), growing it with the backoff multiplier.
+    fn back_off(&self, sock: &dyn Transport, attempt: u32) -> Result<(), io::Error> {
+        if self.backoff_multiplier <= 1 || attempt == 0 {
+            return Ok(());
+        }
+        let base = match self.in_handshake.get() {
+            true => self.handshake_timeout.unwrap_or(self.options.timeout),
+            false => self.options.timeout,
+        };
+        let timeout = base.saturating_mul(self.backoff_multiplier.saturating_pow(attempt));
+        let timeout = self.max_backoff.map_or(timeout, |max| timeout.min(max)).max(base);
+        self.backed_off.set(true);
+        sock.set_read_timeout(Some(timeout))
     }
 
-    pub fn parse_error(&self, buf: &[u8]) -> std::io::Error {
-        let mut kind = std::io::ErrorKind::InvalidData;
-        let mut error = String::from("Invalid packet received");
+    /// Makes [`parse_file_mode_options`](Tftp::parse_file_mode_options) assume
+    /// `mode` for requests whose mode is missing, empty or not valid UTF-8,
+    /// as sent by some minimal clients, instead of rejecting them.
+    pub fn set_default_mode(&mut self, mode: Option<Mode>) {
+        self.default_mode = mode;
+    }
 
-        if buf.len() < 5 {
-            return std::io::Error::new(kind, error);
+    /// Limits the bandwidth of transfers; the throttle can be shared with other transfers.
+    pub fn set_throttle(&mut self, throttle: Option<Arc<Throttle>>) {
+        self.throttle = throttle;
+    }
+
+    /// Checks `token` before each block of a transfer; once it is set, the
+    /// transfer is stopped with an ERROR to the peer and fails with
+    /// [`Cancelled`]. The token can be shared with another thread.
+    pub fn set_cancel_token(&mut self, token: Option<Arc<AtomicBool>>) {
+        self.cancel = token;
+    }
+
+    fn check_cancelled(&self, sock: &dyn Transport) -> Result<(), io::Error> {
+        match &self.cancel {
+            Some(token) if token.load(Ordering::SeqCst) => {
+                self.send_error(sock, 0, "Transfer cancelled")?;
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, Cancelled))
+            }
+            _ => Ok(()),
         }
+    }
 
-        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
-        if opcode != Opcode::ERROR as u16 {
-            return std::io::Error::new(kind, error);
+    /// Returns the code of the last error sent to the peer, if any.
+    pub fn last_error(&self) -> Option<u16> {
+        self.last_error.get()
+    }
+
+    fn check_deadline(&self, start: Instant) -> Result<(), io::Error> {
+        match self.transfer_timeout {
+            Some(timeout) if start.elapsed() > timeout => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timeout"))
+            }
+            _ => Ok(()),
         }
+    }
 
-        let errorcode = u16::from_be_bytes([buf[2], buf[3]]);
-        error = match String::from_utf8(buf[4..].to_vec()) {
-            Ok(e) => e,
-            Err(_) => return std::io::Error::new(kind, error),
-        };
+    fn transfer_size(&self, file: &File) -> u64 {
+        match file.metadata() {
+            Ok(ref m) if m.len() > 0 => m.len(),
+            _ => self.options.tsize,
+        }
+    }
 
-        kind = match errorcode {
-            1 => std::io::ErrorKind::NotFound,
-            2 => std::io::ErrorKind::PermissionDenied,
-            3 => std::io::ErrorKind::UnexpectedEof,
-            4 => std::io::ErrorKind::InvalidData,
-            5 => std::io::ErrorKind::InvalidInput,
-            6 => std::io::ErrorKind::AlreadyExists,
-            7 => std::io::ErrorKind::NotFound,
-            _ => std::io::ErrorKind::InvalidData,
-        };
+    pub fn append_option(&self, buf: &mut Vec<u8>, key: &str, val: &str) {
+        packet::append_str(buf, key);
+        packet::append_str(buf, val);
+    }
 
-        std::io::Error::new(kind, error)
+    /// Converts an ERROR packet into an error with a [`PeerError`] inside.
+    pub fn parse_error(&self, buf: &[u8]) -> std::io::Error {
+        match PeerError::parse(buf) {
+            Some(err) => err.into(),
+            None => io::Error::new(io::ErrorKind::InvalidData, "Invalid packet received"),
+        }
     }
 
-    fn wait_for_ack(&self, sock: &UdpSocket, expected_block: u16) -> Result<bool, io::Error> {
+    fn wait_for_ack(&self, sock: &dyn Transport, expected_block: u16) -> Result<Reply, io::Error> {
         let mut buf = [0; 512];
         let len = match sock.recv(&mut buf) {
             Ok(l) => l,
             Err(ref error) if [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut].contains(&error.kind()) => {
-                return Ok(false);
+                return Ok(Reply::Other);
             }
             Err(err) => return Err(err),
         };
@@ -251,15 +522,40 @@ impl Tftp {
         let block_nr = u16::from_be_bytes([buf[2], buf[3]]);
 
         if opcode == Opcode::ACK as u16 && block_nr == expected_block {
-            return Ok(true);
+            return Ok(Reply::Ack);
         } else if opcode == Opcode::ERROR as u16 {
-            return Err(self.parse_error(&buf[4..]));
+            return match PeerError::parse(&buf[..len]) {
+                Some(err) => Ok(Reply::Error(err)),
+                None => Err(self.parse_error(&buf[..len])),
+            };
         }
 
-        Ok(false)
+        Ok(Reply::Other)
+    }
+
+    /// Answers a requested tsize option of a RRQ with the size of the file to be
+    /// sent. When the size is not known in advance (e.g. for a stream), the
+    /// option is removed, so it is left out of the OACK instead of echoing the
+    /// requested value.
+    pub fn set_transfer_size(&self, options: &mut HashMap<String, String>, size: Option<u64>) {
+        match size {
+            Some(size) => {
+                if let Some(opt) = options.get_mut("tsize") {
+                    *opt = size.to_string();
+                }
+            }
+            None => {
+                options.remove("tsize");
+            }
+        }
     }
 
-    pub fn ack_options(&self, sock: &UdpSocket, options: &HashMap<String, String>, ackwait: bool) -> Result<(), io::Error> {
+    /// Answers a request after its options were negotiated with `init_tftp_options`.
+    /// Sends an OACK with `options`, or for a WRQ without options an ACK of block 0.
+    /// With `ackwait` (for RRQs), waits for the peer to acknowledge the OACK,
+    /// retransmitting it a few times before giving up with `TimedOut`. Without,
+    /// `recv_file` retransmits the OACK until the first block arrives.
+    pub fn ack_options(&self, sock: &dyn Transport, options: &HashMap<String, String>, ackwait: bool) -> Result<(), io::Error> {
         if options.is_empty() {
             if !ackwait {
                 /* it's a WRQ, send normal ack to start transfer */
@@ -268,21 +564,20 @@ impl Tftp {
             return Ok(());
         }
 
-        let mut buf = Vec::with_capacity(512);
-        buf.extend((Opcode::OACK as u16).to_be_bytes().iter());
-
-        for (key, val) in options {
-            self.append_option(&mut buf, key, val);
-        }
-
-        for _ in 1..5 {
+        let buf = packet::encode_oack(options);
+        for attempt in 0..4 {
+            self.back_off(sock, attempt)?;
             sock.send(&buf)?;
             if !ackwait {
+                /* retransmitted by recv_file until the first block arrives */
+                self.oack.replace(Some(buf));
                 return Ok(());
             }
             match self.wait_for_ack(sock, 0) {
-                Ok(true) => return Ok(()),
-                Ok(false) => continue,
+                Ok(Reply::Ack) => return self.handshake_done(sock),
+                Ok(Reply::Other) => continue,
+                /* usually with code 8, nothing may be sent anymore */
+                Ok(Reply::Error(err)) => return Err(PeerError { options_rejected: true, ..err }.into()),
                 Err(e) => return Err(e),
             };
         }
@@ -290,14 +585,20 @@ impl Tftp {
         Err(io::Error::new(io::ErrorKind::TimedOut, "ack timeout"))
     }
 
-    pub fn init_tftp_options(&mut self, sock: &UdpSocket, options: &mut HashMap<String, String>) -> Result<(), io::Error> {
+    /// Applies the options of a request to this transfer and removes those that
+    /// are unsupported or have invalid values, so `options` can be passed on to
+    /// `ack_options`. Keys are matched case-insensitively. Sets the read timeout
+    /// of `sock` to the negotiated timeout, or to the handshake timeout until
+    /// the peer answered.
+    pub fn init_tftp_options(&mut self, sock: &dyn Transport, options: &mut HashMap<String, String>) -> Result<(), io::Error> {
         self.options = Default::default();
+        self.oack.replace(None);
 
         options.retain(|key, val| {
             let val = val.to_lowercase();
             match key.to_lowercase().as_str() {
                 "blksize" => match val.parse() {
-                    Ok(b) if (8..=65464).contains(&b) => {
+                    Ok(b) if (8..=packet::MAX_BLKSIZE).contains(&b) => {
                         self.options.blksize = b;
                         true
                     }
@@ -312,192 +613,140 @@ impl Tftp {
                     _ => false,
                 },
                 "timeout" => match val.parse() {
-                    Ok(t) if t >= 1 => {
+                    Ok(t) if (1..=255).contains(&t) => {
                         self.options.timeout = Duration::from_secs(t);
                         true
                     }
                     _ => false,
                 },
                 "utimeout" => match val.parse() {
-                    Ok(t) if t >= 1 => {
+                    Ok(t) if (1..=255_000_000).contains(&t) => {
                         self.options.timeout = Duration::from_micros(t);
                         true
                     }
                     _ => false,
                 },
                 "tsize" => match val.parse() {
-                    Ok(t) => {
+                    Ok(t) if t <= MAX_TSIZE => {
                         self.options.tsize = t;
                         true
                     }
                     _ => false,
                 },
+                "rollover" => match val.parse() {
+                    Ok(r) if r <= 1 => {
+                        self.options.rollover = r;
+                        true
+                    }
+                    _ => false,
+                },
                 _ => false,
             }
         });
 
-        sock.set_read_timeout(Some(self.options.timeout))?;
+        sock.set_read_timeout(Some(self.handshake_timeout.unwrap_or(self.options.timeout)))?;
+        self.in_handshake.set(self.handshake_timeout.is_some());
+        self.backed_off.set(false);
 
         Ok(())
     }
 
+    /// Parses NUL-terminated key/value pairs. Parsing stops at the first
+    /// incomplete pair and after `packet::MAX_OPTIONS` pairs. Keys are
+    /// case-insensitive and returned lowercased; later occurrences of a key
+    /// replace earlier ones.
     pub fn parse_options(&self, buf: &[u8]) -> HashMap<String, String> {
-        let mut options = HashMap::new();
-
-        let mut pos = 0;
-        #[allow(clippy::while_let_loop)]
-        loop {
-            let key = match self.get_tftp_str(&buf[pos..]) {
-                Some(k) => k,
-                None => break,
-            };
-            pos += key.len() + 1;
-
-            let val = match self.get_tftp_str(&buf[pos..]) {
-                Some(v) => v,
-                None => break,
-            };
-            pos += val.len() + 1;
-
-            options.insert(key, val);
-        }
-
-        options
+        packet::parse_options(buf)
     }
 
+    /// Parses the body of a RRQ or WRQ (everything after the opcode) into
+    /// filename, lowercased mode and options (see `parse_options` and
+    /// `packet::MAX_REQUEST_SIZE`). The filename keeps the bytes that were
+    /// sent, even if they are not valid UTF-8. Fails with `InvalidData` if
+    /// filename or mode are missing, not NUL-terminated or if the mode is not
+    /// valid UTF-8, unless a default mode was set with
+    /// [`set_default_mode`](Tftp::set_default_mode).
     pub fn parse_file_mode_options(&self, buf: &[u8]) -> Result<(PathBuf, String, HashMap<String, String>), io::Error> {
-        let dataerr = || io::Error::new(io::ErrorKind::InvalidData, "invalid data received");
-
-        let mut pos = 0;
-        let filename = self.get_tftp_str(&buf[pos..]).ok_or_else(dataerr)?;
-        pos += filename.len() + 1;
-
-        let mode = self.get_tftp_str(&buf[pos..]).ok_or_else(dataerr)?.to_lowercase();
-        pos += mode.len() + 1;
-
-        let options = self.parse_options(&buf[pos..]);
-
-        Ok((Path::new(&filename).to_path_buf(), mode, options))
+        let default_mode = self.default_mode.map(|mode| match mode {
+            Mode::OCTET => "octet",
+            Mode::NETASCII => "netascii",
+        });
+        let (filename, mode, options) = packet::parse_request_bytes(buf, default_mode)?;
+        Ok((PathBuf::from(OsString::from_vec(filename)), mode, options))
     }
 
-    pub fn send_error(&self, socket: &UdpSocket, code: u16, msg: &str) -> Result<(), io::Error> {
-        let mut buf = Vec::with_capacity(512);
-        buf.extend((Opcode::ERROR as u16).to_be_bytes().iter());
-        buf.extend(code.to_be_bytes().iter());
-        buf.extend(msg.as_bytes());
+    pub fn send_error(&self, socket: &dyn Transport, code: u16, msg: &str) -> Result<(), io::Error> {
+        self.last_error.set(Some(code));
+        socket.send(&packet::encode_error(code, msg))?;
+        Ok(())
+    }
 
-        socket.send(&buf)?;
+    pub fn send_ack(&self, sock: &dyn Transport, block_nr: u16) -> Result<(), io::Error> {
+        sock.send(&packet::encode_ack(block_nr))?;
         Ok(())
     }
 
-    fn _send_ack(&self, sock: &UdpSocket, cl: Option<SocketAddr>, block_nr: u16) -> Result<(), io::Error> {
-        let mut buf = Vec::with_capacity(4);
-        buf.extend((Opcode::ACK as u16).to_be_bytes().iter());
-        buf.extend(block_nr.to_be_bytes().iter());
+    /// Returns the block number following `block_nr`, wrapping around
+    /// to the negotiated rollover value (0 unless requested otherwise).
+    fn next_block(&self, block_nr: u16) -> u16 {
+        match block_nr.checked_add(1) {
+            Some(next) => next,
+            None => self.options.rollover,
+        }
+    }
 
-        match cl {
-            Some(remote) => { sock.send_to(&buf, remote)?; }
-            None => { sock.send(&buf)?; }
+    /// Acknowledges `block_nr` again, resending the OACK instead of the ACK of block 0.
+    fn resend_ack(&self, sock: &dyn Transport, block_nr: u16) -> Result<(), io::Error> {
+        match &*self.oack.borrow() {
+            Some(oack) if block_nr == 0 => {
+                sock.send(oack)?;
+                Ok(())
+            }
+            _ => self.send_ack(sock, block_nr),
         }
-        Ok(())
     }
 
-    pub fn send_ack(&self, sock: &UdpSocket, block_nr: u16) -> Result<(), io::Error> {
-        self._send_ack(sock, None, block_nr)
+    pub fn send_error_to(&self, sock: &UdpSocket, cl: SocketAddr, code: u16, msg: &str) -> Result<(), io::Error> {
+        sock.send_to(&packet::encode_error(code, msg), cl)?;
+        Ok(())
     }
 
     pub fn send_ack_to(&self, sock: &UdpSocket, cl: SocketAddr, block_nr: u16) -> Result<(), io::Error> {
-        self._send_ack(sock, Some(cl), block_nr)
+        sock.send_to(&packet::encode_ack(block_nr), cl)?;
+        Ok(())
     }
 
-    pub fn send_file(&self, socket: &UdpSocket, file: &mut File) -> Result<(), io::Error> {
-        let mut block_nr: u16 = 1;
-        let mut transferred = 0;
-        let mut prog_update = 0;
-        let tsize = self.transfer_size(file);
-
-        /* holds bytes from netascii conversion that did not fit in tx buffer */
-        let mut overflow = Vec::with_capacity(2 * self.options.blksize);
-
-        let mut reader = BufReader::new(file);
-        loop {
-            let mut filebuf = vec![0; self.options.blksize - overflow.len()];
-            let mut len = match self.read_exact(&mut reader, &mut filebuf) {
-                Ok(n) => n,
-                Err(err) => {
-                    self.send_error(socket, 0, "File reading error")?;
-                    return Err(err);
-                }
-            };
-
-            /* take care of netascii conversion */
-            let mut databuf = filebuf[0..len].to_vec();
-            match self.mode {
-                Mode::OCTET => {},
-                Mode::NETASCII => {
-                    overflow.extend(octet_to_netascii(&databuf));
-                    databuf = overflow.clone();
-                    if overflow.len() > self.options.blksize {
-                        overflow = databuf.split_off(self.options.blksize);
-                    } else {
-                        overflow.clear();
-                    }
-                    len = databuf.len();
-                }
-            }
-
-            let mut sendbuf = Vec::with_capacity(4 + len);
-            sendbuf.extend((Opcode::DATA as u16).to_be_bytes().iter());
-            sendbuf.extend(block_nr.to_be_bytes().iter());
-            sendbuf.extend(databuf.iter());
-
-            let mut acked = false;
-            for _ in 1..5 {
-                /* try a couple of times to send data, in case of timeouts
-                or re-ack of previous data */
-                socket.send(&sendbuf)?;
-                match self.wait_for_ack(socket, block_nr) {
-                    Ok(true) => {
-                        acked = true;
-                        break;
-                    }
-                    Ok(false) => continue,
-                    Err(e) => return Err(e),
-                };
-            }
-            if !acked {
-                return Err(io::Error::new(io::ErrorKind::TimedOut, "ack timeout"));
-            }
-
-            transferred += len as u64;
-            if let Some(cb) = &self.progress_cb {
-                prog_update = cb(transferred, tsize, prog_update);
-            }
+    pub fn send_file(&self, socket: &dyn Transport, file: &mut File) -> Result<u64, io::Error> {
+        self.send_file_part(socket, file, u64::MAX)
+    }
 
-            if len < self.options.blksize {
-                /* this was the last block */
-                break;
-            }
+    /// Like [`send_file`](Tftp::send_file), but stops after `len` bytes of
+    /// `file`, e.g. the size announced with tsize when the file keeps growing.
+    pub fn send_file_part(&self, socket: &dyn Transport, file: &mut File, len: u64) -> Result<u64, io::Error> {
+        let tsize = cmp::min(self.transfer_size(file), len);
+        let mut reader = BufReader::with_capacity(IO_BUFFER_BLOCKS * self.options.blksize, file.take(len));
+        self.send_reader(socket, &mut reader, tsize)
+    }
 
-            /* increment with rollover on overflow */
-            block_nr = block_nr.wrapping_add(1);
-        }
-        Ok(())
+    pub fn send_slice(&self, socket: &dyn Transport, slice: &[u8]) -> Result<u64, io::Error> {
+        self.send_reader(socket, &mut io::Cursor::new(slice), slice.len() as u64)
     }
 
-    pub fn send_slice(&self, socket: &UdpSocket, slice: &[u8]) -> Result<(), io::Error> {
+    /// Sends everything that can be read from `reader`.
+    /// `tsize` is only used for progress reporting; 0 if unknown.
+    pub fn send_reader(&self, socket: &dyn Transport, reader: &mut dyn Read, tsize: u64) -> Result<u64, io::Error> {
         let mut block_nr: u16 = 1;
         let mut transferred = 0;
         let mut prog_update = 0;
-        let tsize = slice.len() as u64;
 
         /* holds bytes from netascii conversion that did not fit in tx buffer */
         let mut overflow = Vec::with_capacity(2 * self.options.blksize);
+        let start = Instant::now();
 
-        let mut reader = io::Cursor::new(slice);
         loop {
+            self.check_cancelled(socket)?;
             let mut filebuf = vec![0; self.options.blksize - overflow.len()];
-            let mut len = match self.read_exact(&mut reader, &mut filebuf) {
+            let mut len = match self.read_exact(reader, &mut filebuf) {
                 Ok(n) => n,
                 Err(err) => {
                     self.send_error(socket, 0, "File reading error")?;
@@ -508,7 +757,7 @@ impl Tftp {
             /* take care of netascii conversion */
             let mut databuf = filebuf[0..len].to_vec();
             match self.mode {
-                Mode::OCTET => {}
+                Mode::OCTET => {},
                 Mode::NETASCII => {
                     overflow.extend(octet_to_netascii(&databuf));
                     databuf = overflow.clone();
@@ -521,28 +770,36 @@ impl Tftp {
                 }
             }
 
-            let mut sendbuf = Vec::with_capacity(4 + len);
-            sendbuf.extend((Opcode::DATA as u16).to_be_bytes().iter());
-            sendbuf.extend(block_nr.to_be_bytes().iter());
-            sendbuf.extend(databuf.iter());
+            let sendbuf = packet::encode_data(block_nr, &databuf);
+
+            if let Some(throttle) = &self.throttle {
+                throttle.consume(sendbuf.len());
+            }
 
             let mut acked = false;
-            for _ in 1..5 {
+            for attempt in 0..4 {
                 /* try a couple of times to send data, in case of timeouts
                 or re-ack of previous data */
+                self.back_off(socket, attempt)?;
+                if let Err(err) = self.check_deadline(start) {
+                    self.send_error(socket, 0, "Transfer timeout")?;
+                    return Err(err);
+                }
                 socket.send(&sendbuf)?;
                 match self.wait_for_ack(socket, block_nr) {
-                    Ok(true) => {
+                    Ok(Reply::Ack) => {
                         acked = true;
                         break;
                     }
-                    Ok(false) => continue,
+                    Ok(Reply::Other) => continue,
+                    Ok(Reply::Error(err)) => return Err(err.into()),
                     Err(e) => return Err(e),
                 };
             }
             if !acked {
                 return Err(io::Error::new(io::ErrorKind::TimedOut, "ack timeout"));
             }
+            self.handshake_done(socket)?;
 
             transferred += len as u64;
             if let Some(cb) = &self.progress_cb {
@@ -550,33 +807,41 @@ impl Tftp {
             }
 
             if len < self.options.blksize {
-                /* this was the last block */
+                /* this was the last block; the retry loop above is the dally of
+                   RFC 1350: the block was resent on each timeout until it was
+                   acknowledged, so nothing is left that a wait could recover */
                 break;
             }
 
-            /* increment with rollover on overflow */
-            block_nr = block_nr.wrapping_add(1);
+            block_nr = self.next_block(block_nr);
         }
-        Ok(())
+        Ok(transferred)
     }
 
-    pub fn recv_file(&self, sock: &UdpSocket, file: &mut File) -> Result<(), io::Error> {
+    pub fn recv_file(&self, sock: &dyn Transport, file: &mut dyn Write) -> Result<u64, io::Error> {
+        let mut file = BufWriter::with_capacity(IO_BUFFER_BLOCKS * self.options.blksize, file);
         let mut block_nr: u16 = 1;
+        /* last acknowledged block, which is not always block_nr - 1 after a rollover */
+        let mut prev_block: u16 = 0;
         let mut prog_update = 0;
         let mut transferred = 0;
         let mut netascii_state = false;
-        let tsize = self.transfer_size(file);
+        let tsize = self.options.tsize;
+        let start = Instant::now();
 
         loop {
+            self.check_cancelled(sock)?;
             let mut buf = vec![0; 4 + self.options.blksize + 1]; // +1 for later size check
             let mut len = 0;
 
-            for _ in 1..5 {
+            for attempt in 0..4 {
+                self.back_off(sock, attempt)?;
+                self.check_deadline(start)?;
                 len = match sock.recv(&mut buf) {
                     Ok(n) => n,
                     Err(ref error) if [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut].contains(&error.kind()) => {
                         /* re-ack previous and try to recv again */
-                        self.send_ack(sock, block_nr - 1)?;
+                        self.resend_ack(sock, prev_block)?;
                         continue;
                     }
                     Err(err) => return Err(err),
@@ -590,14 +855,23 @@ impl Tftp {
 
             match u16::from_be_bytes([buf[0], buf[1]]) {  // opcode
                 opc if opc == Opcode::DATA as u16 => (),
-                opc if opc == Opcode::ERROR as u16 => return Err(self.parse_error(&buf[..len])),
-                _ => return Err(io::Error::new(io::ErrorKind::Other, "unexpected opcode")),
+                opc if opc == Opcode::ERROR as u16 => {
+                    /* the OACK is still unconfirmed before the first block */
+                    return Err(match PeerError::parse(&buf[..len]) {
+                        Some(err) => PeerError { options_rejected: self.oack.borrow().is_some(), ..err }.into(),
+                        None => self.parse_error(&buf[..len]),
+                    });
+                }
+                _ => return Err(io::Error::other("unexpected opcode")),
             };
             if u16::from_be_bytes([buf[2], buf[3]]) != block_nr {
                 /* already received or packets were missed, re-acknowledge */
-                self.send_ack(sock, block_nr - 1)?;
+                self.resend_ack(sock, prev_block)?;
                 continue;
             }
+            /* the first block confirms the OACK, a block 0 after a rollover must be acked normally */
+            self.oack.replace(None);
+            self.handshake_done(sock)?;
 
             let mut databuf = buf[4..len].to_vec();
             match self.mode {
@@ -610,59 +884,218 @@ impl Tftp {
             }
             file.write_all(&databuf)?;
 
+            /* delaying the ack slows down the sender */
+            if let Some(throttle) = &self.throttle {
+                throttle.consume(len);
+            }
+
             transferred += (len - 4) as u64;
             if let Some(cb) = &self.progress_cb {
                 prog_update = cb(transferred, tsize, prog_update);
             }
 
+            let last = len < 4 + self.options.blksize;
+            if last {
+                if netascii_state {
+                    /* the file ended with an incomplete \r encoding */
+                    file.write_all(b"\r")?;
+                }
+                /* the peer considers the file complete once the last block is acked */
+                file.flush()?;
+            }
+
             self.send_ack(sock, block_nr)?;
-            block_nr = block_nr.wrapping_add(1);
+            prev_block = block_nr;
+            block_nr = self.next_block(block_nr);
 
-            if len < 4 + self.options.blksize {
+            if last {
                 break;
             }
         }
 
-        if netascii_state {
-            /* the file ended with an incomplete \r encoding */
-            file.write_all(&[b'\r'])?;
+        Ok(transferred)
+    }
+
+    fn client_socket(&self, server: &SocketAddr) -> Result<UdpSocket, io::Error> {
+        let local = match server {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let sock = UdpSocket::bind(local)?;
+        sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(sock)
+    }
+
+    fn init_request(&self, opcode: Opcode, filename: &str, options: &HashMap<String, String>) -> Vec<u8> {
+        let mode = match self.mode {
+            Mode::OCTET => "octet",
+            Mode::NETASCII => "netascii",
+        };
+        packet::encode_request(opcode, filename, mode, options)
+    }
+
+    fn wait_for_option_ack(&mut self, sock: &UdpSocket) -> Option<SocketAddr> {
+        let mut buf = [0; 512];
+        sock.peek_from(&mut buf).ok()?;
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        if opcode != Opcode::OACK as u16 {
+            return None;
         }
 
-        file.flush()?;
+        let (len, remote) = sock.recv_from(&mut buf).ok()?;
 
-        Ok(())
+        let mut options = self.parse_options(&buf[2..len]);
+        let offset = options.get("offset").and_then(|val| val.parse().ok());
+        self.init_tftp_options(sock, &mut options).ok()?;
+        self.options.offset = offset.unwrap_or(0);
+
+        Some(remote)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    fn wait_for_response(&self, sock: &UdpSocket, expected_opcode: Opcode, expected_block: u16, expected_remote: Option<SocketAddr>) -> Result<Option<SocketAddr>, io::Error> {
+        let mut buf = [0; 4];
+        let (len, remote) = sock.peek_from(&mut buf)?;
 
-    #[test]
-    fn test_get_tftp_str() {
-        let tftp = Tftp::new();
+        if let Some(rem) = expected_remote {
+            /* verify we got a response from the same client that sent
+               an optional previous option ack */
+            if rem != remote {
+                return Ok(None);
+            }
+        }
 
-        let mut buf = Vec::with_capacity(100);
-        assert_eq!(tftp.get_tftp_str(&buf), None);
+        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
+        let block_nr = u16::from_be_bytes([buf[2], buf[3]]);
+
+        if opcode == Opcode::ERROR as u16 {
+            let mut buf = [0; 512];
+            let len = sock.recv(&mut buf)?;
+            return Err(self.parse_error(&buf[..len]));
+        }
+
+        /* first data packet is expected to be block 1 */
+        if len != 4 || opcode != expected_opcode as u16 || block_nr != expected_block {
+            return Ok(None);
+        }
+
+        Ok(Some(remote))
+    }
 
-        buf.extend("key".bytes());
-        assert_eq!(tftp.get_tftp_str(&buf), None);
+    /// Downloads the file `remote` from `server` and writes it to `dest`.
+    ///
+    /// `options` (e.g. blksize or tsize) are requested from the server;
+    /// the ones it does not acknowledge fall back to their defaults.
+    /// An `offset` option resumes a download at that byte of the file, so that
+    /// `dest` only receives the rest; as it is not part of the protocol, the
+    /// download fails with `Unsupported` if the server does not acknowledge it.
+    /// Returns the number of bytes received.
+    pub fn get(&mut self, server: SocketAddr, remote: &str, mut dest: impl Write, options: &HashMap<String, String>) -> Result<u64, io::Error> {
+        let sock = self.client_socket(&server)?;
+        let buf = self.init_request(Opcode::RRQ, remote, options);
+        self.options = Default::default();
+        let offset = match options.get("offset").map(|val| val.parse::<u64>()) {
+            Some(Ok(offset)) => offset,
+            Some(Err(_)) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid offset")),
+            None => 0,
+        };
 
-        buf.push(0x00);
-        buf.extend("value".bytes());
-        buf.push(0x00);
-        /* empty option */
-        buf.push(0x00);
+        let mut peer = None;
+        for _ in 1..3 {
+            sock.send_to(&buf, server)?;
+            let oack_remote = self.wait_for_option_ack(&sock);
+            if let Some(r) = oack_remote {
+                /* for RRQ the received OACKs need to be acked */
+                self.send_ack_to(&sock, r, 0)?;
+            }
+            peer = self.wait_for_response(&sock, Opcode::DATA, 1, oack_remote)?;
+            if peer.is_some() {
+                break;
+            }
+        }
+        /* reconnect to remote to communicate from now on with updated port */
+        match peer {
+            Some(p) => sock.connect(p)?,
+            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "No response from server")),
+        }
+        if self.options.offset != offset {
+            /* the data would not continue where dest ends */
+            self.send_error(&sock, 8, "Offset not acknowledged")?;
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "server does not support resuming at an offset"));
+        }
 
-        let mut pos = 0;
-        let val = tftp.get_tftp_str(&buf).unwrap();
-        assert_eq!(val, "key");
-        pos += val.len() + 1;
-        let val = tftp.get_tftp_str(&buf[pos..]).unwrap();
-        assert_eq!(val, "value");
-        pos += val.len() + 1;
-        let val = tftp.get_tftp_str(&buf[pos..]).unwrap();
-        assert_eq!(val, "");
+        match self.recv_file(&sock, &mut dest) {
+            Ok(size) => Ok(size),
+            Err(err) => {
+                if !error_exchanged(&err) {
+                    self.send_error(&sock, 0, "Receiving error")?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Uploads everything read from `src` to `server`, stored there as `remote`.
+    ///
+    /// `options` (e.g. blksize or tsize) are requested from the server;
+    /// the ones it does not acknowledge fall back to their defaults.
+    /// Rejections by the server are reported with the matching error kind,
+    /// e.g. `AlreadyExists` or `PermissionDenied`.
+    /// Returns the number of bytes sent.
+    pub fn put(&mut self, server: SocketAddr, remote: &str, src: impl Read, options: &HashMap<String, String>) -> Result<u64, io::Error> {
+        let sock = self.client_socket(&server)?;
+        let buf = self.init_request(Opcode::WRQ, remote, options);
+        self.options = Default::default();
+
+        let mut peer = None;
+        for _ in 1..3 {
+            sock.send_to(&buf, server)?;
+            peer = self.wait_for_option_ack(&sock);
+            if peer.is_none() {
+                /* for WRQ either OACK or ACK is replied */
+                peer = self.wait_for_response(&sock, Opcode::ACK, 0, None)?;
+                if peer.is_some() {
+                    /* drop the peeked ACK, it's not part of the data transfer */
+                    sock.recv(&mut [0; 4])?;
+                }
+            }
+            if peer.is_some() {
+                break;
+            }
+        }
+        /* reconnect to remote to communicate from now on with updated port */
+        match peer {
+            Some(p) => sock.connect(p)?,
+            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "No response from server")),
+        }
+
+        let tsize = options.get("tsize").and_then(|t| t.parse().ok()).unwrap_or(0);
+        let mut reader = BufReader::with_capacity(IO_BUFFER_BLOCKS * self.options.blksize, src);
+        match self.send_reader(&sock, &mut reader, tsize) {
+            Ok(size) => Ok(size),
+            Err(err) => {
+                if !error_exchanged(&err) {
+                    self.send_error(&sock, 0, "Sending error")?;
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::thread;
+
+    /// Returns two sockets connected to each other; the first one with a short read timeout.
+    fn socket_pair() -> (UdpSocket, UdpSocket) {
+        let a = UdpSocket::bind("[::1]:0").unwrap();
+        let b = UdpSocket::bind("[::1]:0").unwrap();
+        a.connect(b.local_addr().unwrap()).unwrap();
+        b.connect(a.local_addr().unwrap()).unwrap();
+        a.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        (a, b)
     }
 
     #[test]
@@ -673,12 +1106,12 @@ mod tests {
         let opts = tftp.parse_options(&buf);
         assert_eq!(opts.len(), 0);
 
-        buf.extend("blksize\x001234\x00tsize\x000\x00incomplete".bytes());
+        buf.extend("BLKSIZE\x001234\x00tsize\x000\x00incomplete".bytes());
         let opts = tftp.parse_options(&buf);
         assert_eq!(opts.len(), 2);
         assert_eq!(opts["blksize"], "1234");
         assert_eq!(opts["tsize"], "0");
-        assert_eq!(opts.contains_key("incomplete"), false);
+        assert!(!opts.contains_key("incomplete"));
     }
 
     #[test]
@@ -686,9 +1119,9 @@ mod tests {
         let tftp = Tftp::new();
 
         let mut buf = Vec::with_capacity(100);
-        assert_eq!(tftp.parse_file_mode_options(&buf).is_err(), true);
+        assert!(tftp.parse_file_mode_options(&buf).is_err());
         buf.extend("FileName\x00".bytes());
-        assert_eq!(tftp.parse_file_mode_options(&buf).is_err(), true);
+        assert!(tftp.parse_file_mode_options(&buf).is_err());
         buf.extend("NetASCII\x00".bytes());
         let (filename, mode, opts) = tftp.parse_file_mode_options(&buf).unwrap();
         assert_eq!(filename, PathBuf::from("FileName"));
@@ -703,6 +1136,144 @@ mod tests {
         assert_eq!(opts["blksize"], "1024");
     }
 
+    #[test]
+    fn test_parse_malformed_requests() {
+        let tftp = Tftp::new();
+
+        /* missing terminators */
+        assert_eq!(tftp.parse_file_mode_options(b"").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(tftp.parse_file_mode_options(b"file").is_err());
+        assert!(tftp.parse_file_mode_options(b"file\x00octet").is_err());
+        assert!(tftp.parse_file_mode_options(b"file\x00oct\xffet\x00").is_err());
+
+        /* filenames in other encodings are preserved */
+        let (filename, _, _) = tftp.parse_file_mode_options(b"caf\xe9\x00octet\x00").unwrap();
+        assert_eq!(filename.as_os_str().as_encoded_bytes(), b"caf\xe9");
+
+        /* truncated option pairs are ignored */
+        let (_, _, opts) = tftp.parse_file_mode_options(b"file\x00octet\x00blksize").unwrap();
+        assert!(opts.is_empty());
+        let (_, _, opts) = tftp.parse_file_mode_options(b"file\x00octet\x00tsize\x000\x00blksize\x00").unwrap();
+        assert_eq!(opts.len(), 1);
+        let (_, _, opts) = tftp.parse_file_mode_options(b"file\x00octet\x00tsize\x000\x00blksize\x001024").unwrap();
+        assert_eq!(opts.len(), 1);
+        assert_eq!(opts["tsize"], "0");
+
+        /* the last of duplicate keys wins */
+        let (_, _, opts) = tftp.parse_file_mode_options(b"file\x00octet\x00blksize\x00512\x00blksize\x001024\x00").unwrap();
+        assert_eq!(opts.len(), 1);
+        assert_eq!(opts["blksize"], "1024");
+        let (_, _, opts) = tftp.parse_file_mode_options(b"file\x00octet\x00blksize\x00512\x00BlkSize\x001024\x00").unwrap();
+        assert_eq!(opts.len(), 1);
+        assert_eq!(opts["blksize"], "1024");
+
+        /* lowercasing the mode must not shift the options */
+        let (_, mode, opts) = tftp.parse_file_mode_options("file\x00OCTET\u{130}\x00tsize\x000\x00".as_bytes()).unwrap();
+        assert_eq!(mode, "octeti\u{307}");
+        assert_eq!(opts["tsize"], "0");
+    }
+
+    #[test]
+    fn test_default_mode() {
+        let mut tftp = Tftp::new();
+        tftp.set_default_mode(Some(Mode::OCTET));
+
+        for buf in [&b"file\x00"[..], b"file\x00\x00", b"file\x00oct", b"file\x00oct\xffet\x00tsize\x000\x00"] {
+            let (filename, mode, opts) = tftp.parse_file_mode_options(buf).unwrap();
+            assert_eq!((filename, mode.as_str()), (PathBuf::from("file"), "octet"), "{:?}", buf);
+            assert!(opts.is_empty());
+        }
+        /* a mode that was sent is kept, even if it is not supported */
+        let (_, mode, opts) = tftp.parse_file_mode_options(b"file\x00NetASCII\x00tsize\x000\x00").unwrap();
+        assert_eq!(mode, "netascii");
+        assert_eq!(opts["tsize"], "0");
+        assert_eq!(tftp.parse_file_mode_options(b"file\x00mail\x00").unwrap().1, "mail");
+        /* without a file name, there is nothing to serve */
+        assert!(tftp.parse_file_mode_options(b"file").is_err());
+
+        tftp.set_default_mode(Some(Mode::NETASCII));
+        assert_eq!(tftp.parse_file_mode_options(b"file\x00").unwrap().1, "netascii");
+        tftp.set_default_mode(None);
+        assert!(tftp.parse_file_mode_options(b"file\x00").is_err());
+    }
+
+    #[test]
+    fn test_init_and_ack_options() {
+        let (server, client) = socket_pair();
+        let mut tftp = Tftp::new();
+
+        let mut options = tftp.parse_options(b"blksize\x001024\x00timeout\x000\x00windowsize\x004\x00tsize\x00100\x00");
+        tftp.init_tftp_options(&server, &mut options).unwrap();
+        assert_eq!(options.len(), 2);
+        assert_eq!(options["blksize"], "1024");
+        assert_eq!(options["tsize"], "100");
+        /* invalid timeout is dropped, so the default one applies */
+        assert_eq!(server.read_timeout().unwrap(), Some(Duration::from_secs(3)));
+
+        /* OACK for a WRQ, no ack expected */
+        tftp.ack_options(&server, &options, false).unwrap();
+        let mut buf = [0; 100];
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..2], b"\x00\x06");
+        assert_eq!(tftp.parse_options(&buf[2..len]), options);
+
+        /* no options: ACK 0 for a WRQ, nothing for a RRQ */
+        tftp.ack_options(&server, &HashMap::new(), false).unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x04\x00\x00");
+        tftp.ack_options(&server, &HashMap::new(), true).unwrap();
+    }
+
+    #[test]
+    fn test_supported_options() {
+        let (server, _client) = socket_pair();
+        let mut tftp = Tftp::new();
+
+        let mut options: HashMap<String, String> = SUPPORTED_OPTIONS.iter()
+            .map(|opt| (opt.to_string(), String::from(if *opt == "rollover" { "1" } else { "16" })))
+            .collect();
+        options.insert(String::from("unsupported"), String::from("512"));
+        tftp.init_tftp_options(&server, &mut options).unwrap();
+
+        let mut accepted: Vec<&str> = options.keys().map(String::as_str).collect();
+        accepted.sort();
+        let mut supported = SUPPORTED_OPTIONS.to_vec();
+        supported.sort();
+        assert_eq!(accepted, supported);
+    }
+
+    #[test]
+    fn test_option_bounds() {
+        let (server, _client) = socket_pair();
+        let mut tftp = Tftp::new();
+
+        let accepted = |tftp: &mut Tftp, key: &str, val: &str| {
+            let mut options = HashMap::from([(key.to_string(), val.to_string())]);
+            tftp.init_tftp_options(&server, &mut options).unwrap();
+            options.contains_key(key)
+        };
+
+        for (key, valid, invalid) in [
+            ("blksize", &["8", "65464"][..], &["7", "65465", "18446744073709551616", "-512", "0x200", ""][..]),
+            ("blksize2", &["8", "32768"], &["7", "32769", "99999999999999999999"]),
+            ("timeout", &["1", "255"], &["0", "256", "-1", "18446744073709551615"]),
+            ("utimeout", &["1", "255000000"], &["0", "255000001", "18446744073709551615"]),
+            ("tsize", &["0", "9223372036854775807"], &["9223372036854775808", "18446744073709551616", "-1", "1e9"]),
+            ("rollover", &["0", "1"], &["2", "65536", "-1"]),
+        ] {
+            for val in valid {
+                assert!(accepted(&mut tftp, key, val), "{}={} refused", key, val);
+            }
+            for val in invalid {
+                assert!(!accepted(&mut tftp, key, val), "{}={} accepted", key, val);
+                /* ignored options keep their defaults */
+                assert_eq!(tftp.options.blksize, 512);
+                assert_eq!(tftp.options.timeout, Duration::from_secs(3));
+                assert_eq!(tftp.options.tsize, 0);
+            }
+        }
+    }
+
     #[test]
     fn test_append_option() {
         let tftp = Tftp::new();
@@ -741,6 +1312,18 @@ mod tests {
         assert_eq!(octet_to_netascii(b""), b"");
     }
 
+    #[test]
+    fn test_opcode() {
+        let opcodes = [Opcode::RRQ, Opcode::WRQ, Opcode::DATA, Opcode::ACK, Opcode::ERROR, Opcode::OACK];
+        for opcode in opcodes {
+            assert_eq!(Opcode::try_from(opcode as u16).unwrap(), opcode);
+        }
+        assert_eq!(Opcode::DATA as u16, 3);
+        assert_eq!(Opcode::OACK.to_string(), "OACK");
+        assert!(Opcode::try_from(0).is_err());
+        assert!(Opcode::try_from(7).is_err());
+    }
+
     #[test]
     fn test_blksize2() {
         assert_eq!(blksize2(16), 16);
@@ -749,4 +1332,342 @@ mod tests {
         assert_eq!(blksize2(1), 1);
         assert_eq!(blksize2(0), 0);
     }
+
+    #[test]
+    fn test_send_retransmits_last_block() {
+        let (sender, receiver) = socket_pair();
+
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 516];
+            /* pretend the ACK of the first transmission got lost */
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x00\x03\x00\x01data");
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x00\x03\x00\x01data");
+            receiver.send(b"\x00\x04\x00\x01").unwrap();
+            receiver
+        });
+
+        let tftp = Tftp::new();
+        assert_eq!(tftp.send_slice(&sender, b"data").unwrap(), 4);
+        let _receiver = peer.join().unwrap();
+
+        /* final block never acknowledged */
+        assert_eq!(tftp.send_slice(&sender, b"data").unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let (sender, receiver) = socket_pair();
+
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 516];
+            /* a single empty block, which is also the last one */
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"\x00\x03\x00\x01");
+            receiver.send(b"\x00\x04\x00\x01").unwrap();
+            receiver
+        });
+        assert_eq!(Tftp::new().send_slice(&sender, b"").unwrap(), 0);
+        let receiver = peer.join().unwrap();
+
+        receiver.send(b"\x00\x03\x00\x01").unwrap();
+        let mut received = Vec::new();
+        assert_eq!(Tftp::new().recv_file(&sender, &mut received).unwrap(), 0);
+        assert!(received.is_empty());
+        let mut buf = [0; 516];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x04\x00\x01");
+    }
+
+    #[test]
+    fn test_unknown_transfer_size() {
+        let listener = UdpSocket::bind("[::1]:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let content: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+
+        let stream = content.clone();
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, cl) = listener.recv_from(&mut buf).unwrap();
+            let socket = UdpSocket::bind("[::1]:0").unwrap();
+            socket.connect(cl).unwrap();
+
+            let mut tftp = Tftp::new();
+            let (_, _, mut options) = tftp.parse_file_mode_options(&buf[2..len]).unwrap();
+            tftp.init_tftp_options(&socket, &mut options).unwrap();
+            tftp.set_transfer_size(&mut options, None);
+            assert!(!options.contains_key("tsize"));
+            tftp.ack_options(&socket, &options, true).unwrap();
+            /* no size hint; the short final block ends the transfer */
+            tftp.send_reader(&socket, &mut io::Cursor::new(stream), 0).unwrap()
+        });
+
+        let mut tftp = Tftp::new();
+        let options = HashMap::from([(String::from("tsize"), String::from("0")), (String::from("blksize"), String::from("1024"))]);
+        let mut received = Vec::new();
+        assert_eq!(tftp.get(server, "stream", &mut received, &options).unwrap(), content.len() as u64);
+        assert_eq!(peer.join().unwrap(), content.len() as u64);
+        assert_eq!(received, content);
+        /* blksize was acknowledged, tsize left out */
+        assert_eq!(tftp.options.blksize, 1024);
+        assert_eq!(tftp.options.tsize, 0);
+
+        let mut options = HashMap::from([(String::from("tsize"), String::from("0"))]);
+        tftp.set_transfer_size(&mut options, Some(3000));
+        assert_eq!(options["tsize"], "3000");
+        let mut options = HashMap::new();
+        tftp.set_transfer_size(&mut options, Some(3000));
+        assert!(options.is_empty());
+    }
+
+    #[test]
+    fn test_growing_file() {
+        let listener = UdpSocket::bind("[::1]:0").unwrap();
+        let server = listener.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("rtftp-growing-{}", std::process::id()));
+        let content: Vec<u8> = (0..1500u32).map(|i| (i % 251) as u8).collect();
+        fs::write(&path, &content).unwrap();
+
+        let growing = path.clone();
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (len, cl) = listener.recv_from(&mut buf).unwrap();
+            let socket = UdpSocket::bind("[::1]:0").unwrap();
+            socket.connect(cl).unwrap();
+
+            let mut tftp = Tftp::new();
+            let (_, _, mut options) = tftp.parse_file_mode_options(&buf[2..len]).unwrap();
+            tftp.init_tftp_options(&socket, &mut options).unwrap();
+            let mut file = File::open(&growing).unwrap();
+            let len = file.metadata().unwrap().len();
+            let size = tftp.transfersize_of(&mut file, len).unwrap();
+            tftp.set_transfer_size(&mut options, Some(size));
+            tftp.ack_options(&socket, &options, true).unwrap();
+
+            /* appended after the size was reported */
+            fs::OpenOptions::new().append(true).open(&growing).unwrap().write_all(&[b'x'; 700]).unwrap();
+            tftp.send_file_part(&socket, &mut file, len).unwrap()
+        });
+
+        let mut tftp = Tftp::new();
+        let options = HashMap::from([(String::from("tsize"), String::from("0"))]);
+        let mut received = Vec::new();
+        assert_eq!(tftp.get(server, "growing", &mut received, &options).unwrap(), content.len() as u64);
+        assert_eq!(peer.join().unwrap(), content.len() as u64);
+        assert_eq!(tftp.options.tsize, content.len() as u64);
+        assert_eq!(received, content);
+
+        /* the netascii size covers the same bytes */
+        let mut file = File::open(&path).unwrap();
+        tftp.set_mode(Mode::NETASCII);
+        fs::write(&path, b"a\nb\nc\n").unwrap();
+        assert_eq!(tftp.transfersize_of(&mut file, 4).unwrap(), 6);
+        assert_eq!(tftp.transfersize(&mut file).unwrap(), 9);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rollover() {
+        let data: Vec<u8> = (0..8 * 65540 + 3).map(|i: u32| (i % 251) as u8).collect();
+
+        for rollover in ["0", "1"] {
+            let (sender, receiver) = socket_pair();
+            let mut options: HashMap<String, String> = [("blksize", "8"), ("rollover", rollover)].iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+
+            let mut tftp = Tftp::new();
+            tftp.init_tftp_options(&receiver, &mut options.clone()).unwrap();
+            let peer = thread::spawn(move || {
+                let mut received = Vec::new();
+                tftp.recv_file(&receiver, &mut received).map(|_| received)
+            });
+
+            let mut tftp = Tftp::new();
+            tftp.init_tftp_options(&sender, &mut options).unwrap();
+            assert_eq!(options["rollover"], rollover);
+            assert_eq!(tftp.send_slice(&sender, &data).unwrap(), data.len() as u64);
+            assert_eq!(peer.join().unwrap().unwrap(), data);
+        }
+
+        assert_eq!(Tftp { options: TftpOptions { rollover: 1, ..Default::default() }, ..Default::default() }.next_block(65535), 1);
+        assert_eq!(Tftp::new().next_block(65535), 0);
+        assert_eq!(Tftp::new().next_block(1), 2);
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let (sender, receiver) = socket_pair();
+        let mut tftp = Tftp::new();
+        tftp.set_handshake_timeout(Some(Duration::from_millis(50)));
+        let mut options = tftp.parse_options(b"timeout\x002\x00");
+        tftp.init_tftp_options(&sender, &mut options).unwrap();
+        /* the kernel may round it up slightly */
+        assert!(sender.read_timeout().unwrap().unwrap() < Duration::from_secs(1));
+
+        /* the negotiated timeout applies once the first block was acknowledged */
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 516];
+            let len = receiver.recv(&mut buf).unwrap();
+            receiver.send(&[0, Opcode::ACK as u8, buf[2], buf[3]]).unwrap();
+            (len, receiver)
+        });
+        tftp.send_slice(&sender, b"data").unwrap();
+        let (len, _receiver) = peer.join().unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(sender.read_timeout().unwrap(), Some(Duration::from_secs(2)));
+
+        /* a peer that never answers is given up on after a few short waits */
+        tftp.init_tftp_options(&sender, &mut options).unwrap();
+        let start = Instant::now();
+        assert_eq!(tftp.send_slice(&sender, b"data").unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_options_rejected() {
+        let (sender, receiver) = socket_pair();
+        let mut tftp = Tftp::new();
+        let mut options = tftp.parse_options(b"blksize\x001428\x00");
+        tftp.init_tftp_options(&sender, &mut options).unwrap();
+
+        /* a client not understanding the OACK of a RRQ answers with an error */
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 516];
+            let len = receiver.recv(&mut buf).unwrap();
+            receiver.send(b"\x00\x05\x00\x08Option negotiation refused\x00").unwrap();
+            (buf[..len].to_vec(), receiver)
+        });
+        let err = tftp.ack_options(&sender, &options, true).unwrap_err();
+        assert_eq!(err.to_string(), "client rejected the negotiated options: Option negotiation refused");
+        let (oack, receiver) = peer.join().unwrap();
+        assert_eq!(oack, b"\x00\x06blksize\x001428\x00");
+
+        /* nothing else is sent afterwards */
+        receiver.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(receiver.recv(&mut [0; 516]).is_err());
+
+        /* the same for the OACK of a WRQ, answered instead of sending the first block */
+        tftp.ack_options(&sender, &options, false).unwrap();
+        assert_eq!(receiver.recv(&mut [0; 516]).unwrap(), oack.len());
+        receiver.send(b"\x00\x05\x00\x08Option negotiation refused\x00").unwrap();
+        let err = tftp.recv_file(&sender, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.to_string(), "client rejected the negotiated options: Option negotiation refused");
+        assert!(receiver.recv(&mut [0; 516]).is_err());
+    }
+
+    #[test]
+    fn test_cancel_token() {
+        let (sender, receiver) = socket_pair();
+        let token = Arc::new(AtomicBool::new(false));
+        let mut tftp = Tftp::new();
+        tftp.set_cancel_token(Some(Arc::clone(&token)));
+
+        /* cancelled from the peer thread after the first block of a download */
+        let peer_token = Arc::clone(&token);
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 516];
+            assert_eq!(receiver.recv(&mut buf).unwrap(), 516);
+            peer_token.store(true, Ordering::SeqCst);
+            receiver.send(b"\x00\x04\x00\x01").unwrap();
+            let len = receiver.recv(&mut buf).unwrap();
+            (buf[..len].to_vec(), receiver)
+        });
+        let err = tftp.send_slice(&sender, &[0; 2000]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(Cancelled::of(&err).is_some());
+        let (error, receiver) = peer.join().unwrap();
+        assert_eq!(error, b"\x00\x05\x00\x00Transfer cancelled\x00");
+
+        /* an upload is stopped before waiting for the next block */
+        let err = tftp.recv_file(&sender, &mut Vec::new()).unwrap_err();
+        assert!(Cancelled::of(&err).is_some());
+        assert_eq!(receiver.recv(&mut [0; 516]).unwrap(), error.len());
+    }
+
+    /// Answers with scripted packets, `None` standing for a lost one,
+    /// and records the read timeouts set on it.
+    struct LossyTransport {
+        replies: RefCell<Vec<Option<Vec<u8>>>>,
+        timeouts: RefCell<Vec<Duration>>,
+    }
+
+    impl Transport for LossyTransport {
+        fn send(&self, buf: &[u8]) -> Result<usize, io::Error> {
+            Ok(buf.len())
+        }
+
+        fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+            match self.replies.borrow_mut().remove(0) {
+                Some(reply) => {
+                    buf[..reply.len()].copy_from_slice(&reply);
+                    Ok(reply.len())
+                }
+                None => Err(io::Error::new(io::ErrorKind::WouldBlock, "lost")),
+            }
+        }
+
+        fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+            self.timeouts.borrow_mut().push(timeout.unwrap());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_backoff() {
+        let secs = Duration::from_secs;
+        let lossy = |replies: Vec<Option<&[u8]>>| LossyTransport {
+            replies: RefCell::new(replies.into_iter().map(|r| r.map(<[u8]>::to_vec)).collect()),
+            timeouts: RefCell::new(Vec::new()),
+        };
+        let mut tftp = Tftp::new();
+        tftp.set_backoff(2, Some(secs(10)));
+
+        /* the wait doubles with each lost packet, up to the maximum */
+        let sock = lossy(vec![None, None, Some(b"\x00\x04\x00\x01")]);
+        assert_eq!(tftp.send_slice(&sock, b"data").unwrap(), 4);
+        assert_eq!(*sock.timeouts.borrow(), [secs(6), secs(10), secs(3)]);
+
+        /* and starts over at the negotiated timeout after progress */
+        let sock = lossy(vec![None, Some(b"\x00\x04\x00\x01"), None, Some(b"\x00\x04\x00\x02")]);
+        assert_eq!(tftp.send_slice(&sock, &[0; 600]).unwrap(), 600);
+        assert_eq!(*sock.timeouts.borrow(), [secs(6), secs(3), secs(6), secs(3)]);
+
+        /* uploads back off the same way */
+        let sock = lossy(vec![None, None, Some(b"\x00\x03\x00\x01data")]);
+        assert_eq!(tftp.recv_file(&sock, &mut Vec::new()).unwrap(), 4);
+        assert_eq!(*sock.timeouts.borrow(), [secs(6), secs(10), secs(3)]);
+
+        /* without a multiplier, the timeout is never changed */
+        tftp.set_backoff(1, None);
+        let sock = lossy(vec![None, None, Some(b"\x00\x04\x00\x01")]);
+        assert_eq!(tftp.send_slice(&sock, b"data").unwrap(), 4);
+        assert!(sock.timeouts.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_transfer_timeout() {
+        let (sender, receiver) = socket_pair();
+
+        /* acknowledges every block, but slowly */
+        let peer = thread::spawn(move || {
+            let mut buf = [0; 516];
+            while let Ok(len) = receiver.recv(&mut buf) {
+                if buf[1] != Opcode::DATA as u8 || len < 516 {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+                receiver.send(&[0, Opcode::ACK as u8, buf[2], buf[3]]).unwrap();
+            }
+        });
+
+        let mut tftp = Tftp::new();
+        tftp.set_transfer_timeout(Some(Duration::from_millis(120)));
+        let err = tftp.send_slice(&sender, &[0; 512 * 10]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(err.to_string(), "transfer timeout");
+        peer.join().unwrap();
+    }
 }
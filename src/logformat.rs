@@ -0,0 +1,157 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! The line logged for each transfer, see `--log-format`.
+//!
+//! A template is plain text with the placeholders `{time}` (UTC, RFC 3339),
+//! `{client}`, `{op}` (`read` or `write`), `{file}`, `{bytes}`, `{duration}`
+//! (in seconds), `{result}` (`ok` or the error message) and `{message}` (the
+//! line logged without a template). `{{` and `}}` stand for literal braces.
+
+use std::fmt::Write as _;
+
+use crate::accesslog::{timestamp, Record};
+
+/// Logs the same lines as before templates existed.
+pub(crate) const DEFAULT_LOG_FORMAT: &str = "{message}";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Field {
+    Time,
+    Client,
+    Op,
+    File,
+    Bytes,
+    Duration,
+    Result,
+    Message,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LogFormat {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl Default for LogFormat {
+    fn default() -> LogFormat {
+        LogFormat::parse(DEFAULT_LOG_FORMAT).expect("valid default template")
+    }
+}
+
+impl LogFormat {
+    /// Parses `template`, failing on unknown placeholders and unmatched braces.
+    pub(crate) fn parse(template: &str) -> Result<LogFormat, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest.find('}').ok_or_else(|| String::from("unclosed placeholder"))?;
+                    let field = match &rest[..end] {
+                        "time" => Field::Time,
+                        "client" => Field::Client,
+                        "op" => Field::Op,
+                        "file" => Field::File,
+                        "bytes" => Field::Bytes,
+                        "duration" => Field::Duration,
+                        "result" => Field::Result,
+                        "message" => Field::Message,
+                        name => return Err(format!("unknown placeholder {{{}}}", name)),
+                    };
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(String::from("unmatched }")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(LogFormat { template: template.to_string(), parts })
+    }
+
+    pub(crate) fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// Formats the line for `record`; `message` is the one logged by default.
+    pub(crate) fn format(&self, record: &Record, message: &str) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            let _ = match part {
+                Part::Text(text) => write!(out, "{}", text),
+                Part::Field(Field::Time) => write!(out, "{}", timestamp(record.time)),
+                Part::Field(Field::Client) => write!(out, "{}", record.client),
+                Part::Field(Field::Op) => write!(out, "{}", record.direction),
+                Part::Field(Field::File) => write!(out, "{}", record.filename),
+                Part::Field(Field::Bytes) => write!(out, "{}", record.bytes),
+                Part::Field(Field::Duration) => write!(out, "{:.3}", record.duration.as_secs_f64()),
+                Part::Field(Field::Result) => write!(out, "{}", record.error.as_deref().unwrap_or("ok")),
+                Part::Field(Field::Message) => write!(out, "{}", message),
+            };
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(LogFormat::parse("{{{op}}} {file}").unwrap().parts,
+                   [Part::Text(String::from("{")), Part::Field(Field::Op), Part::Text(String::from("} ")), Part::Field(Field::File)]);
+        assert_eq!(LogFormat::parse("").unwrap().parts, []);
+        assert_eq!(LogFormat::parse("{op} {size}").unwrap_err(), "unknown placeholder {size}");
+        assert_eq!(LogFormat::parse("{File}").unwrap_err(), "unknown placeholder {File}");
+        assert_eq!(LogFormat::parse("{op").unwrap_err(), "unclosed placeholder");
+        assert_eq!(LogFormat::parse("op}").unwrap_err(), "unmatched }");
+    }
+
+    #[test]
+    fn test_format() {
+        let mut record = Record {
+            time: UNIX_EPOCH,
+            client: "[::1]:1234".parse().unwrap(),
+            direction: "read",
+            filename: "pxe/boot.bin",
+            bytes: 1024,
+            duration: Duration::from_millis(1500),
+            error_code: None,
+            error: None,
+        };
+        let format = LogFormat::parse("{time} {client} {op} {file} {bytes} {duration}s {result} {{file}}").unwrap();
+        assert_eq!(format.format(&record, "Sent pxe/boot.bin (1024 bytes) to [::1]:1234."), "1970-01-01T00:00:00.000Z [::1]:1234 read pxe/boot.bin 1024 1.500s ok {file}");
+
+        record.error = Some(String::from("Sending pxe/boot.bin to [::1]:1234 failed (Timeout)."));
+        assert_eq!(LogFormat::parse("{op} {result}").unwrap().format(&record, ""),
+                   "read Sending pxe/boot.bin to [::1]:1234 failed (Timeout).");
+        let message = record.error.clone().unwrap();
+        assert_eq!(LogFormat::default().format(&record, &message), message);
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! SHA-256 checksums of the files that may be served, see `--manifest`.
+//!
+//! The manifest uses the format of `sha256sum`: one file per line, with the
+//! hex-encoded checksum, whitespace, and the file name relative to the served
+//! directory (optionally marked as binary by a leading `*`).
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use sha2::{Digest, Sha256};
+
+use crate::storage::ReadSeek;
+
+type Checksum = [u8; 32];
+
+/// Identifies the version of a served file. The change time can't be set
+/// from user space, so it also tells if the mtime was restored after a change.
+#[derive(PartialEq, Eq)]
+struct Stamp {
+    dev: u64,
+    ino: u64,
+    len: u64,
+    modified: SystemTime,
+    changed: (i64, i64),
+}
+
+impl Stamp {
+    fn of(metadata: &fs::Metadata) -> Result<Stamp, io::Error> {
+        Ok(Stamp {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            changed: (metadata.ctime(), metadata.ctime_nsec()),
+        })
+    }
+}
+
+struct Verified {
+    stamp: Stamp,
+    checksum: Checksum,
+}
+
+pub(crate) struct Manifest {
+    checksums: HashMap<PathBuf, Checksum>,
+    /* checksums of the served files while they are unchanged,
+       so that a file is not hashed again for every transfer */
+    verified: Mutex<HashMap<PathBuf, Verified>>,
+}
+
+fn parse_checksum(hex: &str) -> Option<Checksum> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut checksum = [0; 32];
+    for (i, byte) in checksum.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(checksum)
+}
+
+/// Drops `.` components, so that `./boot.bin` matches a request of `boot.bin`.
+fn normalize(path: &Path) -> PathBuf {
+    path.components().filter(|c| *c != Component::CurDir).collect()
+}
+
+impl Manifest {
+    pub(crate) fn open(path: &Path) -> Result<Manifest, io::Error> {
+        Manifest::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the lines of a manifest; blank lines and comments starting with
+    /// `#` are ignored.
+    pub(crate) fn parse(content: &str) -> Result<Manifest, io::Error> {
+        let mut checksums = HashMap::new();
+        for (nr, line) in content.lines().enumerate() {
+            let line = line.trim_end();
+            if line.trim_start().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid entry in line {}", nr + 1));
+            let (hex, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let name = name.trim_start();
+            let name = name.strip_prefix('*').unwrap_or(name);
+            if name.is_empty() {
+                return Err(invalid());
+            }
+            checksums.insert(normalize(Path::new(name)), parse_checksum(hex).ok_or_else(invalid)?);
+        }
+        Ok(Manifest {
+            checksums,
+            verified: Default::default(),
+        })
+    }
+
+    /// Checks that `file`, served as `path`, is listed in the manifest and
+    /// its content has the listed checksum. Leaves `file` at its start.
+    /// The checksums of local files are kept until they are modified.
+    pub(crate) fn verify(&self, path: &Path, file: &mut dyn ReadSeek) -> Result<bool, io::Error> {
+        let path = normalize(path);
+        let expected = match self.checksums.get(&path) {
+            Some(checksum) => checksum,
+            None => return Ok(false),
+        };
+
+        let stamp = match file.as_file() {
+            Some(local) => Some(Stamp::of(&local.metadata()?)?),
+            None => None,
+        };
+        if let Some(stamp) = &stamp {
+            if let Some(verified) = self.verified.lock().unwrap().get(&path) {
+                if verified.stamp == *stamp {
+                    return Ok(verified.checksum == *expected);
+                }
+            }
+        }
+
+        let mut hasher = Sha256::new();
+        io::copy(file, &mut hasher)?;
+        file.rewind()?;
+        let mut checksum = [0; 32];
+        checksum.copy_from_slice(&hasher.finalize()[..]);
+
+        if let Some(stamp) = stamp {
+            self.verified.lock().unwrap().insert(path, Verified { stamp, checksum });
+        }
+        Ok(checksum == *expected)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::{Seek, Write};
+
+    /* sha256 of "data" */
+    const DATA_SHA256: &str = "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7";
+
+    #[test]
+    fn test_parse() {
+        let manifest = Manifest::parse(&format!("# firmware\n{}  boot.bin\n\n{} *./pxe/boot.bin\n", DATA_SHA256, DATA_SHA256.to_uppercase())).unwrap();
+        assert_eq!(manifest.checksums.len(), 2);
+        assert!(manifest.checksums.contains_key(Path::new("pxe/boot.bin")));
+
+        for content in ["boot.bin", "0123  boot.bin", &format!("{}  ", DATA_SHA256), &format!("{}x boot.bin", &DATA_SHA256[1..])] {
+            assert_eq!(Manifest::parse(content).err().map(|err| err.kind()), Some(io::ErrorKind::InvalidData), "{}", content);
+        }
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = env::temp_dir().join(format!("rtftp-manifest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("boot.bin"), b"data").unwrap();
+        fs::write(dir.join("other.bin"), b"data").unwrap();
+        let manifest = Manifest::parse(&format!("{}  boot.bin\n", DATA_SHA256)).unwrap();
+
+        let mut file = File::open(dir.join("boot.bin")).unwrap();
+        assert!(manifest.verify(Path::new("boot.bin"), &mut file).unwrap());
+        assert_eq!(file.stream_position().unwrap(), 0);
+        assert!(!manifest.verify(Path::new("other.bin"), &mut File::open(dir.join("other.bin")).unwrap()).unwrap());
+
+        /* the checksum is kept while the file is unchanged */
+        assert!(manifest.verify(Path::new("boot.bin"), &mut file).unwrap());
+
+        /* restoring mtime and size after a change still changes the ctime, which
+           is taken from a clock with a resolution of a few milliseconds */
+        let modified = file.metadata().unwrap().modified().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        let mut tampered = fs::OpenOptions::new().write(true).open(dir.join("boot.bin")).unwrap();
+        tampered.write_all(b"evil").unwrap();
+        tampered.set_modified(modified).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 4);
+        assert!(!manifest.verify(Path::new("boot.bin"), &mut file).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
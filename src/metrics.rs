@@ -0,0 +1,194 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Transfer counters of the server, see `--metrics-addr`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    active: AtomicU64,
+    rate_limited: AtomicU64,
+    /* failed requests by io::ErrorKind */
+    errors: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Counts a transfer as active while it is alive.
+pub(crate) struct ActiveTransfer<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Drop for ActiveTransfer<'_> {
+    fn drop(&mut self) {
+        self.metrics.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Metrics {
+    pub(crate) fn active(&self) -> ActiveTransfer<'_> {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        ActiveTransfer { metrics: self }
+    }
+
+    pub(crate) fn sent(&self, bytes: u64) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn received(&self, bytes: u64) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn error(&self, kind: io::ErrorKind) {
+        *self.errors.lock().unwrap().entry(format!("{:?}", kind)).or_insert(0) += 1;
+    }
+
+    /// Formats the counters as `key=value` pairs on one line, for the status
+    /// of the control socket.
+    pub(crate) fn summary(&self) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let errors: u64 = self.errors.lock().unwrap().values().sum();
+        format!("active={} reads={} writes={} bytes_sent={} bytes_received={} rate_limited={} errors={}",
+                load(&self.active), load(&self.reads), load(&self.writes), load(&self.bytes_sent),
+                load(&self.bytes_received), load(&self.rate_limited), errors)
+    }
+
+    /// Formats the counters in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, values: &[(String, u64)]| {
+            let _ = writeln!(out, "# HELP rtftp_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE rtftp_{} {}", name, kind);
+            for (labels, value) in values {
+                let _ = writeln!(out, "rtftp_{}{} {}", name, labels, value);
+            }
+        };
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        metric("transfers_total", "counter", "Completed transfers.", &[
+            (String::from("{direction=\"read\"}"), load(&self.reads)),
+            (String::from("{direction=\"write\"}"), load(&self.writes)),
+        ]);
+        metric("bytes_sent_total", "counter", "Bytes sent in completed read requests.",
+               &[(String::new(), load(&self.bytes_sent))]);
+        metric("bytes_received_total", "counter", "Bytes received in completed write requests.",
+               &[(String::new(), load(&self.bytes_received))]);
+        metric("active_transfers", "gauge", "Requests currently being handled.",
+               &[(String::new(), load(&self.active))]);
+        metric("rate_limited_total", "counter", "Requests dropped by the client rate limit.",
+               &[(String::new(), load(&self.rate_limited))]);
+
+        let errors: Vec<(String, u64)> = self.errors.lock().unwrap().iter()
+            .map(|(kind, count)| (format!("{{kind=\"{}\"}}", kind), *count))
+            .collect();
+        metric("errors_total", "counter", "Failed requests by error kind.", &errors);
+
+        out
+    }
+}
+
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<(), io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+
+    /* the request itself is irrelevant, every path serves the counters */
+    let mut request = [0; 1024];
+    let _ = stream.read(&mut request)?;
+
+    let body = metrics.render();
+    write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+           body.len(), body)?;
+    stream.flush()
+}
+
+/// Serves the counters over HTTP on `listener` in a background thread.
+/// Each connection is answered in a thread of its own, so that clients
+/// sending nothing don't hold up the others.
+pub(crate) fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let metrics = Arc::clone(&metrics);
+                    thread::spawn(move || {
+                        if let Err(err) = respond(stream, &metrics) {
+                            println!("Serving metrics failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => println!("Accepting metrics connection failed: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.sent(100);
+        metrics.sent(50);
+        metrics.received(10);
+        metrics.error(io::ErrorKind::NotFound);
+        metrics.error(io::ErrorKind::NotFound);
+        metrics.rate_limited();
+
+        let active = metrics.active();
+        let text = metrics.render();
+        assert!(text.contains("# TYPE rtftp_transfers_total counter\n"));
+        assert!(text.contains("rtftp_transfers_total{direction=\"read\"} 2\n"));
+        assert!(text.contains("rtftp_transfers_total{direction=\"write\"} 1\n"));
+        assert!(text.contains("rtftp_bytes_sent_total 150\n"));
+        assert!(text.contains("rtftp_bytes_received_total 10\n"));
+        assert!(text.contains("rtftp_active_transfers 1\n"));
+        assert!(text.contains("rtftp_rate_limited_total 1\n"));
+        assert!(text.contains("rtftp_errors_total{kind=\"NotFound\"} 2\n"));
+
+        assert_eq!(metrics.summary(), "active=1 reads=2 writes=1 bytes_sent=150 bytes_received=10 rate_limited=1 errors=2");
+
+        drop(active);
+        assert!(metrics.render().contains("rtftp_active_transfers 0\n"));
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("[::1]:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.sent(42);
+        serve(listener, Arc::clone(&metrics));
+
+        /* a client sending nothing does not delay the others */
+        let _idle = TcpStream::connect(addr).unwrap();
+        let start = std::time::Instant::now();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(response.ends_with(&metrics.render()));
+        assert!(response.contains("rtftp_bytes_sent_total 42\n"));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
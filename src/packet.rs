@@ -0,0 +1,357 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Encoding and decoding of TFTP packets (RFC 1350, with the OACK of RFC 2347),
+//! the framing used by [`Tftp`](crate::Tftp). Useful for building other TFTP
+//! tools on top of the crate, like test clients or proxies.
+
+use std::collections::HashMap;
+use std::io;
+
+use crate::Opcode;
+
+/// Largest block size that can be negotiated (RFC 2348).
+pub const MAX_BLKSIZE: usize = 65464;
+
+/// Largest packet accepted by [`parse_packet`]: a DATA packet carrying a
+/// block of `MAX_BLKSIZE` bytes.
+pub const MAX_PACKET_SIZE: usize = 4 + MAX_BLKSIZE;
+
+/// Most options parsed from a request or OACK; further ones are ignored.
+pub const MAX_OPTIONS: usize = 16;
+
+/// Options of a request are only parsed from its first `MAX_REQUEST_SIZE`
+/// bytes (including the opcode); those ending after it are ignored.
+pub const MAX_REQUEST_SIZE: usize = 2048;
+
+/// A decoded TFTP packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Packet {
+    /// read request with the lowercased mode and the requested options
+    Rrq { filename: String, mode: String, options: HashMap<String, String> },
+    /// write request, like `Rrq`
+    Wrq { filename: String, mode: String, options: HashMap<String, String> },
+    Data { block: u16, data: Vec<u8> },
+    Ack { block: u16 },
+    Error { code: u16, message: String },
+    /// acknowledged options
+    Oack { options: HashMap<String, String> },
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn header(opcode: Opcode, capacity: usize) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(2 + capacity);
+    buf.extend((opcode as u16).to_be_bytes().iter());
+    buf
+}
+
+/// Appends `s` with its terminating NUL.
+pub(crate) fn append_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(s.bytes());
+    buf.push(0x00);
+}
+
+fn append_options(buf: &mut Vec<u8>, options: &HashMap<String, String>) {
+    for (key, val) in options {
+        append_str(buf, key);
+        append_str(buf, val);
+    }
+}
+
+/// Encodes a RRQ or WRQ (`opcode`) for `filename` in `mode`, requesting `options`.
+pub fn encode_request(opcode: Opcode, filename: &str, mode: &str, options: &HashMap<String, String>) -> Vec<u8> {
+    let mut buf = header(opcode, 512);
+    append_str(&mut buf, filename);
+    append_str(&mut buf, mode);
+    append_options(&mut buf, options);
+    buf
+}
+
+pub fn encode_data(block: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = header(Opcode::DATA, 2 + data.len());
+    buf.extend(block.to_be_bytes().iter());
+    buf.extend(data.iter());
+    buf
+}
+
+pub fn encode_ack(block: u16) -> Vec<u8> {
+    let mut buf = header(Opcode::ACK, 2);
+    buf.extend(block.to_be_bytes().iter());
+    buf
+}
+
+pub fn encode_error(code: u16, msg: &str) -> Vec<u8> {
+    let mut buf = header(Opcode::ERROR, 2 + msg.len() + 1);
+    buf.extend(code.to_be_bytes().iter());
+    append_str(&mut buf, msg);
+    buf
+}
+
+pub fn encode_oack(options: &HashMap<String, String>) -> Vec<u8> {
+    let mut buf = header(Opcode::OACK, 512);
+    append_options(&mut buf, options);
+    buf
+}
+
+/// Returns the bytes up to the NUL at the start of `buf`,
+/// or `None` if the terminator is missing.
+fn get_bytes(buf: &[u8]) -> Option<&[u8]> {
+    let len = buf.iter().position(|&x| x == 0)?;
+    Some(&buf[..len])
+}
+
+/// Returns the NUL-terminated string at the start of `buf`,
+/// or `None` if the terminator is missing or it is not valid UTF-8.
+pub(crate) fn get_str(buf: &[u8]) -> Option<String> {
+    String::from_utf8(get_bytes(buf)?.to_vec()).ok()
+}
+
+/// Parses NUL-terminated key/value pairs. Parsing stops at the first
+/// incomplete pair and after `MAX_OPTIONS` pairs. Keys are case-insensitive
+/// and returned lowercased; later occurrences of a key replace earlier ones.
+pub fn parse_options(buf: &[u8]) -> HashMap<String, String> {
+    let mut options = HashMap::new();
+
+    let mut pos = 0;
+    for _ in 0..MAX_OPTIONS {
+        let key = match get_str(&buf[pos..]) {
+            Some(k) => k,
+            None => break,
+        };
+        pos += key.len() + 1;
+
+        let val = match get_str(&buf[pos..]) {
+            Some(v) => v,
+            None => break,
+        };
+        pos += val.len() + 1;
+
+        options.insert(key.to_lowercase(), val);
+    }
+
+    options
+}
+
+/// Parses the body of a RRQ or WRQ (everything after the opcode) into
+/// filename, lowercased mode and options (see `parse_options` and
+/// `MAX_REQUEST_SIZE`). Fails with `InvalidData` if filename or mode are
+/// missing, not NUL-terminated or not valid UTF-8.
+pub fn parse_request(buf: &[u8]) -> Result<(String, String, HashMap<String, String>), io::Error> {
+    let (filename, mode, options) = parse_request_bytes(buf, None)?;
+    let filename = String::from_utf8(filename).map_err(|_| invalid("invalid data received"))?;
+    Ok((filename, mode, options))
+}
+
+/// Filename, mode and options of a request.
+type Request<T> = (T, String, HashMap<String, String>);
+
+/// Like [`parse_request`], but returns the filename as it was sent,
+/// which does not have to be UTF-8. With a `default_mode`, a mode that is
+/// missing, empty, not NUL-terminated or not UTF-8 is replaced by it instead
+/// of failing; options following it are ignored then.
+pub(crate) fn parse_request_bytes(buf: &[u8], default_mode: Option<&str>) -> Result<Request<Vec<u8>>, io::Error> {
+    let dataerr = || invalid("invalid data received");
+
+    let mut pos = 0;
+    let filename = get_bytes(&buf[pos..]).ok_or_else(dataerr)?;
+    pos += filename.len() + 1;
+
+    let mode = match (get_str(&buf[pos..]), default_mode) {
+        (Some(mode), None) => mode,
+        (Some(mode), Some(_)) if !mode.is_empty() => mode,
+        (_, Some(default)) => return Ok((filename.to_vec(), default.to_string(), HashMap::new())),
+        (None, None) => return Err(dataerr()),
+    };
+    pos += mode.len() + 1;
+
+    /* the opcode preceding `buf` counts towards the size */
+    let end = buf.len().min(MAX_REQUEST_SIZE - 2).max(pos);
+    let options = parse_options(&buf[pos..end]);
+
+    Ok((filename.to_vec(), mode.to_lowercase(), options))
+}
+
+/// Decodes a packet. Fails with `InvalidData` for unknown opcodes, packets
+/// too short for their header, DATA larger than `MAX_PACKET_SIZE`, ACKs with
+/// trailing bytes and malformed requests. A missing NUL after the message of
+/// an ERROR is tolerated, as some implementations leave it out.
+pub fn parse_packet(buf: &[u8]) -> Result<Packet, io::Error> {
+    if buf.len() < 2 {
+        return Err(invalid("truncated packet"));
+    }
+    let opcode = Opcode::try_from(u16::from_be_bytes([buf[0], buf[1]]))?;
+    let body = &buf[2..];
+
+    /* the block number or error code following the opcode */
+    let number = || match body {
+        [a, b, ..] => Ok(u16::from_be_bytes([*a, *b])),
+        _ => Err(invalid("truncated packet")),
+    };
+
+    match opcode {
+        Opcode::RRQ | Opcode::WRQ => {
+            let (filename, mode, options) = parse_request(body)?;
+            match opcode {
+                Opcode::RRQ => Ok(Packet::Rrq { filename, mode, options }),
+                _ => Ok(Packet::Wrq { filename, mode, options }),
+            }
+        }
+        Opcode::DATA => {
+            let block = number()?;
+            if buf.len() > MAX_PACKET_SIZE {
+                return Err(invalid("oversized packet"));
+            }
+            Ok(Packet::Data { block, data: body[2..].to_vec() })
+        }
+        Opcode::ACK => {
+            let block = number()?;
+            if body.len() > 2 {
+                return Err(invalid("oversized packet"));
+            }
+            Ok(Packet::Ack { block })
+        }
+        Opcode::ERROR => {
+            let code = number()?;
+            let msg = body[2..].split(|&b| b == 0).next().unwrap_or_default();
+            let message = String::from_utf8(msg.to_vec()).map_err(|_| invalid("invalid error message"))?;
+            Ok(Packet::Error { code, message })
+        }
+        Opcode::OACK => Ok(Packet::Oack { options: parse_options(body) }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_get_str() {
+        let mut buf = Vec::with_capacity(100);
+        assert_eq!(get_str(&buf), None);
+
+        buf.extend("key".bytes());
+        assert_eq!(get_str(&buf), None);
+
+        buf.push(0x00);
+        buf.extend("value".bytes());
+        buf.push(0x00);
+        /* empty option */
+        buf.push(0x00);
+
+        let mut pos = 0;
+        let val = get_str(&buf).unwrap();
+        assert_eq!(val, "key");
+        pos += val.len() + 1;
+        let val = get_str(&buf[pos..]).unwrap();
+        assert_eq!(val, "value");
+        pos += val.len() + 1;
+        let val = get_str(&buf[pos..]).unwrap();
+        assert_eq!(val, "");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let opts = options(&[("blksize", "1428"), ("tsize", "0")]);
+        let packets = [
+            (encode_request(Opcode::RRQ, "boot/pxelinux.0", "octet", &opts),
+             Packet::Rrq { filename: String::from("boot/pxelinux.0"), mode: String::from("octet"), options: opts.clone() }),
+            (encode_request(Opcode::WRQ, "upload", "netascii", &HashMap::new()),
+             Packet::Wrq { filename: String::from("upload"), mode: String::from("netascii"), options: HashMap::new() }),
+            (encode_data(7, b"data"), Packet::Data { block: 7, data: b"data".to_vec() }),
+            (encode_data(65535, b""), Packet::Data { block: 65535, data: Vec::new() }),
+            (encode_data(1, &[0xaa; MAX_BLKSIZE]), Packet::Data { block: 1, data: vec![0xaa; MAX_BLKSIZE] }),
+            (encode_ack(0), Packet::Ack { block: 0 }),
+            (encode_error(1, "File not found"), Packet::Error { code: 1, message: String::from("File not found") }),
+            (encode_error(0, ""), Packet::Error { code: 0, message: String::new() }),
+            (encode_oack(&opts), Packet::Oack { options: opts.clone() }),
+        ];
+        for (buf, packet) in packets {
+            assert_eq!(parse_packet(&buf).unwrap(), packet);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode_data(1, b"abc"), b"\x00\x03\x00\x01abc");
+        assert_eq!(encode_ack(258), b"\x00\x04\x01\x02");
+        assert_eq!(encode_error(2, "Permission denied"), b"\x00\x05\x00\x02Permission denied\x00");
+        assert_eq!(encode_request(Opcode::RRQ, "file", "octet", &HashMap::new()), b"\x00\x01file\x00octet\x00");
+        assert_eq!(encode_oack(&options(&[("blksize", "512")])), b"\x00\x06blksize\x00512\x00");
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let kind = |buf: &[u8]| parse_packet(buf).unwrap_err().kind();
+        for buf in [&b""[..], b"\x00", b"\x00\x03", b"\x00\x03\x00", b"\x00\x04\x00", b"\x00\x05\x00"] {
+            assert_eq!(kind(buf), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+        /* unknown opcodes */
+        assert_eq!(kind(b"\x00\x00\x00\x01"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"\x00\x07\x00\x01"), io::ErrorKind::InvalidData);
+
+        /* oversized */
+        assert_eq!(kind(&encode_data(1, &[0; MAX_BLKSIZE + 1])), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"\x00\x04\x00\x01\x00"), io::ErrorKind::InvalidData);
+
+        /* requests without mode or terminator */
+        assert_eq!(kind(b"\x00\x01file"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"\x00\x02file\x00octet"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"\x00\x01fi\xffle\x00octet\x00"), io::ErrorKind::InvalidData);
+        assert_eq!(kind(b"\x00\x05\x00\x00\xff\x00"), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        /* an ERROR without terminating NUL, and text after it */
+        assert_eq!(parse_packet(b"\x00\x05\x00\x01not found").unwrap(),
+                   Packet::Error { code: 1, message: String::from("not found") });
+        assert_eq!(parse_packet(b"\x00\x05\x00\x01not found\x00junk").unwrap(),
+                   Packet::Error { code: 1, message: String::from("not found") });
+
+        /* an incomplete trailing option is ignored */
+        assert_eq!(parse_packet(b"\x00\x01file\x00OCTET\x00BlkSize\x00512\x00tsize\x00").unwrap(),
+                   Packet::Rrq { filename: String::from("file"), mode: String::from("octet"), options: options(&[("blksize", "512")]) });
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let mut buf = encode_request(Opcode::RRQ, "file", "octet", &options(&[("blksize", "1024")]));
+        for i in 0..10000 {
+            append_str(&mut buf, &format!("x-{}", i));
+            append_str(&mut buf, "1");
+        }
+        let parsed = match parse_packet(&buf).unwrap() {
+            Packet::Rrq { options, .. } => options,
+            packet => panic!("unexpected packet {:?}", packet),
+        };
+        assert_eq!(parsed.len(), MAX_OPTIONS);
+        assert_eq!(parsed["blksize"], "1024");
+        assert!(parsed.contains_key("x-14") && !parsed.contains_key("x-15"));
+
+        /* options ending after the first MAX_REQUEST_SIZE bytes */
+        let padded = |len: usize| {
+            let mut buf = encode_request(Opcode::RRQ, "file", "octet", &options(&[("pad", "")]));
+            buf.pop();
+            buf.resize(len - "tsize\x000\x00".len() - 1, b'x');
+            buf.push(0);
+            append_str(&mut buf, "tsize");
+            append_str(&mut buf, "0");
+            assert_eq!(buf.len(), len);
+            parse_request(&buf[2..]).unwrap().2
+        };
+        assert_eq!(padded(MAX_REQUEST_SIZE)["tsize"], "0");
+        assert!(!padded(MAX_REQUEST_SIZE + 1).contains_key("tsize"));
+        /* a long filename leaves no room for options, but is still accepted */
+        let buf = encode_request(Opcode::RRQ, &"a".repeat(4000), "octet", &options(&[("tsize", "0")]));
+        assert_eq!(parse_request(&buf[2..]).unwrap(), ("a".repeat(4000), String::from("octet"), HashMap::new()));
+    }
+}
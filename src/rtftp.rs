@@ -0,0 +1,277 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, IsTerminal, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::fd::AsFd;
+use std::path::Path;
+use std::process;
+
+use getopts::Options;
+
+/* exit codes */
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Get,
+    Put,
+}
+
+struct Configuration {
+    command: Command,
+    mode: rtftp::Mode,
+    remote: SocketAddr,
+    remote_file: String,
+    /* None for stdin/stdout */
+    local_file: Option<String>,
+    blksize: usize,
+    progress: bool,
+}
+
+fn update_progress(current: u64, total: u64, last: u64) -> u64 {
+    if total == 0 {
+        /* unknown; remote does not support tsize */
+        return 0;
+    }
+    let onepercent = total / 100;
+    if current < total && current < last + onepercent {
+        /* not enough progress to warrant an update */
+        return last;
+    }
+
+    /* stdout may carry the transferred data */
+    eprint!("\r {}% ", 100 * current / total);
+    if current >= total {
+        eprint!("\r");
+    }
+    current
+}
+
+/// Splits `host[:port]:file` (with IPv6 hosts in brackets) into the
+/// host with port and the file name.
+fn split_remote(spec: &str, default_port: u16) -> Option<(String, String)> {
+    let (host, rest) = match spec.strip_prefix('[') {
+        Some(spec) => {
+            let (host, rest) = spec.split_once(']')?;
+            (host, rest.strip_prefix(':')?)
+        }
+        None => spec.split_once(':')?,
+    };
+    if host.is_empty() {
+        return None;
+    }
+
+    let (port, file) = match rest.split_once(':') {
+        Some((port, file)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => (port.parse().ok()?, file),
+        _ => (default_port, rest),
+    };
+    if file.is_empty() {
+        return None;
+    }
+
+    let host = if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) };
+    Some((host, file.to_string()))
+}
+
+fn request_options(blksize: usize, tsize: Option<u64>) -> HashMap<String, String> {
+    let mut options = HashMap::new();
+    options.insert(String::from("blksize"), format!("{}", blksize));
+    options.insert(String::from("timeout"), format!("{}", 3));
+    if let Some(tsize) = tsize {
+        options.insert(String::from("tsize"), format!("{}", tsize));
+    }
+    options
+}
+
+fn get(tftp: &mut rtftp::Tftp, conf: &Configuration) -> Result<u64, io::Error> {
+    /* tsize 0 asks the server for the size of the file */
+    let options = request_options(conf.blksize, Some(0));
+    match &conf.local_file {
+        Some(path) => tftp.get(conf.remote, &conf.remote_file, File::create(path)?, &options),
+        None => {
+            let mut stdout = BufWriter::new(io::stdout().lock());
+            let size = tftp.get(conf.remote, &conf.remote_file, &mut stdout, &options)?;
+            stdout.flush()?;
+            Ok(size)
+        }
+    }
+}
+
+fn put(tftp: &mut rtftp::Tftp, conf: &Configuration) -> Result<u64, io::Error> {
+    let mut file = match &conf.local_file {
+        Some(path) => File::open(path)?,
+        None => File::from(io::stdin().as_fd().try_clone_to_owned()?),
+    };
+    /* the size of pipes is not known in advance */
+    let tsize = match file.metadata()?.is_file() {
+        true => Some(tftp.transfersize(&mut file)?),
+        false => None,
+    };
+    let options = request_options(conf.blksize, tsize);
+    tftp.put(conf.remote, &conf.remote_file, file, &options)
+}
+
+fn run(conf: &Configuration) -> Result<String, io::Error> {
+    let mut tftp = rtftp::Tftp::new();
+    tftp.set_mode(conf.mode);
+    if conf.progress {
+        tftp.set_progress_callback(update_progress);
+    }
+
+    let local = conf.local_file.as_deref().unwrap_or("stdin");
+    let result = match conf.command {
+        Command::Get => get(&mut tftp, conf),
+        Command::Put => put(&mut tftp, conf),
+    };
+    match (&conf.command, result) {
+        (Command::Get, Ok(size)) => Ok(format!("Received {} ({} bytes) from {}.", conf.remote_file, size, conf.remote)),
+        (Command::Put, Ok(size)) => Ok(format!("Sent {} ({} bytes) to {}.", local, size, conf.remote)),
+        (Command::Get, Err(err)) => {
+            let error = format!("Receiving {} from {} failed ({}).", conf.remote_file, conf.remote, err);
+            Err(io::Error::new(err.kind(), error))
+        }
+        (Command::Put, Err(err)) => {
+            let error = format!("Sending {} to {} failed ({}).", local, conf.remote, err);
+            Err(io::Error::new(err.kind(), error))
+        }
+    }
+}
+
+fn usage(opts: &Options, program: &str, error: Option<String>) {
+    if let Some(err) = error {
+        eprintln!("{}\n", err);
+    }
+    let version = rtftp::VERSION.unwrap_or("");
+    let brief = format!("RusTFTP {}\n\n{} [options] get <remote>[:port]:<file> [<local>|-]\n{} [options] put <remote>[:port]:<file> [<local>|-]",
+                        version, program, program);
+    eprintln!("{}", opts.usage(&brief));
+}
+
+fn parse_commandline(args: &[String]) -> Result<Configuration, i32> {
+    let program = args[0].clone();
+    let blksize = 1428;
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "display usage information");
+    opts.optopt("b", "blksize", format!("negotiate a different block size (default: {})", blksize).as_ref(), "SIZE");
+    opts.optflag("n", "netascii", "use netascii mode (instead of octet)");
+    opts.optflag("q", "quiet", "don't report the progress on stderr");
+
+    let matches = opts.parse(&args[1..]).map_err(|err| {
+        usage(&opts, &program, Some(err.to_string()));
+        EXIT_USAGE
+    })?;
+    if matches.opt_present("h") {
+        usage(&opts, &program, None);
+        return Err(0);
+    }
+    let fail = |error: &str| {
+        usage(&opts, &program, Some(error.to_string()));
+        EXIT_USAGE
+    };
+
+    let (command, spec, local) = match matches.free.as_slice() {
+        [command, spec] => (command, spec, None),
+        [command, spec, local] => (command, spec, Some(local)),
+        _ => return Err(fail("A command and a remote file are required")),
+    };
+    let command = match command.as_str() {
+        "get" => Command::Get,
+        "put" => Command::Put,
+        _ => return Err(fail("Unknown command (expected get or put)")),
+    };
+
+    let (host, remote_file) = split_remote(spec, 69).ok_or_else(|| fail("Remote file must be given as <remote>[:port]:<file>"))?;
+    let remote = host.to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+                     .ok_or_else(|| fail("Failed to parse and lookup specified remote"))?;
+
+    /* without a local file, the remote name without directories is used */
+    let local_file = match local.map(String::as_str) {
+        Some("-") => None,
+        Some(local) => Some(local.to_string()),
+        None => match Path::new(&remote_file).file_name().and_then(|f| f.to_str()) {
+            Some(name) => Some(name.to_string()),
+            None => return Err(fail("Invalid path/filename")),
+        },
+    };
+
+    let blksize = matches.opt_get_default::<usize>("b", blksize).map_err(|err| fail(&err.to_string()))?;
+    let mode = if matches.opt_present("n") { rtftp::Mode::NETASCII } else { rtftp::Mode::OCTET };
+    let progress = !matches.opt_present("q") && io::stderr().is_terminal();
+
+    Ok(Configuration {
+        command,
+        mode,
+        remote,
+        remote_file,
+        local_file,
+        blksize,
+        progress,
+    })
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let conf = match parse_commandline(&args) {
+        Ok(c) => c,
+        Err(code) => process::exit(code),
+    };
+
+    match run(&conf) {
+        Ok(msg) => eprintln!("{}", msg),
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            process::exit(EXIT_FAILURE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_remote() {
+        let split = |spec| split_remote(spec, 69);
+        assert_eq!(split("localhost:pxelinux.0"), Some((String::from("localhost:69"), String::from("pxelinux.0"))));
+        assert_eq!(split("localhost:6969:boot/pxelinux.0"), Some((String::from("localhost:6969"), String::from("boot/pxelinux.0"))));
+        assert_eq!(split("192.0.2.7:a:b"), Some((String::from("192.0.2.7:69"), String::from("a:b"))));
+        assert_eq!(split("[::1]:file"), Some((String::from("[::1]:69"), String::from("file"))));
+        assert_eq!(split("[::1]:6969:file"), Some((String::from("[::1]:6969"), String::from("file"))));
+        assert_eq!(split("localhost"), None);
+        assert_eq!(split("localhost:"), None);
+        assert_eq!(split(":file"), None);
+        assert_eq!(split("[::1]file"), None);
+    }
+
+    #[test]
+    fn test_parse_commandline() {
+        let conf = parse_commandline(&args(&["rtftp", "get", "[::1]:6969:boot/pxelinux.0", "-"])).ok().unwrap();
+        assert_eq!(conf.command, Command::Get);
+        assert_eq!(conf.remote, "[::1]:6969".parse().unwrap());
+        assert_eq!(conf.remote_file, "boot/pxelinux.0");
+        assert_eq!(conf.local_file, None);
+
+        let conf = parse_commandline(&args(&["rtftp", "-b", "512", "put", "[::1]:boot/pxelinux.0"])).ok().unwrap();
+        assert_eq!(conf.command, Command::Put);
+        assert_eq!(conf.remote.port(), 69);
+        assert_eq!(conf.local_file.as_deref(), Some("pxelinux.0"));
+        assert_eq!(conf.blksize, 512);
+
+        assert_eq!(parse_commandline(&args(&["rtftp", "-h"])).err(), Some(0));
+        assert_eq!(parse_commandline(&args(&["rtftp", "get"])).err(), Some(EXIT_USAGE));
+        assert_eq!(parse_commandline(&args(&["rtftp", "fetch", "[::1]:file"])).err(), Some(EXIT_USAGE));
+        assert_eq!(parse_commandline(&args(&["rtftp", "get", "[::1]:file", "a", "b"])).err(), Some(EXIT_USAGE));
+    }
+}
@@ -0,0 +1,5993 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! TFTP server, as used by the `rtftpd` binary.
+
+use std::cell::Cell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsStr;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, UdpSocket};
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Component, Path, PathBuf};
+use std::process::{self, Child, Command};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use nix::libc;
+use nix::net::if_::if_nameindex;
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::socket::{bind, getsockopt, recvmsg, setsockopt, socket, sockopt, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag,
+                       SockType, SockaddrIn, SockaddrIn6, SockaddrStorage};
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{chdir, chroot, fork, setresgid, setresuid, ForkResult, Gid, Pid, Uid, ROOT};
+use threadpool::ThreadPool;
+
+use crate::accesslog::{AccessLog, Record};
+use crate::cache::FileCache;
+use crate::control::ControlSocket;
+use crate::logformat::LogFormat;
+use crate::manifest::Manifest;
+use crate::metrics::Metrics;
+use crate::storage::{FsStorage, ReadSeek, Storage};
+use crate::{PeerError, Throttle, Transport};
+
+#[cfg(feature = "landlock")]
+use landlock::{
+    Access, AccessFs, PathBeneath, PathFd, RestrictionStatus, RulesetAttr,
+    RulesetCreatedAttr, RulesetError, RulesetStatus, ABI
+};
+
+/* set from the signal handler when the server should stop accepting requests */
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+/* how often the accept loop wakes up to check for a pending shutdown */
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/* how often to check whether the transfers finished, with --shutdown-timeout */
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/* set from the signal handler when the configuration should be reloaded */
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_shutdown_signal(_: nix::libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn handle_reload_signal(_: nix::libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+fn install_signal_handlers() -> nix::Result<()> {
+    let action = SigAction::new(SigHandler::Handler(handle_shutdown_signal), SaFlags::empty(), SigSet::empty());
+    unsafe {
+        sigaction(Signal::SIGINT, &action)?;
+        sigaction(Signal::SIGTERM, &action)?;
+        let action = SigAction::new(SigHandler::Handler(handle_reload_signal), SaFlags::empty(), SigSet::empty());
+        sigaction(Signal::SIGHUP, &action)?;
+    }
+    Ok(())
+}
+
+/* large enough for DATA packets with the maximum block size */
+const MAX_PACKET_SIZE: usize = 65536;
+/* how long a transfer waits for its client until the options set one, without --handshake-timeout */
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Transport for a transfer running on the listening socket, see `--single-port`.
+/// The accept loop forwards the datagrams of the peer to `incoming`.
+struct SharedSocket {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    incoming: Receiver<Vec<u8>>,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl Transport for SharedSocket {
+    fn send(&self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        let received = match self.timeout.get() {
+            Some(timeout) => self.incoming.recv_timeout(timeout).map_err(|err| match err {
+                RecvTimeoutError::Timeout => io::Error::new(io::ErrorKind::TimedOut, "receive timeout"),
+                RecvTimeoutError::Disconnected => io::Error::new(io::ErrorKind::BrokenPipe, "listener closed"),
+            }),
+            None => self.incoming.recv().map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "listener closed")),
+        };
+        let data = received?;
+
+        /* like a datagram socket, truncate what does not fit */
+        let len = cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), io::Error> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+/// Identifies a client host. Link-local addresses are only unique per
+/// interface, so they are qualified with the scope id.
+fn client_key(cl: &SocketAddr) -> (IpAddr, u32) {
+    match cl {
+        SocketAddr::V6(addr) if addr.ip().segments()[0] & 0xffc0 == 0xfe80 => (cl.ip(), addr.scope_id()),
+        _ => (cl.ip(), 0),
+    }
+}
+
+/// Client address for log messages, showing the interface of scoped
+/// IPv6 addresses by name (e.g. `[fe80::1%eth0]:1234`).
+struct Peer(SocketAddr);
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let SocketAddr::V6(addr) = self.0 {
+            if addr.scope_id() != 0 {
+                let name = if_nameindex().ok().and_then(|interfaces| {
+                    interfaces.iter().find(|interface| interface.index() == addr.scope_id())
+                                     .map(|interface| interface.name().to_string_lossy().into_owned())
+                });
+                if let Some(name) = name {
+                    return write!(f, "[{}%{}]:{}", addr.ip(), name, addr.port());
+                }
+            }
+        }
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Formats options for log messages, sorted by name.
+fn format_options(options: &HashMap<String, String>) -> String {
+    if options.is_empty() {
+        return String::from("none");
+    }
+    let mut options: Vec<String> = options.iter().map(|(key, val)| format!("{}={}", key, val)).collect();
+    options.sort();
+    options.join(", ")
+}
+
+/// Direction of a transfer, as seen by the client.
+#[derive(Clone, Copy)]
+enum Direction {
+    Read,
+    Write,
+}
+
+impl Direction {
+    /// Describes the transfer of `path` for log messages.
+    fn describe(self, path: &Path, cl: &Peer) -> String {
+        match self {
+            Direction::Read => format!("Sending {} to {}", path.display(), cl),
+            Direction::Write => format!("Receiving {} from {}", path.display(), cl),
+        }
+    }
+
+    /// Wraps the error that ended the transfer of `path` for logging.
+    fn failed(self, path: &Path, cl: &Peer, err: &io::Error) -> io::Error {
+        io::Error::new(err.kind(), format!("{} failed ({}).", self.describe(path, cl), failure_reason(err)))
+    }
+}
+
+/// Explains why a transfer failed, naming the ERROR a client aborted it with.
+fn failure_reason(err: &io::Error) -> String {
+    match PeerError::of(err) {
+        Some(peer) if !peer.options_rejected => format!("aborted by the client with error {}: {}", peer.code, peer.message),
+        _ => err.to_string(),
+    }
+}
+
+/* how long an appending upload waits for another one of the same file to finish */
+const APPEND_WAIT: Duration = Duration::from_secs(1);
+
+/// Files currently being appended to.
+#[derive(Default)]
+struct Appending {
+    files: Mutex<HashSet<PathBuf>>,
+    released: Condvar,
+}
+
+/// Claim on a file being appended to, released when dropped.
+struct AppendClaim<'a> {
+    appending: &'a Appending,
+    path: PathBuf,
+}
+
+impl<'a> AppendClaim<'a> {
+    /// Claims `path`, waiting up to `APPEND_WAIT` while another transfer is
+    /// appending to it.
+    fn new(appending: &'a Appending, path: &Path) -> Option<AppendClaim<'a>> {
+        let files = appending.files.lock().unwrap();
+        let (mut files, _) = appending.released
+            .wait_timeout_while(files, APPEND_WAIT, |files| files.contains(path))
+            .unwrap();
+        if !files.insert(path.to_path_buf()) {
+            return None;
+        }
+        Some(AppendClaim { appending, path: path.to_path_buf() })
+    }
+}
+
+impl Drop for AppendClaim<'_> {
+    fn drop(&mut self) {
+        self.appending.files.lock().unwrap().remove(&self.path);
+        self.appending.released.notify_all();
+    }
+}
+
+/// Operations permitted by an [`AllowRule`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// Subnet whose clients may read, write or both (`--allow-from`), parsed from
+/// `read:192.0.2.0/24`, `write:2001:db8::10` or `rw:10.0.0.0/8`. An address
+/// without prefix length stands for itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllowRule {
+    access: Access,
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl AllowRule {
+    fn contains(&self, ip: IpAddr) -> bool {
+        /* the bits of the network covered by the prefix, also in the client address */
+        match (self.network, unmapped(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn permits(&self, direction: Direction) -> bool {
+        matches!((self.access, direction),
+                 (Access::ReadWrite, _) | (Access::Read, Direction::Read) | (Access::Write, Direction::Write))
+    }
+}
+
+impl std::str::FromStr for AllowRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AllowRule, String> {
+        let invalid = || format!("Invalid rule '{}' (expected read, write or rw, a colon and an address with optional prefix length)", s);
+        let (access, subnet) = s.split_once(':').ok_or_else(invalid)?;
+        let access = match access {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            "rw" => Access::ReadWrite,
+            _ => return Err(invalid()),
+        };
+        let (network, prefix) = match subnet.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (subnet, None),
+        };
+        let network: IpAddr = network.parse().map_err(|_| invalid())?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|&prefix| prefix <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(AllowRule { access, network, prefix })
+    }
+}
+
+/* idle clients are forgotten after this time */
+const RATE_LIMIT_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter per client address, allowing bursts of up to
+/// `rate` requests.
+struct RateLimiter {
+    rate: u32,
+    buckets: Mutex<HashMap<(IpAddr, u32), TokenBucket>>,
+    pruned: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            buckets: Mutex::new(HashMap::new()),
+            pruned: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn allow(&self, cl: &SocketAddr, now: Instant) -> bool {
+        let rate = f64::from(self.rate);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let mut pruned = self.pruned.lock().unwrap();
+        if now.saturating_duration_since(*pruned) >= RATE_LIMIT_PRUNE_INTERVAL {
+            buckets.retain(|_, bucket| now.saturating_duration_since(bucket.updated) < RATE_LIMIT_PRUNE_INTERVAL);
+            *pruned = now;
+        }
+
+        let bucket = buckets.entry(client_key(cl)).or_insert(TokenBucket { tokens: rate, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = f64::min(rate, bucket.tokens + elapsed * rate);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/* default for --duplicate-window */
+const DUPLICATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Requests being served, to recognize the retransmissions of clients whose
+/// first answer got lost (`--duplicate-window`). A request is identified by
+/// the client address and port and its exact content.
+#[derive(Default)]
+struct RecentRequests {
+    started: Mutex<HashMap<(SocketAddr, Vec<u8>), Instant>>,
+}
+
+impl RecentRequests {
+    /// Returns whether the same request from `cl` is still being served and
+    /// arrived less than `window` ago; otherwise it is remembered.
+    fn is_duplicate(&self, cl: &SocketAddr, request: &[u8], window: Duration, now: Instant) -> bool {
+        if window.is_zero() {
+            return false;
+        }
+        let mut started = self.started.lock().unwrap();
+        started.retain(|_, start| now.saturating_duration_since(*start) < window);
+
+        let key = (*cl, request.to_vec());
+        if started.contains_key(&key) {
+            return true;
+        }
+        started.insert(key, now);
+        false
+    }
+
+    /// Forgets a request once its transfer is over; repeating it starts a new one.
+    fn finished(&self, cl: &SocketAddr, request: &[u8]) {
+        self.started.lock().unwrap().remove(&(*cl, request.to_vec()));
+    }
+}
+
+/// Handle for cancelling running transfers of a [`Tftpd`] from another
+/// thread, e.g. when a device is decommissioned in the middle of a download.
+/// Obtained with [`Tftpd::canceller`]; clones share the running transfers.
+#[derive(Clone, Default)]
+pub struct Canceller {
+    tokens: Arc<Mutex<HashMap<SocketAddr, Arc<AtomicBool>>>>,
+}
+
+impl Canceller {
+    /// Cancels the running or queued transfers of the client with address
+    /// `client` (at any port); they end with an ERROR to the client before
+    /// the next block. Returns the number of transfers cancelled.
+    pub fn cancel(&self, client: IpAddr) -> usize {
+        let client = unmapped(client);
+        let mut cancelled = 0;
+        for (cl, token) in self.tokens.lock().unwrap().iter() {
+            if unmapped(cl.ip()) == client {
+                token.store(true, Ordering::SeqCst);
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+
+    /// Returns the token for the transfer of `cl`.
+    fn register(&self, cl: SocketAddr) -> Arc<AtomicBool> {
+        let token = Arc::new(AtomicBool::new(false));
+        self.tokens.lock().unwrap().insert(cl, Arc::clone(&token));
+        token
+    }
+
+    fn unregister(&self, cl: &SocketAddr, token: &Arc<AtomicBool>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        /* another transfer of the same client may have started meanwhile */
+        if tokens.get(cl).is_some_and(|t| Arc::ptr_eq(t, token)) {
+            tokens.remove(cl);
+        }
+    }
+
+    fn active(&self) -> usize {
+        self.tokens.lock().unwrap().len()
+    }
+
+    /// Cancels all running and queued transfers, returning their clients.
+    fn cancel_all(&self) -> Vec<SocketAddr> {
+        let tokens = self.tokens.lock().unwrap();
+        for token in tokens.values() {
+            token.store(true, Ordering::SeqCst);
+        }
+        tokens.keys().copied().collect()
+    }
+}
+
+/// Waits up to `timeout` for the transfers of `canceller` to finish. The
+/// transfers still running then are cancelled, and their clients returned.
+fn drain(canceller: &Canceller, timeout: Duration) -> Vec<SocketAddr> {
+    let deadline = Instant::now() + timeout;
+    while canceller.active() > 0 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        thread::sleep(cmp::min(left, DRAIN_POLL_INTERVAL));
+    }
+    canceller.cancel_all()
+}
+
+/// Child processes running one transfer each, see `--fork-per-transfer`.
+#[derive(Default)]
+struct Children {
+    running: HashMap<Pid, (SocketAddr, Vec<u8>, Arc<AtomicBool>)>,
+}
+
+impl Children {
+    fn insert(&mut self, pid: Pid, cl: SocketAddr, request: Vec<u8>, token: Arc<AtomicBool>) {
+        self.running.insert(pid, (cl, request, token));
+    }
+
+    /// Collects the children that exited, and returns how many are still running.
+    fn reap(&mut self, recent: &RecentRequests, canceller: &Canceller) -> usize {
+        let mut exited = Vec::new();
+        for (&pid, (cl, _, _)) in &self.running {
+            match waitpid(pid, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::StillAlive) => continue,
+                Ok(WaitStatus::Signaled(_, signal, _)) => println!("Transfer with {} was killed by {}", Peer(*cl), signal),
+                Ok(_) => {}
+                Err(err) => println!("Waiting for the transfer with {} failed: {}", Peer(*cl), err),
+            }
+            exited.push(pid);
+        }
+        for pid in exited {
+            if let Some((cl, request, token)) = self.running.remove(&pid) {
+                recent.finished(&cl, &request);
+                canceller.unregister(&cl, &token);
+            }
+        }
+        self.running.len()
+    }
+
+    /// Waits for all children to exit, or up to `timeout`. The children still
+    /// running then are killed, and their clients returned.
+    fn wait(&mut self, timeout: Option<Duration>, recent: &RecentRequests, canceller: &Canceller) -> Vec<SocketAddr> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        while self.reap(recent, canceller) > 0 {
+            let left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if left.is_some_and(|left| left.is_zero()) {
+                break;
+            }
+            thread::sleep(left.map_or(DRAIN_POLL_INTERVAL, |left| cmp::min(left, DRAIN_POLL_INTERVAL)));
+        }
+
+        let mut killed = Vec::new();
+        for (pid, (cl, _, _)) in self.running.drain() {
+            if kill(pid, Signal::SIGKILL).is_ok() {
+                let _ = waitpid(pid, None);
+            }
+            killed.push(cl);
+        }
+        killed
+    }
+}
+
+/// Returns IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, as IPv4 address.
+fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Returns `data` without its first `offset` bytes.
+fn skipped(data: &[u8], offset: u64) -> &[u8] {
+    &data[usize::try_from(offset).map_or(data.len(), |offset| cmp::min(offset, data.len()))..]
+}
+
+/// Returns at most the first `len` bytes of `data`.
+fn truncated(data: &[u8], len: u64) -> &[u8] {
+    &data[..usize::try_from(len).map_or(data.len(), |len| cmp::min(len, data.len()))]
+}
+
+/// Whether `buf` is a RRQ or WRQ, i.e. may start a transfer.
+fn is_request(buf: &[u8]) -> bool {
+    match buf {
+        [a, b, ..] => matches!(crate::Opcode::try_from(u16::from_be_bytes([*a, *b])),
+                               Ok(crate::Opcode::RRQ) | Ok(crate::Opcode::WRQ)),
+        _ => false,
+    }
+}
+
+/// Replaces the backslashes of Windows-style paths by slashes.
+fn translate_backslashes(filename: &Path) -> PathBuf {
+    let bytes: Vec<u8> = filename.as_os_str().as_bytes().iter()
+        .map(|&b| if b == b'\\' { b'/' } else { b })
+        .collect();
+    PathBuf::from(OsStr::from_bytes(&bytes))
+}
+
+/* upper bound for the data served from a FIFO or device (--allow-special) */
+const SPECIAL_FILE_LIMIT: u64 = 32 * 1024 * 1024;
+/* how long to wait for a FIFO or device to become ready */
+const SPECIAL_FILE_TIMEOUT: Duration = Duration::from_secs(5);
+/* granularity in which zeros are skipped for --sparse, the usual filesystem block size */
+const SPARSE_BLOCK_SIZE: u64 = 4096;
+
+/// Maps an error from opening a file to the TFTP error code and message
+/// sent to the client.
+fn open_error(err: &io::Error) -> (u16, String) {
+    match (err.kind(), err.raw_os_error()) {
+        (io::ErrorKind::NotFound, _) => (1, String::from("File not found")),
+        (io::ErrorKind::PermissionDenied, _) => (2, String::from("Permission denied")),
+        (_, Some(libc::ENOSPC)) | (_, Some(libc::EDQUOT)) => (3, String::from("Disk full or allocation exceeded")),
+        (io::ErrorKind::AlreadyExists, _) => (6, String::from("File already exists")),
+        /* e.g. EBUSY or ETXTBSY; pass on what the system reported */
+        _ => (0, err.to_string()),
+    }
+}
+
+fn is_special(file_type: fs::FileType) -> bool {
+    file_type.is_fifo() || file_type.is_char_device() || file_type.is_block_device()
+}
+
+/// A FIFO or device opened with O_NONBLOCK. Waits a bounded time for it to
+/// become ready instead of blocking the worker forever.
+struct SpecialFile<F> {
+    file: F,
+}
+
+impl<F> SpecialFile<F> {
+    fn new(file: F) -> SpecialFile<F> {
+        SpecialFile { file }
+    }
+
+    fn retry<T>(&mut self, mut op: impl FnMut(&mut F) -> Result<T, io::Error>) -> Result<T, io::Error> {
+        let start = Instant::now();
+        loop {
+            match op(&mut self.file) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if start.elapsed() >= SPECIAL_FILE_TIMEOUT {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "special file not ready"));
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<F: Read> Read for SpecialFile<F> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        self.retry(|file| file.read(buf))
+    }
+}
+
+impl<F: Write> Write for SpecialFile<F> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.retry(|file| file.write(buf))
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.file.flush()
+    }
+}
+
+/// An upload written with `--sparse`: blocks consisting only of zeros are
+/// seeked over instead of written, leaving holes in the file.
+struct SparseFile {
+    file: File,
+    /* current position, and the end of the data actually written */
+    pos: u64,
+    len: u64,
+}
+
+impl SparseFile {
+    fn new(file: File) -> SparseFile {
+        SparseFile { file, pos: 0, len: 0 }
+    }
+}
+
+impl Write for SparseFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        /* up to the next block boundary, so holes are aligned to the blocks of the filesystem */
+        let n = cmp::min(buf.len() as u64, SPARSE_BLOCK_SIZE - self.pos % SPARSE_BLOCK_SIZE) as usize;
+        let n = match buf[..n].iter().all(|&b| b == 0) {
+            true => n,
+            false => {
+                if self.len != self.pos {
+                    self.file.seek(io::SeekFrom::Start(self.pos))?;
+                }
+                let n = self.file.write(&buf[..n])?;
+                self.len = self.pos + n as u64;
+                n
+            }
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    /// Extends the file over a trailing hole, so it gets its full length.
+    fn flush(&mut self) -> Result<(), io::Error> {
+        if self.pos > self.len {
+            self.file.set_len(self.pos)?;
+            self.len = self.pos;
+        }
+        self.file.flush()
+    }
+}
+
+/// An upload written with `--preallocate`: the file is allocated with the
+/// size announced by the client, and cut to the size actually received when
+/// flushed, or when the upload fails.
+struct PreallocatedFile {
+    file: File,
+    len: u64,
+    written: u64,
+}
+
+impl PreallocatedFile {
+    fn new(file: File, size: u64) -> Result<PreallocatedFile, io::Error> {
+        let len = libc::off_t::try_from(size).map_err(|_| io::Error::from_raw_os_error(libc::EFBIG))?;
+        /* returns the error instead of setting errno */
+        match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, len) } {
+            0 => Ok(PreallocatedFile { file, len: size, written: 0 }),
+            err => Err(io::Error::from_raw_os_error(err)),
+        }
+    }
+
+    fn truncate(&mut self) -> Result<(), io::Error> {
+        if self.len != self.written {
+            self.file.set_len(self.written)?;
+            self.len = self.written;
+        }
+        Ok(())
+    }
+}
+
+impl Write for PreallocatedFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        let n = self.file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.truncate()?;
+        self.file.flush()
+    }
+}
+
+impl Drop for PreallocatedFile {
+    fn drop(&mut self) {
+        /* leaves no zeros behind the data of a failed upload */
+        let _ = self.truncate();
+    }
+}
+
+/// An upload written with `--fsync`: flushing it, which `recv_file` does
+/// before acknowledging the last block, waits until the file and its
+/// directory entry are on disk.
+struct SyncedFile<W> {
+    inner: W,
+    file: File,
+    dir: PathBuf,
+}
+
+impl<W: Write> Write for SyncedFile<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        self.inner.flush()?;
+        self.file.sync_all()?;
+        File::open(&self.dir)?.sync_all()
+    }
+}
+
+/// Read-only memory mapping of a served file, see `--mmap`.
+struct Mapping {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn as_slice(&self) -> &[u8] {
+        /* valid until unmapped in drop */
+        unsafe { slice::from_raw_parts(self.addr as *const u8, self.len) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.addr, self.len);
+        }
+    }
+}
+
+/// Marks the datagrams sent on `socket` to `cl` with the DSCP class `dscp`,
+/// in the traffic class (IPv6) or type of service (IPv4) field.
+fn set_dscp(socket: &UdpSocket, cl: &SocketAddr, dscp: u8) -> Result<(), io::Error> {
+    /* the lower two bits are used for ECN */
+    let tos: libc::c_int = libc::c_int::from(dscp) << 2;
+    let ipv4 = match cl {
+        SocketAddr::V4(_) => true,
+        SocketAddr::V6(a) => a.ip().to_ipv4_mapped().is_some(),
+    };
+    let (level, name) = if ipv4 {
+        (libc::IPPROTO_IP, libc::IP_TOS)
+    } else {
+        (libc::IPPROTO_IPV6, libc::IPV6_TCLASS)
+    };
+    let ret = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name, &tos as *const libc::c_int as *const libc::c_void,
+                         std::mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Checks that the effective user and group may create files in `dir`,
+/// including on read-only mounts.
+fn check_writable(dir: &Path) -> Result<(), io::Error> {
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    if unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::W_OK | libc::X_OK, libc::AT_EACCESS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets the name shown for the process by `ps -o comm`, `top` and `pgrep`
+/// (`--process-name`); threads started afterwards inherit it.
+#[cfg(target_os = "linux")]
+fn set_process_name(name: &str) -> Result<(), io::Error> {
+    let name = std::ffi::CString::new(name)?;
+    if unsafe { libc::prctl(libc::PR_SET_NAME, name.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// There is no portable way to rename a process; the name stays unchanged.
+#[cfg(not(target_os = "linux"))]
+fn set_process_name(_name: &str) -> Result<(), io::Error> {
+    Ok(())
+}
+
+/// Connects to the socket systemd listens on for notifications (sd_notify(3))
+/// of services with `Type=notify`, given as `NOTIFY_SOCKET`. Names starting
+/// with `@` are in the abstract namespace. Connecting early keeps the socket
+/// reachable after changing root.
+fn connect_notify_socket(path: &OsStr) -> Result<UnixDatagram, io::Error> {
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            socket.connect_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)?;
+        }
+        _ => socket.connect(path)?,
+    }
+    Ok(socket)
+}
+
+/// Waits for the `--on-upload` command run for `path` in `storage`, deleting
+/// the upload if it failed and `delete_on_failure` is set. It is reported by
+/// its `location`, as passed to the command.
+fn wait_upload_hook(mut child: Child, storage: &dyn Storage, path: &Path, location: &Path, delete_on_failure: bool, quiet: bool) {
+    let status = match child.wait() {
+        Ok(status) => status,
+        Err(err) => {
+            println!("Waiting for upload hook for {} failed: {}", location.display(), err);
+            return;
+        }
+    };
+    if !status.success() || !quiet {
+        println!("Upload hook for {} exited with {}.", location.display(), status);
+    }
+    if !status.success() && delete_on_failure {
+        match storage.remove(path) {
+            Ok(_) => println!("Deleted {}.", location.display()),
+            Err(err) => println!("Deleting {} failed: {}", location.display(), err),
+        }
+    }
+}
+
+/// Returns the name of the `--per-client-subdir` directory for uploads from
+/// `ip`: the address itself, with the colons of IPv6 addresses replaced by
+/// dashes. IPv4-mapped addresses are named like the IPv4 address.
+fn client_subdir(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.to_string(),
+            None => ip.to_string().replace(':', "-"),
+        },
+        ip => ip.to_string(),
+    }
+}
+
+/// Runs the handler of a request from `cl` and logs its outcome. A panic is
+/// reported to the client with an ERROR sent from `socket`, instead of letting
+/// it wait for a timeout, and does not take down the worker (which relies on
+/// the release profile unwinding panics). Returns whether the handler
+/// succeeded.
+fn run_handler(socket: &UdpSocket, cl: SocketAddr, quiet: bool, handler: impl FnOnce() -> Result<String, io::Error>) -> bool {
+    match panic::catch_unwind(AssertUnwindSafe(handler)) {
+        Ok(Ok(msg)) => {
+            if !quiet {
+                println!("{}", msg);
+            }
+            return true;
+        }
+        Ok(Err(err)) => {
+            println!("{}", err);
+            if err.get_ref().is_some_and(|err| err.is::<OutOfDescriptors>()) {
+                if let Err(err) = crate::Tftp::new().send_error_to(socket, cl, 0, "Server busy") {
+                    println!("Sending error to {} failed: {}", Peer(cl), err);
+                }
+            }
+        }
+        Err(payload) => {
+            let msg = payload.downcast_ref::<&str>().copied()
+                             .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                             .unwrap_or("unknown cause");
+            println!("Handling request from {} panicked ({}); run with RUST_BACKTRACE=1 for a backtrace.", Peer(cl), msg);
+            if let Err(err) = crate::Tftp::new().send_error_to(socket, cl, 0, "Internal server error") {
+                println!("Sending error to {} failed: {}", Peer(cl), err);
+            }
+        }
+    }
+    false
+}
+
+/// Takes the outcomes reported by finished transfers (`--serve-once`),
+/// clearing `transferring`. Returns whether one of them succeeded.
+fn take_outcomes(outcome: &Receiver<bool>, transferring: &mut bool) -> bool {
+    let mut succeeded = false;
+    for ok in outcome.try_iter() {
+        succeeded |= ok;
+        *transferring = false;
+    }
+    succeeded
+}
+
+/// A transfer refused because the process ran out of file descriptors for its
+/// socket. The client is told that the server is busy from the listening
+/// socket, see [`run_handler`].
+#[derive(Debug)]
+struct OutOfDescriptors(SocketAddr);
+
+impl fmt::Display for OutOfDescriptors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Refused request from {} (too many open files; raise the limit, e.g. with --raise-nofile)", Peer(self.0))
+    }
+}
+
+impl Error for OutOfDescriptors {}
+
+/// Reports running out of file descriptors while handling the request of
+/// `cl` as [`OutOfDescriptors`]; other errors are returned unchanged.
+fn descriptor_error(cl: &SocketAddr, err: io::Error) -> io::Error {
+    match err.raw_os_error() {
+        Some(libc::EMFILE) | Some(libc::ENFILE) => io::Error::new(err.kind(), OutOfDescriptors(*cl)),
+        _ => err,
+    }
+}
+
+/// Raises the soft limit of open files to the hard limit (`--raise-nofile`),
+/// returning the previous and the new limit.
+fn raise_nofile_limit() -> Result<(libc::rlim_t, libc::rlim_t), io::Error> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let previous = limit.rlim_cur;
+    limit.rlim_cur = limit.rlim_max;
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((previous, limit.rlim_cur))
+}
+
+/* from linux/capability.h */
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Parses the effective capability set from the contents of `/proc/<pid>/status`.
+fn effective_capabilities(status: &str) -> Option<u64> {
+    let caps = status.lines().find_map(|line| line.strip_prefix("CapEff:"))?;
+    u64::from_str_radix(caps.trim(), 16).ok()
+}
+
+fn has_capability(cap: u32) -> bool {
+    fs::read_to_string("/proc/self/status").ok()
+        .and_then(|status| effective_capabilities(&status))
+        .is_some_and(|caps| caps & (1 << cap) != 0)
+}
+
+/// Clears all capabilities of the process, e.g. CAP_NET_BIND_SERVICE
+/// granted to an unprivileged user for binding the listening socket.
+fn drop_capabilities() -> Result<(), io::Error> {
+    let header = CapHeader { version: LINUX_CAPABILITY_VERSION_3, pid: 0 };
+    let data = [CapData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capset, &header as *const CapHeader, data.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Whether the real and effective ids in `uids` and `gids` are all `uid` and `gid`.
+fn running_as(uid: Uid, gid: Gid, uids: [Uid; 2], gids: [Gid; 2]) -> bool {
+    uids.iter().all(|&u| u == uid) && gids.iter().all(|&g| g == gid)
+}
+
+/// Binds a UDP socket to `addr`, allowing quick rebinding after a restart.
+/// With `reuse_port`, other processes may bind the same port as well. For IPv6
+/// addresses, `v6only` decides whether IPv4 clients are received on it as
+/// well, independent of the system default.
+fn bind_socket(addr: SocketAddr, reuse_port: bool, v6only: bool) -> Result<UdpSocket, io::Error> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    /* take ownership right away, so the descriptor is closed on errors */
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    if reuse_port {
+        setsockopt(fd, sockopt::ReusePort, &true)?;
+    }
+    if family == AddressFamily::Inet6 {
+        setsockopt(fd, sockopt::Ipv6V6Only, &v6only)?;
+        /* also reported for IPv4 requests on dual-stack sockets */
+        setsockopt(fd, sockopt::Ipv6RecvPacketInfo, &true)?;
+    } else {
+        setsockopt(fd, sockopt::Ipv4PacketInfo, &true)?;
+    }
+    match addr {
+        SocketAddr::V4(a) => bind(fd, &SockaddrIn::from(a))?,
+        SocketAddr::V6(a) => bind(fd, &SockaddrIn6::from(a))?,
+    }
+
+    Ok(sock)
+}
+
+/// Receives a datagram on `socket`, along with the local address it was sent
+/// to if the socket reports it (see [`bind_socket`]).
+fn recv_request(socket: &UdpSocket, buf: &mut [u8]) -> Result<(usize, SocketAddr, Option<SocketAddr>), io::Error> {
+    let mut iov = [io::IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(libc::in6_pktinfo, libc::in_pktinfo);
+    let msg = recvmsg::<SockaddrStorage>(socket.as_raw_fd(), &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+    let src = msg.address.as_ref().and_then(|addr| match (addr.as_sockaddr_in6(), addr.as_sockaddr_in()) {
+        (Some(addr), _) => Some(SocketAddr::V6((*addr).into())),
+        (_, Some(addr)) => Some(SocketAddr::V4((*addr).into())),
+        _ => None,
+    }).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "datagram without IP source address"))?;
+
+    let mut local = None;
+    for cmsg in msg.cmsgs() {
+        match cmsg {
+            ControlMessageOwned::Ipv6PacketInfo(info) => {
+                let ip = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                let scope_id = if ip.segments()[0] & 0xffc0 == 0xfe80 { info.ipi6_ifindex } else { 0 };
+                local = Some(SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, scope_id)));
+            }
+            ControlMessageOwned::Ipv4PacketInfo(info) => {
+                let ip = Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr));
+                local = Some(SocketAddr::new(IpAddr::V4(ip), 0));
+            }
+            _ => {}
+        }
+    }
+    Ok((msg.bytes, src, local))
+}
+
+/// Loads the configuration again, see [`Tftpd::on_reload`].
+type ReloadFn = dyn FnMut(Option<&Path>) -> Option<Configuration> + Send;
+
+/// Settings of a [`Tftpd`] instance; created with a [`ConfigurationBuilder`].
+#[derive(Clone)]
+pub struct Configuration {
+    port: u16,
+    uid: u32,
+    gid: u32,
+    ro: bool,
+    wo: bool,
+    threads: usize,
+    dir: PathBuf,
+    verbose: bool,
+    chroot: bool,
+    reuse_port: bool,
+    rcvbuf: Option<usize>,
+    sndbuf: Option<usize>,
+    single_port: bool,
+    client_rate: Option<u32>,
+    allow_ext: Vec<String>,
+    deny_ext: Vec<String>,
+    no_dotfiles: bool,
+    enable_listing: bool,
+    allow_special: bool,
+    metrics_addr: Option<SocketAddr>,
+    on_upload: Option<String>,
+    on_upload_delete: bool,
+    transfer_timeout: Option<Duration>,
+    total_rate: Option<u64>,
+    no_backslashes: bool,
+    mmap: bool,
+    cache_size: Option<usize>,
+    access_log: Option<PathBuf>,
+    append: bool,
+    modes: Vec<String>,
+    max_queue: Option<usize>,
+    include: Vec<PathBuf>,
+    handshake_timeout: Option<Duration>,
+    require_drop: bool,
+    v6only: bool,
+    dscp: Option<u8>,
+    map_all: Option<PathBuf>,
+    config_file: Option<PathBuf>,
+    read_dir: Option<PathBuf>,
+    write_dir: Option<PathBuf>,
+    backoff_multiplier: u32,
+    max_backoff: Option<Duration>,
+    per_client_subdir: bool,
+    process_name: Option<String>,
+    duplicate_window: Duration,
+    quiet: bool,
+    allow_gzip: bool,
+    allow_from: Vec<AllowRule>,
+    translate_backslash: bool,
+    shutdown_timeout: Option<Duration>,
+    drop_invalid: bool,
+    manifest: Option<PathBuf>,
+    allow_offset: bool,
+    idle_exit: Option<Duration>,
+    sparse: bool,
+    per_client_config: bool,
+    utf8_filenames: bool,
+    require_writable: bool,
+    force_blksize: Option<usize>,
+    control_socket: Option<PathBuf>,
+    fsync: bool,
+    max_filename_len: usize,
+    max_rrq_size: Option<u64>,
+    fork_per_transfer: bool,
+    log_format: LogFormat,
+    preallocate: bool,
+    raise_nofile: bool,
+    index: Option<String>,
+    mirror: Option<SocketAddr>,
+    strict_mirror: bool,
+    default_mode: Option<String>,
+    serve_once: bool,
+}
+
+impl Default for Configuration {
+    fn default() -> Configuration {
+        Configuration {
+            port: 69,
+            uid: 65534,
+            gid: 65534,
+            ro: false,
+            wo: false,
+            threads: 2,
+            dir: env::current_dir().expect("Can't get current directory"),
+            verbose: false,
+            chroot: false,
+            reuse_port: false,
+            rcvbuf: None,
+            sndbuf: None,
+            single_port: false,
+            client_rate: None,
+            allow_ext: Vec::new(),
+            deny_ext: Vec::new(),
+            no_dotfiles: false,
+            enable_listing: false,
+            allow_special: false,
+            metrics_addr: None,
+            on_upload: None,
+            on_upload_delete: false,
+            transfer_timeout: None,
+            total_rate: None,
+            no_backslashes: true,
+            mmap: false,
+            cache_size: None,
+            access_log: None,
+            append: false,
+            modes: vec![String::from("octet"), String::from("netascii")],
+            max_queue: None,
+            include: Vec::new(),
+            handshake_timeout: None,
+            require_drop: false,
+            v6only: false,
+            dscp: None,
+            map_all: None,
+            config_file: None,
+            read_dir: None,
+            write_dir: None,
+            backoff_multiplier: 1,
+            max_backoff: None,
+            per_client_subdir: false,
+            process_name: None,
+            duplicate_window: DUPLICATE_WINDOW,
+            quiet: false,
+            allow_gzip: false,
+            allow_from: Vec::new(),
+            translate_backslash: false,
+            shutdown_timeout: None,
+            drop_invalid: false,
+            manifest: None,
+            allow_offset: false,
+            idle_exit: None,
+            sparse: false,
+            per_client_config: false,
+            utf8_filenames: false,
+            require_writable: false,
+            force_blksize: None,
+            control_socket: None,
+            fsync: false,
+            max_filename_len: 255,
+            max_rrq_size: None,
+            fork_per_transfer: false,
+            log_format: LogFormat::default(),
+            preallocate: false,
+            raise_nofile: false,
+            index: None,
+            mirror: None,
+            strict_mirror: false,
+            default_mode: None,
+            serve_once: false,
+        }
+    }
+}
+
+impl Configuration {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.ro
+    }
+
+    pub fn write_only(&self) -> bool {
+        self.wo
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn verbose(&self) -> bool {
+        self.verbose
+    }
+
+    pub fn chroot(&self) -> bool {
+        self.chroot
+    }
+
+    pub fn reuse_port(&self) -> bool {
+        self.reuse_port
+    }
+
+    pub fn rcvbuf(&self) -> Option<usize> {
+        self.rcvbuf
+    }
+
+    pub fn sndbuf(&self) -> Option<usize> {
+        self.sndbuf
+    }
+
+    pub fn single_port(&self) -> bool {
+        self.single_port
+    }
+
+    pub fn client_rate(&self) -> Option<u32> {
+        self.client_rate
+    }
+
+    pub fn allow_ext(&self) -> &[String] {
+        &self.allow_ext
+    }
+
+    pub fn deny_ext(&self) -> &[String] {
+        &self.deny_ext
+    }
+
+    pub fn no_dotfiles(&self) -> bool {
+        self.no_dotfiles
+    }
+
+    pub fn enable_listing(&self) -> bool {
+        self.enable_listing
+    }
+
+    pub fn allow_special(&self) -> bool {
+        self.allow_special
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    pub fn on_upload(&self) -> Option<&str> {
+        self.on_upload.as_deref()
+    }
+
+    pub fn on_upload_delete(&self) -> bool {
+        self.on_upload_delete
+    }
+
+    pub fn transfer_timeout(&self) -> Option<Duration> {
+        self.transfer_timeout
+    }
+
+    pub fn total_rate(&self) -> Option<u64> {
+        self.total_rate
+    }
+
+    pub fn no_backslashes(&self) -> bool {
+        self.no_backslashes
+    }
+
+    pub fn mmap(&self) -> bool {
+        self.mmap
+    }
+
+    pub fn cache_size(&self) -> Option<usize> {
+        self.cache_size
+    }
+
+    pub fn access_log(&self) -> Option<&Path> {
+        self.access_log.as_deref()
+    }
+
+    pub fn append(&self) -> bool {
+        self.append
+    }
+
+    pub fn modes(&self) -> &[String] {
+        &self.modes
+    }
+
+    pub fn max_queue(&self) -> Option<usize> {
+        self.max_queue
+    }
+
+    pub fn include(&self) -> &[PathBuf] {
+        &self.include
+    }
+
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        self.handshake_timeout
+    }
+
+    pub fn require_drop(&self) -> bool {
+        self.require_drop
+    }
+
+    pub fn v6only(&self) -> bool {
+        self.v6only
+    }
+
+    pub fn dscp(&self) -> Option<u8> {
+        self.dscp
+    }
+
+    pub fn map_all(&self) -> Option<&Path> {
+        self.map_all.as_deref()
+    }
+
+    pub fn config_file(&self) -> Option<&Path> {
+        self.config_file.as_deref()
+    }
+
+    pub fn read_dir(&self) -> Option<&Path> {
+        self.read_dir.as_deref()
+    }
+
+    pub fn write_dir(&self) -> Option<&Path> {
+        self.write_dir.as_deref()
+    }
+
+    pub fn backoff_multiplier(&self) -> u32 {
+        self.backoff_multiplier
+    }
+
+    pub fn max_backoff(&self) -> Option<Duration> {
+        self.max_backoff
+    }
+
+    pub fn per_client_subdir(&self) -> bool {
+        self.per_client_subdir
+    }
+
+    pub fn process_name(&self) -> Option<&str> {
+        self.process_name.as_deref()
+    }
+
+    pub fn duplicate_window(&self) -> Duration {
+        self.duplicate_window
+    }
+
+    pub fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub fn allow_gzip(&self) -> bool {
+        self.allow_gzip
+    }
+
+    pub fn allow_from(&self) -> &[AllowRule] {
+        &self.allow_from
+    }
+
+    pub fn translate_backslash(&self) -> bool {
+        self.translate_backslash
+    }
+
+    pub fn shutdown_timeout(&self) -> Option<Duration> {
+        self.shutdown_timeout
+    }
+
+    pub fn drop_invalid(&self) -> bool {
+        self.drop_invalid
+    }
+
+    pub fn manifest(&self) -> Option<&Path> {
+        self.manifest.as_deref()
+    }
+
+    pub fn allow_offset(&self) -> bool {
+        self.allow_offset
+    }
+
+    pub fn idle_exit(&self) -> Option<Duration> {
+        self.idle_exit
+    }
+
+    pub fn sparse(&self) -> bool {
+        self.sparse
+    }
+
+    pub fn per_client_config(&self) -> bool {
+        self.per_client_config
+    }
+
+    pub fn utf8_filenames(&self) -> bool {
+        self.utf8_filenames
+    }
+
+    pub fn require_writable(&self) -> bool {
+        self.require_writable
+    }
+
+    pub fn force_blksize(&self) -> Option<usize> {
+        self.force_blksize
+    }
+
+    pub fn control_socket(&self) -> Option<&Path> {
+        self.control_socket.as_deref()
+    }
+
+    pub fn fsync(&self) -> bool {
+        self.fsync
+    }
+
+    pub fn max_filename_len(&self) -> usize {
+        self.max_filename_len
+    }
+
+    pub fn max_rrq_size(&self) -> Option<u64> {
+        self.max_rrq_size
+    }
+
+    pub fn fork_per_transfer(&self) -> bool {
+        self.fork_per_transfer
+    }
+
+    pub fn log_format(&self) -> &str {
+        self.log_format.template()
+    }
+
+    pub fn preallocate(&self) -> bool {
+        self.preallocate
+    }
+
+    pub fn raise_nofile(&self) -> bool {
+        self.raise_nofile
+    }
+
+    pub fn index(&self) -> Option<&str> {
+        self.index.as_deref()
+    }
+
+    pub fn mirror(&self) -> Option<SocketAddr> {
+        self.mirror
+    }
+
+    pub fn strict_mirror(&self) -> bool {
+        self.strict_mirror
+    }
+
+    pub fn default_mode(&self) -> Option<&str> {
+        self.default_mode.as_deref()
+    }
+
+    pub fn serve_once(&self) -> bool {
+        self.serve_once
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// read-only and write-only were both requested
+    ConflictingAccess,
+    /// sparse uploads can't be appended
+    ConflictingSparse,
+    /// preallocated files can't be sparse, or be appended to
+    ConflictingPreallocate,
+    /// child processes can't share the listening socket for their transfers
+    ConflictingForkPerTransfer,
+    /// the metrics and control socket are served by threads, and processes
+    /// running other threads can't safely fork
+    ConflictingForkServices,
+    /// each child process would be throttled on its own, not all together
+    ConflictingForkRate,
+    /// the outcome of transfers in child processes is not tracked
+    ConflictingServeOnce,
+    /// appended data can't be mirrored, the upstream server refuses existing files
+    ConflictingMirror,
+    /// quiet and verbose were both requested
+    ConflictingVerbosity,
+    /// no worker threads
+    NoThreads,
+    /// port 0 can't be used to listen on
+    InvalidPort,
+    /// a client rate below one request per second
+    InvalidClientRate,
+    /// a zero transfer timeout
+    InvalidTransferTimeout,
+    /// a zero handshake timeout
+    InvalidHandshakeTimeout,
+    /// a zero idle time before exiting
+    InvalidIdleExit,
+    /// a backoff multiplier below 1, which would shorten the timeouts
+    InvalidBackoffMultiplier,
+    /// a zero maximum backoff
+    InvalidMaxBackoff,
+    /// a total rate below one byte per second
+    InvalidTotalRate,
+    /// no modes or an unknown one allowed
+    InvalidModes,
+    /// the mode assumed for requests without one is not allowed
+    InvalidDefaultMode,
+    /// a queue that can't hold any request
+    InvalidMaxQueue,
+    /// a zero maximum file name length
+    InvalidMaxFilenameLen,
+    /// outside of the block sizes allowed by RFC 2348
+    InvalidForceBlksize,
+    /// DSCP values have 6 bits
+    InvalidDscp,
+    /// Linux limits process names to 15 bytes
+    InvalidProcessName,
+    /// the template has unknown placeholders or unmatched braces
+    InvalidLogFormat(String),
+    /// the file served for all reads is not relative to the served directory
+    InvalidMapAll,
+    /// the index file is not a plain file name
+    InvalidIndex,
+    /// the directory to serve is not usable
+    InvalidDirectory(PathBuf, io::Error),
+    /// an included path is not absolute or not accessible
+    InvalidInclude(PathBuf, io::Error),
+    /// included paths would not be reachable after changing root
+    ConflictingInclude,
+    /// separate read or write directories would not be reachable after changing root
+    ConflictingRoots,
+    /// compressing files requires the gzip feature
+    GzipUnsupported,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::ConflictingAccess => write!(f, "Only one of read-only and write-only allowed"),
+            ConfigError::ConflictingSparse => write!(f, "Only one of sparse and append allowed"),
+            ConfigError::ConflictingPreallocate => write!(f, "Preallocation can't be used with sparse or append"),
+            ConfigError::ConflictingForkPerTransfer => write!(f, "Only one of fork-per-transfer and single-port allowed"),
+            ConfigError::ConflictingForkServices => write!(f, "Fork-per-transfer can't be used with metrics-addr or control-socket"),
+            ConfigError::ConflictingForkRate => write!(f, "Only one of fork-per-transfer and total-rate allowed"),
+            ConfigError::ConflictingServeOnce => write!(f, "Only one of serve-once and fork-per-transfer allowed"),
+            ConfigError::ConflictingMirror => write!(f, "Only one of mirror and append allowed"),
+            ConfigError::ConflictingVerbosity => write!(f, "Only one of quiet and verbose allowed"),
+            ConfigError::NoThreads => write!(f, "At least one worker thread required"),
+            ConfigError::InvalidPort => write!(f, "Invalid port"),
+            ConfigError::InvalidClientRate => write!(f, "Client rate must be at least one request per second"),
+            ConfigError::InvalidTransferTimeout => write!(f, "Transfer timeout must not be zero"),
+            ConfigError::InvalidHandshakeTimeout => write!(f, "Handshake timeout must not be zero"),
+            ConfigError::InvalidIdleExit => write!(f, "Idle time before exiting must not be zero"),
+            ConfigError::InvalidBackoffMultiplier => write!(f, "Backoff multiplier must be at least 1"),
+            ConfigError::InvalidMaxBackoff => write!(f, "Maximum backoff must not be zero"),
+            ConfigError::InvalidTotalRate => write!(f, "Total rate must be at least one byte per second"),
+            ConfigError::InvalidMaxQueue => write!(f, "Queue must hold at least one request"),
+            ConfigError::InvalidMaxFilenameLen => write!(f, "Maximum file name length must not be zero"),
+            ConfigError::InvalidForceBlksize => write!(f, "Forced block size must be between 8 and {}", crate::packet::MAX_BLKSIZE),
+            ConfigError::InvalidDscp => write!(f, "DSCP value must be at most 63"),
+            ConfigError::InvalidProcessName => write!(f, "Process name must be 1 to 15 bytes long and must not contain NUL"),
+            ConfigError::InvalidLogFormat(err) => write!(f, "Invalid log format ({})", err),
+            ConfigError::InvalidMapAll => write!(f, "File served for all reads must be relative to the served directory"),
+            ConfigError::InvalidIndex => write!(f, "Index file must be a file name without directories"),
+            ConfigError::InvalidModes => write!(f, "Modes must be one or both of octet and netascii"),
+            ConfigError::InvalidDefaultMode => write!(f, "Default mode must be one of the allowed modes"),
+            ConfigError::InvalidDirectory(dir, err) => write!(f, "Invalid directory {} ({})", dir.display(), err),
+            ConfigError::InvalidInclude(path, err) => write!(f, "Invalid include {} ({})", path.display(), err),
+            ConfigError::ConflictingInclude => write!(f, "Included paths can't be used with chroot"),
+            ConfigError::ConflictingRoots => write!(f, "Separate read and write directories can't be used with chroot"),
+            ConfigError::GzipUnsupported => write!(f, "Compressing files is not supported by this build (gzip feature)"),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+
+/// Builds a validated [`Configuration`], starting from the defaults.
+///
+/// ```no_run
+/// let conf = rtftp::server::ConfigurationBuilder::new()
+///     .port(6969)
+///     .read_only(true)
+///     .dir("/srv/tftp")
+///     .build()
+///     .expect("invalid configuration");
+/// rtftp::server::Tftpd::new(conf).start();
+/// ```
+#[derive(Clone, Default)]
+pub struct ConfigurationBuilder {
+    conf: Configuration,
+    /// parsed by [`ConfigurationBuilder::build`]
+    log_format: Option<String>,
+}
+
+impl ConfigurationBuilder {
+    pub fn new() -> ConfigurationBuilder {
+        Default::default()
+    }
+
+    pub fn port(&mut self, port: u16) -> &mut Self {
+        self.conf.port = port;
+        self
+    }
+
+    pub fn uid(&mut self, uid: u32) -> &mut Self {
+        self.conf.uid = uid;
+        self
+    }
+
+    pub fn gid(&mut self, gid: u32) -> &mut Self {
+        self.conf.gid = gid;
+        self
+    }
+
+    pub fn read_only(&mut self, ro: bool) -> &mut Self {
+        self.conf.ro = ro;
+        self
+    }
+
+    pub fn write_only(&mut self, wo: bool) -> &mut Self {
+        self.conf.wo = wo;
+        self
+    }
+
+    pub fn threads(&mut self, threads: usize) -> &mut Self {
+        self.conf.threads = threads;
+        self
+    }
+
+    pub fn dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.conf.dir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn verbose(&mut self, verbose: bool) -> &mut Self {
+        self.conf.verbose = verbose;
+        self
+    }
+
+    pub fn chroot(&mut self, chroot: bool) -> &mut Self {
+        self.conf.chroot = chroot;
+        self
+    }
+
+    pub fn reuse_port(&mut self, reuse_port: bool) -> &mut Self {
+        self.conf.reuse_port = reuse_port;
+        self
+    }
+
+    pub fn rcvbuf(&mut self, rcvbuf: usize) -> &mut Self {
+        self.conf.rcvbuf = Some(rcvbuf);
+        self
+    }
+
+    pub fn sndbuf(&mut self, sndbuf: usize) -> &mut Self {
+        self.conf.sndbuf = Some(sndbuf);
+        self
+    }
+
+    pub fn single_port(&mut self, single_port: bool) -> &mut Self {
+        self.conf.single_port = single_port;
+        self
+    }
+
+    pub fn client_rate(&mut self, client_rate: u32) -> &mut Self {
+        self.conf.client_rate = Some(client_rate);
+        self
+    }
+
+    /// Adds a file extension (case-insensitive) to be allowed, e.g. `bin`.
+    pub fn allow_ext<S: AsRef<str>>(&mut self, ext: S) -> &mut Self {
+        self.conf.allow_ext.push(normalize_ext(ext.as_ref()));
+        self
+    }
+
+    /// Adds a file extension (case-insensitive) to be refused, e.g. `bin`.
+    pub fn deny_ext<S: AsRef<str>>(&mut self, ext: S) -> &mut Self {
+        self.conf.deny_ext.push(normalize_ext(ext.as_ref()));
+        self
+    }
+
+    pub fn no_dotfiles(&mut self, no_dotfiles: bool) -> &mut Self {
+        self.conf.no_dotfiles = no_dotfiles;
+        self
+    }
+
+    pub fn enable_listing(&mut self, enable_listing: bool) -> &mut Self {
+        self.conf.enable_listing = enable_listing;
+        self
+    }
+
+    pub fn allow_special(&mut self, allow_special: bool) -> &mut Self {
+        self.conf.allow_special = allow_special;
+        self
+    }
+
+    pub fn metrics_addr(&mut self, metrics_addr: SocketAddr) -> &mut Self {
+        self.conf.metrics_addr = Some(metrics_addr);
+        self
+    }
+
+    /// Command to run after a successful upload, with the path of the file as argument.
+    pub fn on_upload(&mut self, command: &str) -> &mut Self {
+        self.conf.on_upload = Some(command.to_string());
+        self
+    }
+
+    pub fn on_upload_delete(&mut self, on_upload_delete: bool) -> &mut Self {
+        self.conf.on_upload_delete = on_upload_delete;
+        self
+    }
+
+    pub fn transfer_timeout(&mut self, transfer_timeout: Duration) -> &mut Self {
+        self.conf.transfer_timeout = Some(transfer_timeout);
+        self
+    }
+
+    pub fn total_rate(&mut self, total_rate: u64) -> &mut Self {
+        self.conf.total_rate = Some(total_rate);
+        self
+    }
+
+    pub fn no_backslashes(&mut self, no_backslashes: bool) -> &mut Self {
+        self.conf.no_backslashes = no_backslashes;
+        self
+    }
+
+    pub fn mmap(&mut self, mmap: bool) -> &mut Self {
+        self.conf.mmap = mmap;
+        self
+    }
+
+    pub fn cache_size(&mut self, cache_size: usize) -> &mut Self {
+        self.conf.cache_size = Some(cache_size);
+        self
+    }
+
+    pub fn access_log<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.conf.access_log = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.conf.append = append;
+        self
+    }
+
+    /// Sets the transfer modes accepted in requests (by default octet and netascii).
+    pub fn modes<S: AsRef<str>>(&mut self, modes: &[S]) -> &mut Self {
+        self.conf.modes = modes.iter().map(|mode| mode.as_ref().to_lowercase()).collect();
+        self
+    }
+
+    pub fn max_queue(&mut self, max_queue: usize) -> &mut Self {
+        self.conf.max_queue = Some(max_queue);
+        self
+    }
+
+    /// Allows reading `path` (a file or directory) outside of the served directory.
+    pub fn include<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.conf.include.push(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn handshake_timeout(&mut self, handshake_timeout: Duration) -> &mut Self {
+        self.conf.handshake_timeout = Some(handshake_timeout);
+        self
+    }
+
+    pub fn require_drop(&mut self, require_drop: bool) -> &mut Self {
+        self.conf.require_drop = require_drop;
+        self
+    }
+
+    pub fn v6only(&mut self, v6only: bool) -> &mut Self {
+        self.conf.v6only = v6only;
+        self
+    }
+
+    pub fn dscp(&mut self, dscp: u8) -> &mut Self {
+        self.conf.dscp = Some(dscp);
+        self
+    }
+
+    pub fn map_all<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.conf.map_all = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The configuration file, which is read again on SIGHUP, see
+    /// [`Tftpd::on_reload`]. Its directory stays readable under landlock.
+    pub fn config_file<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.conf.config_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn read_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.conf.read_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn write_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.conf.write_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn backoff_multiplier(&mut self, backoff_multiplier: u32) -> &mut Self {
+        self.conf.backoff_multiplier = backoff_multiplier;
+        self
+    }
+
+    pub fn max_backoff(&mut self, max_backoff: Duration) -> &mut Self {
+        self.conf.max_backoff = Some(max_backoff);
+        self
+    }
+
+    pub fn per_client_subdir(&mut self, per_client_subdir: bool) -> &mut Self {
+        self.conf.per_client_subdir = per_client_subdir;
+        self
+    }
+
+    pub fn process_name(&mut self, name: &str) -> &mut Self {
+        self.conf.process_name = Some(name.to_string());
+        self
+    }
+
+    pub fn duplicate_window(&mut self, duplicate_window: Duration) -> &mut Self {
+        self.conf.duplicate_window = duplicate_window;
+        self
+    }
+
+    pub fn quiet(&mut self, quiet: bool) -> &mut Self {
+        self.conf.quiet = quiet;
+        self
+    }
+
+    pub fn allow_gzip(&mut self, allow_gzip: bool) -> &mut Self {
+        self.conf.allow_gzip = allow_gzip;
+        self
+    }
+
+    pub fn allow_from(&mut self, rule: AllowRule) -> &mut Self {
+        self.conf.allow_from.push(rule);
+        self
+    }
+
+    pub fn translate_backslash(&mut self, translate_backslash: bool) -> &mut Self {
+        self.conf.translate_backslash = translate_backslash;
+        self
+    }
+
+    pub fn shutdown_timeout(&mut self, shutdown_timeout: Duration) -> &mut Self {
+        self.conf.shutdown_timeout = Some(shutdown_timeout);
+        self
+    }
+
+    pub fn drop_invalid(&mut self, drop_invalid: bool) -> &mut Self {
+        self.conf.drop_invalid = drop_invalid;
+        self
+    }
+
+    pub fn manifest<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.conf.manifest = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn allow_offset(&mut self, allow_offset: bool) -> &mut Self {
+        self.conf.allow_offset = allow_offset;
+        self
+    }
+
+    pub fn idle_exit(&mut self, idle_exit: Duration) -> &mut Self {
+        self.conf.idle_exit = Some(idle_exit);
+        self
+    }
+
+    pub fn sparse(&mut self, sparse: bool) -> &mut Self {
+        self.conf.sparse = sparse;
+        self
+    }
+
+    pub fn per_client_config(&mut self, per_client_config: bool) -> &mut Self {
+        self.conf.per_client_config = per_client_config;
+        self
+    }
+
+    pub fn utf8_filenames(&mut self, utf8_filenames: bool) -> &mut Self {
+        self.conf.utf8_filenames = utf8_filenames;
+        self
+    }
+
+    pub fn require_writable(&mut self, require_writable: bool) -> &mut Self {
+        self.conf.require_writable = require_writable;
+        self
+    }
+
+    pub fn force_blksize(&mut self, force_blksize: usize) -> &mut Self {
+        self.conf.force_blksize = Some(force_blksize);
+        self
+    }
+
+    pub fn control_socket<P: AsRef<Path>>(&mut self, path: P) -> &mut Self {
+        self.conf.control_socket = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn fsync(&mut self, fsync: bool) -> &mut Self {
+        self.conf.fsync = fsync;
+        self
+    }
+
+    pub fn max_filename_len(&mut self, max_filename_len: usize) -> &mut Self {
+        self.conf.max_filename_len = max_filename_len;
+        self
+    }
+
+    pub fn max_rrq_size(&mut self, max_rrq_size: u64) -> &mut Self {
+        self.conf.max_rrq_size = Some(max_rrq_size);
+        self
+    }
+
+    pub fn fork_per_transfer(&mut self, fork_per_transfer: bool) -> &mut Self {
+        self.conf.fork_per_transfer = fork_per_transfer;
+        self
+    }
+
+    /// Logs each transfer with `template` instead of the default messages,
+    /// see `--log-format`.
+    pub fn log_format(&mut self, template: &str) -> &mut Self {
+        self.log_format = Some(template.to_string());
+        self
+    }
+
+    pub fn preallocate(&mut self, preallocate: bool) -> &mut Self {
+        self.conf.preallocate = preallocate;
+        self
+    }
+
+    pub fn raise_nofile(&mut self, raise_nofile: bool) -> &mut Self {
+        self.conf.raise_nofile = raise_nofile;
+        self
+    }
+
+    /// Serves the file `name` in a directory when the directory is requested.
+    pub fn index(&mut self, name: &str) -> &mut Self {
+        self.conf.index = Some(name.to_string());
+        self
+    }
+
+    /// Uploads each received file to the TFTP server at `mirror` as well,
+    /// see `--mirror`.
+    pub fn mirror(&mut self, mirror: SocketAddr) -> &mut Self {
+        self.conf.mirror = Some(mirror);
+        self
+    }
+
+    pub fn strict_mirror(&mut self, strict_mirror: bool) -> &mut Self {
+        self.conf.strict_mirror = strict_mirror;
+        self
+    }
+
+    /// Assumes `mode` for requests without a usable mode instead of
+    /// rejecting them, see `--default-mode`.
+    pub fn default_mode(&mut self, mode: &str) -> &mut Self {
+        self.conf.default_mode = Some(mode.to_lowercase());
+        self
+    }
+
+    pub fn serve_once(&mut self, serve_once: bool) -> &mut Self {
+        self.conf.serve_once = serve_once;
+        self
+    }
+
+    pub fn build(&self) -> Result<Configuration, ConfigError> {
+        let mut conf = self.conf.clone();
+
+        if conf.ro && conf.wo {
+            return Err(ConfigError::ConflictingAccess);
+        }
+        if conf.sparse && conf.append {
+            return Err(ConfigError::ConflictingSparse);
+        }
+        if conf.preallocate && (conf.sparse || conf.append) {
+            return Err(ConfigError::ConflictingPreallocate);
+        }
+        if conf.fork_per_transfer && conf.single_port {
+            return Err(ConfigError::ConflictingForkPerTransfer);
+        }
+        if conf.fork_per_transfer && (conf.metrics_addr.is_some() || conf.control_socket.is_some()) {
+            return Err(ConfigError::ConflictingForkServices);
+        }
+        if conf.fork_per_transfer && conf.total_rate.is_some() {
+            return Err(ConfigError::ConflictingForkRate);
+        }
+        if conf.serve_once && conf.fork_per_transfer {
+            return Err(ConfigError::ConflictingServeOnce);
+        }
+        if conf.mirror.is_some() && conf.append {
+            return Err(ConfigError::ConflictingMirror);
+        }
+        if conf.quiet && conf.verbose {
+            return Err(ConfigError::ConflictingVerbosity);
+        }
+        if conf.threads == 0 {
+            return Err(ConfigError::NoThreads);
+        }
+        if conf.port == 0 {
+            return Err(ConfigError::InvalidPort);
+        }
+        if conf.client_rate == Some(0) {
+            return Err(ConfigError::InvalidClientRate);
+        }
+        if conf.transfer_timeout == Some(Duration::ZERO) {
+            return Err(ConfigError::InvalidTransferTimeout);
+        }
+        if conf.handshake_timeout == Some(Duration::ZERO) {
+            return Err(ConfigError::InvalidHandshakeTimeout);
+        }
+        if conf.idle_exit == Some(Duration::ZERO) {
+            return Err(ConfigError::InvalidIdleExit);
+        }
+        if conf.backoff_multiplier == 0 {
+            return Err(ConfigError::InvalidBackoffMultiplier);
+        }
+        if conf.max_backoff == Some(Duration::ZERO) {
+            return Err(ConfigError::InvalidMaxBackoff);
+        }
+        if conf.total_rate == Some(0) {
+            return Err(ConfigError::InvalidTotalRate);
+        }
+        if conf.max_queue == Some(0) {
+            return Err(ConfigError::InvalidMaxQueue);
+        }
+        if conf.max_filename_len == 0 {
+            return Err(ConfigError::InvalidMaxFilenameLen);
+        }
+        if conf.force_blksize.is_some_and(|blksize| !(8..=crate::packet::MAX_BLKSIZE).contains(&blksize)) {
+            return Err(ConfigError::InvalidForceBlksize);
+        }
+        if conf.dscp.is_some_and(|dscp| dscp > 63) {
+            return Err(ConfigError::InvalidDscp);
+        }
+        if conf.process_name.as_ref().is_some_and(|name| name.is_empty() || name.len() > 15 || name.contains('\0')) {
+            return Err(ConfigError::InvalidProcessName);
+        }
+        if let Some(template) = &self.log_format {
+            conf.log_format = LogFormat::parse(template).map_err(ConfigError::InvalidLogFormat)?;
+        }
+        if conf.map_all.as_ref().is_some_and(|path| path.is_absolute() || path.as_os_str().is_empty()) {
+            return Err(ConfigError::InvalidMapAll);
+        }
+        if conf.index.as_ref().is_some_and(|name| {
+            let mut components = Path::new(name).components();
+            !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) || name.contains('/')
+        }) {
+            return Err(ConfigError::InvalidIndex);
+        }
+        if conf.chroot && !conf.include.is_empty() {
+            return Err(ConfigError::ConflictingInclude);
+        }
+        if conf.chroot && (conf.read_dir.is_some() || conf.write_dir.is_some()) {
+            return Err(ConfigError::ConflictingRoots);
+        }
+        if conf.allow_gzip && !cfg!(feature = "gzip") {
+            return Err(ConfigError::GzipUnsupported);
+        }
+        if conf.modes.is_empty() || conf.modes.iter().any(|mode| mode != "octet" && mode != "netascii") {
+            return Err(ConfigError::InvalidModes);
+        }
+        if conf.default_mode.as_ref().is_some_and(|mode| !conf.modes.contains(mode)) {
+            return Err(ConfigError::InvalidDefaultMode);
+        }
+
+        /* file_allowed compares against the canonical path */
+        let canonical_dir = |dir: PathBuf| match dir.canonicalize() {
+            Ok(d) if d.is_dir() => Ok(d),
+            Ok(_) => {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "not a directory");
+                Err(ConfigError::InvalidDirectory(dir, err))
+            }
+            Err(err) => Err(ConfigError::InvalidDirectory(dir, err)),
+        };
+        conf.dir = canonical_dir(conf.dir)?;
+        conf.read_dir = conf.read_dir.map(canonical_dir).transpose()?;
+        conf.write_dir = conf.write_dir.map(canonical_dir).transpose()?;
+        for include in &mut conf.include {
+            if !include.is_absolute() {
+                let err = io::Error::new(io::ErrorKind::InvalidInput, "not an absolute path");
+                return Err(ConfigError::InvalidInclude(include.clone(), err));
+            }
+            *include = include.canonicalize().map_err(|err| ConfigError::InvalidInclude(include.clone(), err))?;
+        }
+
+        Ok(conf)
+    }
+}
+
+pub struct Tftpd {
+    tftp: crate::Tftp,
+    /* shared with all workers, which only need read access */
+    conf: Arc<Configuration>,
+    limiter: Option<Arc<RateLimiter>>,
+    /* bandwidth shared by all transfers */
+    throttle: Option<Arc<Throttle>>,
+    cache: Option<Arc<FileCache>>,
+    metrics: Arc<Metrics>,
+    /* set while the served directory is unavailable */
+    dir_missing: Arc<AtomicBool>,
+    access_log: Option<Arc<AccessLog>>,
+    manifest: Option<Arc<Manifest>>,
+    /* files currently appended to (--append) */
+    appending: Arc<Appending>,
+    /* size of the completed transfer, for the access log */
+    transferred: u64,
+    /* loads the configuration again on SIGHUP */
+    reload: Option<Box<ReloadFn>>,
+    /* file system access was restricted to the served directory (chroot or landlock) */
+    confined: bool,
+    /* tokens of the running transfers */
+    canceller: Canceller,
+    /* new requests are refused, set over the control socket */
+    draining: Arc<AtomicBool>,
+    /* replaces the served directory, see set_storage */
+    storage: Option<Arc<dyn Storage>>,
+    /* --on-upload commands running in the background */
+    hooks: Arc<AtomicUsize>,
+    /* address the request was sent to, used as source of the replies */
+    local: Option<SocketAddr>,
+}
+
+impl Tftpd {
+    pub fn new(conf: Configuration) -> Tftpd {
+        let throttle = conf.total_rate.map(|rate| Arc::new(Throttle::new(rate)));
+        Tftpd {
+            tftp: Tftpd::protocol(&conf, &throttle),
+            limiter: conf.client_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+            throttle,
+            cache: conf.cache_size.map(|size| Arc::new(FileCache::new(size))),
+            metrics: Default::default(),
+            dir_missing: Default::default(),
+            access_log: None,
+            manifest: None,
+            appending: Default::default(),
+            transferred: 0,
+            reload: None,
+            confined: false,
+            canceller: Default::default(),
+            draining: Default::default(),
+            storage: None,
+            hooks: Default::default(),
+            local: None,
+            conf: Arc::new(conf),
+        }
+    }
+
+    /// Sets how the configuration is loaded again when the server receives
+    /// SIGHUP. `dir`, if given, must be used as served directory, as the server
+    /// can't change it anymore. The new configuration applies to requests
+    /// arriving afterwards; running transfers keep the one they started with.
+    pub fn on_reload<F>(&mut self, reload: F)
+    where
+        F: FnMut(Option<&Path>) -> Option<Configuration> + Send + 'static,
+    {
+        self.reload = Some(Box::new(reload));
+    }
+
+    /// Reads and writes files from `storage` instead of the served directory
+    /// (or `--read-dir` and `--write-dir`). Requests are handled the same way;
+    /// the features that need files of the local file system apply as far as
+    /// `storage` provides them, see [`Storage::local_dir`], [`ReadSeek::as_file`]
+    /// and [`WriteFile::as_file`](crate::storage::WriteFile::as_file).
+    pub fn set_storage(&mut self, storage: Arc<dyn Storage>) {
+        self.storage = Some(storage);
+    }
+
+    /// Returns a handle for cancelling transfers while the server is running.
+    pub fn canceller(&self) -> Canceller {
+        self.canceller.clone()
+    }
+
+    fn protocol(conf: &Configuration, throttle: &Option<Arc<Throttle>>) -> crate::Tftp {
+        let mut tftp = crate::Tftp::new();
+        tftp.set_transfer_timeout(conf.transfer_timeout);
+        tftp.set_handshake_timeout(conf.handshake_timeout);
+        tftp.set_backoff(conf.backoff_multiplier, conf.max_backoff);
+        tftp.set_throttle(throttle.clone());
+        tftp.set_default_mode(conf.default_mode.as_deref().map(|mode| match mode {
+            "netascii" => crate::Mode::NETASCII,
+            _ => crate::Mode::OCTET,
+        }));
+        tftp
+    }
+
+    /// How long a transfer waits for its client before the options apply.
+    fn request_timeout(&self) -> Duration {
+        self.conf.handshake_timeout.unwrap_or(REQUEST_TIMEOUT)
+    }
+
+    /// Creates a handler for a single request, with its own protocol state.
+    fn worker(&self) -> Tftpd {
+        Tftpd {
+            tftp: Tftpd::protocol(&self.conf, &self.throttle),
+            conf: Arc::clone(&self.conf),
+            limiter: self.limiter.clone(),
+            throttle: self.throttle.clone(),
+            cache: self.cache.clone(),
+            metrics: Arc::clone(&self.metrics),
+            dir_missing: Arc::clone(&self.dir_missing),
+            access_log: self.access_log.clone(),
+            manifest: self.manifest.clone(),
+            appending: Arc::clone(&self.appending),
+            transferred: 0,
+            reload: None,
+            confined: self.confined,
+            canceller: self.canceller.clone(),
+            draining: Arc::clone(&self.draining),
+            storage: self.storage.clone(),
+            hooks: Arc::clone(&self.hooks),
+            local: None,
+        }
+    }
+
+    /// Replaces the configuration with the one returned by the
+    /// [`on_reload`](Tftpd::on_reload) callback. Settings only used at startup
+    /// keep their current values.
+    fn reload(&mut self) {
+        let dir = self.confined.then(|| self.conf.dir.clone());
+        let mut conf = match self.reload.as_mut() {
+            Some(reload) => match reload(dir.as_deref()) {
+                Some(conf) => conf,
+                None => {
+                    println!("Reloading the configuration failed, keeping the current one.");
+                    return;
+                }
+            },
+            None => {
+                println!("Ignoring SIGHUP, no configuration to reload.");
+                return;
+            }
+        };
+
+        let old = Arc::clone(&self.conf);
+        let mut kept = Vec::new();
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if conf.$field != old.$field {
+                        conf.$field = old.$field.clone();
+                        kept.push(stringify!($field));
+                    }
+                )*
+            };
+        }
+        keep!(port, uid, gid, chroot, require_drop, require_writable, reuse_port, v6only, threads, single_port,
+              fork_per_transfer, serve_once, raise_nofile, metrics_addr, control_socket, access_log, process_name, manifest);
+        if self.confined {
+            keep!(include, read_dir, write_dir);
+        }
+        if !kept.is_empty() {
+            println!("Changing {} requires a restart, keeping the current values.", kept.join(", "));
+        }
+
+        if conf.client_rate != old.client_rate {
+            self.limiter = conf.client_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+        }
+        if conf.total_rate != old.total_rate {
+            self.throttle = conf.total_rate.map(|rate| Arc::new(Throttle::new(rate)));
+        }
+        if conf.cache_size != old.cache_size {
+            self.cache = conf.cache_size.map(|size| Arc::new(FileCache::new(size)));
+        }
+        self.tftp = Tftpd::protocol(&conf, &self.throttle);
+        self.conf = Arc::new(conf);
+        println!("Configuration reloaded.");
+    }
+
+    /// Refuses requests from clients exceeding `--client-rate`.
+    /// Nothing is sent to them, so spoofed requests can't be used for amplification.
+    fn check_rate_limit(&self, cl: &SocketAddr) -> Result<(), io::Error> {
+        match &self.limiter {
+            Some(limiter) if !limiter.allow(cl, Instant::now()) => {
+                self.metrics.rate_limited();
+                Err(io::Error::other(format!("Dropped request from {} (rate limit exceeded)", Peer(*cl))))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the file name against `--allow-ext`, `--deny-ext` and `--no-dotfiles`.
+    fn name_allowed(&self, path: &Path) -> bool {
+        if self.conf.no_dotfiles && path.components().any(|c| match c {
+            Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+            _ => false,
+        }) {
+            return false;
+        }
+
+        /* only the extension of the final component counts */
+        let ext = path.extension().map(|ext| normalize_ext(&ext.to_string_lossy()));
+        if !self.conf.allow_ext.is_empty() && !ext.as_ref().is_some_and(|ext| self.conf.allow_ext.contains(ext)) {
+            return false;
+        }
+        !ext.is_some_and(|ext| self.conf.deny_ext.contains(&ext))
+    }
+
+    /// Rejects requested names that are absolute or contain control characters
+    /// or backslashes (unless `--allow-backslashes` is given), before they are
+    /// resolved.
+    fn filename_valid(&self, filename: &Path) -> bool {
+        !filename.is_absolute() && self.filename_chars_valid(filename)
+    }
+
+    fn filename_chars_valid(&self, filename: &Path) -> bool {
+        !filename.as_os_str().as_bytes().iter().any(|&b| {
+            b.is_ascii_control() || (self.conf.no_backslashes && b == b'\\')
+        })
+    }
+
+    /// Resolves a read request for a file outside of the served directory that
+    /// is (or is in) one of the `--include` paths. The requested name may be
+    /// absolute or relative to the served directory; the canonical absolute path
+    /// is returned.
+    fn included_path(&self, filename: &Path) -> Option<PathBuf> {
+        if self.conf.include.is_empty() || !self.filename_chars_valid(filename) {
+            return None;
+        }
+
+        let storage = self.storage(Direction::Read);
+        let path = storage.local_dir()?.join(filename).canonicalize().ok()?;
+        self.conf.include.iter().find_map(|include| {
+            let relative = path.strip_prefix(include).ok()?;
+            /* an included file itself is checked by its name */
+            let name = if relative.as_os_str().is_empty() { Path::new(path.file_name()?) } else { relative };
+            self.name_allowed(name).then(|| path.clone())
+        })
+    }
+
+    /// Returns the directory files are read from or written to:
+    /// `--read-dir` or `--write-dir` if given, otherwise the served directory.
+    fn root(&self, direction: Direction) -> &Path {
+        let root = match direction {
+            Direction::Read => &self.conf.read_dir,
+            Direction::Write => &self.conf.write_dir,
+        };
+        root.as_deref().unwrap_or(&self.conf.dir)
+    }
+
+    /// Returns where files are read from or written to: the storage set with
+    /// [`set_storage`](Tftpd::set_storage), otherwise the directory of
+    /// [`root`](Tftpd::root).
+    fn storage(&self, direction: Direction) -> Arc<dyn Storage> {
+        match &self.storage {
+            Some(storage) => Arc::clone(storage),
+            None => Arc::new(FsStorage::canonical(self.root(direction))),
+        }
+    }
+
+    /// Resolves `filename` within the storage, checking both the requested
+    /// and the resolved name.
+    fn file_allowed(&self, filename: &Path, direction: Direction) -> Option<PathBuf> {
+        if !self.filename_valid(filename) || !self.name_allowed(filename) {
+            return None;
+        }
+        let path = self.storage(direction).resolve(filename)?;
+        self.name_allowed(&path).then_some(path)
+    }
+
+    /// Parses a RRQ or WRQ and applies its options to the transfer. With
+    /// `--verbose`, the requested options are logged along with the accepted ones.
+    /// With `--translate-backslash`, backslashes in the file name become slashes.
+    fn parse_request(&mut self, socket: &dyn Transport, cl: &Peer, buf: &[u8]) -> Result<(PathBuf, String, HashMap<String, String>), io::Error> {
+        let (mut filename, mode, mut options) = self.tftp.parse_file_mode_options(buf)?;
+        let len = filename.as_os_str().len();
+        if len > self.conf.max_filename_len {
+            self.tftp.send_error(socket, 2, "File name too long")?;
+            let err = format!("Request from {} refused (file name of {} bytes too long).", cl, len);
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+        }
+        if filename.to_str().is_none() {
+            if self.conf.utf8_filenames {
+                self.tftp.send_error(socket, 0, "File name is not valid UTF-8")?;
+                let err = format!("Request of {:?} from {} refused (file name is not valid UTF-8).", filename.as_os_str(), cl);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+            if !self.conf.quiet {
+                println!("Non-UTF-8 file name {:?} requested by {}, logged as {}.", filename.as_os_str(), cl, filename.display());
+            }
+        }
+        if self.conf.translate_backslash {
+            filename = translate_backslashes(&filename);
+        }
+        let requested = options.clone();
+        /* not requested, so it is only logged as accepted */
+        if let Some(blksize) = self.conf.force_blksize {
+            if !options.contains_key("blksize") && !options.contains_key("blksize2") {
+                options.insert(String::from("blksize"), blksize.to_string());
+            }
+        }
+        self.tftp.init_tftp_options(socket, &mut options)?;
+        /* not part of the protocol; handled by handle_rrq */
+        if self.conf.allow_offset {
+            if let Some(offset) = requested.get("offset").filter(|val| val.parse::<u64>().is_ok()) {
+                options.insert(String::from("offset"), offset.clone());
+            }
+        }
+
+        if self.conf.verbose && !requested.is_empty() {
+            let ignored: HashMap<String, String> = requested.iter()
+                .filter(|(key, _)| !options.contains_key(*key))
+                .map(|(key, val)| (key.clone(), val.clone()))
+                .collect();
+            println!("Options for {} from {}: requested {}; accepted {}; ignored {}.", filename.display(), cl,
+                     format_options(&requested), format_options(&options), format_options(&ignored));
+        }
+        Ok((filename, mode, options))
+    }
+
+    /// Applies the requested transfer mode, if it is one of `--modes`.
+    fn set_mode(&mut self, socket: &dyn Transport, mode: &str) -> Result<(), io::Error> {
+        if !self.conf.modes.iter().any(|allowed| allowed == mode) {
+            self.tftp.send_error(socket, 0, "Unsupported mode")?;
+            return Err(io::Error::other("unsupported mode"));
+        }
+        match mode {
+            "octet" => self.tftp.set_mode(crate::Mode::OCTET),
+            _ => self.tftp.set_mode(crate::Mode::NETASCII),
+        }
+        Ok(())
+    }
+
+    /// Resolves `filename` with [`file_allowed`](Tftpd::file_allowed), rejecting it
+    /// with a permission denied ERROR if it may not be accessed.
+    /// Reads may also resolve to an `--include` path.
+    fn resolve_path(&self, socket: &dyn Transport, cl: &Peer, filename: &Path, direction: Direction) -> Result<PathBuf, io::Error> {
+        let included = || match direction {
+            Direction::Read => self.included_path(filename),
+            Direction::Write => None,
+        };
+        match self.file_allowed(filename, direction).or_else(included) {
+            Some(path) => Ok(path),
+            None => {
+                let err = format!("{} failed (permission check failed).", direction.describe(filename, cl));
+                self.tftp.send_error(socket, 2, "Permission denied")?;
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, err))
+            }
+        }
+    }
+
+    /// Returns the `--index` file in the requested directory, if it is one.
+    /// The composed name still has to pass the usual checks; with
+    /// `--enable-listing`, directories without the index file are listed.
+    fn index_path(&self, filename: &Path) -> Option<PathBuf> {
+        let index = filename.join(self.conf.index.as_ref()?);
+        if !self.filename_valid(filename) {
+            return None;
+        }
+        let storage = self.storage(Direction::Read);
+        storage.resolve_dir(filename)?;
+        if self.conf.enable_listing && !storage.resolve(&index).is_some_and(|path| storage.exists(&path)) {
+            return None;
+        }
+        Some(index)
+    }
+
+    /// Returns the listing of the requested directory, if it is one and within
+    /// the served directory: the servable files in it, one name per line.
+    fn directory_listing(&self, dirname: &Path) -> Option<Vec<u8>> {
+        if !self.filename_valid(dirname) {
+            return None;
+        }
+        let storage = self.storage(Direction::Read);
+        let dir = storage.resolve_dir(dirname)?;
+        if !self.name_allowed(&dir) {
+            return None;
+        }
+
+        let mut names: Vec<String> = storage.list(&dir).ok()?
+            .into_iter()
+            .filter_map(|name| name.into_string().ok())
+            .filter(|name| self.file_allowed(&dir.join(name), Direction::Read).is_some())
+            .collect();
+        names.sort();
+
+        let mut listing = Vec::new();
+        for name in names {
+            listing.extend(name.as_bytes());
+            listing.push(b'\n');
+        }
+        Some(listing)
+    }
+
+    /// Makes sure the directory of the request still exists (it may have been
+    /// removed or unmounted), so requests don't fail with misleading errors.
+    fn check_served_dir(&self, socket: &dyn Transport, direction: Direction) -> Result<(), io::Error> {
+        let storage = self.storage(direction);
+        let dir = match storage.local_dir() {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let err = match fs::metadata(dir) {
+            Ok(metadata) if metadata.is_dir() => None,
+            Ok(_) => Some(String::from("not a directory")),
+            Err(err) => Some(err.to_string()),
+        };
+
+        match err {
+            None => {
+                /* files are always accessed by absolute path, so nothing else to recover */
+                if self.dir_missing.swap(false, Ordering::SeqCst) {
+                    println!("Served directory {} is available again.", dir.display());
+                }
+                Ok(())
+            }
+            Some(err) => {
+                self.dir_missing.store(true, Ordering::SeqCst);
+                self.tftp.send_error(socket, 0, "Served directory unavailable")?;
+                Err(io::Error::new(io::ErrorKind::NotFound,
+                                   format!("Served directory {} is not available ({}).", dir.display(), err)))
+            }
+        }
+    }
+
+    fn handle_wrq(&mut self, socket: &dyn Transport, cl: &Peer, buf: &[u8]) -> Result<String, io::Error> {
+        self.check_served_dir(socket, Direction::Write)?;
+        let (filename, mode, mut options) = self.parse_request(socket, cl, buf)?;
+        /* uploads can't be resumed */
+        options.remove("offset");
+
+        self.set_mode(socket, &mode)?;
+
+        let filename = match self.conf.per_client_subdir {
+            true => self.client_path(socket, cl, &filename)?,
+            false => filename,
+        };
+        let path = self.resolve_path(socket, cl, &filename, Direction::Write)?;
+        let storage = self.storage(Direction::Write);
+
+        /* FIFOs and device nodes are only found in the local file system */
+        let fullpath = storage.local_dir().map(|dir| dir.join(&path));
+        if let Some(fullpath) = &fullpath {
+            if let Ok(metadata) = fs::metadata(fullpath) {
+                if !metadata.is_file() {
+                    if !self.conf.allow_special || !is_special(metadata.file_type()) {
+                        let err = format!("{} failed (not a regular file).", Direction::Write.describe(&path, cl));
+                        self.tftp.send_error(socket, 2, "Not a regular file")?;
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+                    }
+                    return self.recv_special(socket, cl, &path, fullpath, &options);
+                }
+            }
+        }
+
+        /* concurrent uploads of a file would interleave their blocks; without
+           --append, create_new lets exactly one of them create the file */
+        let appending = Arc::clone(&self.appending);
+        let _claim = if self.conf.append {
+            match AppendClaim::new(&appending, &path) {
+                Some(claim) => Some(claim),
+                None => {
+                    let err = format!("{} failed (already being uploaded).", Direction::Write.describe(&path, cl));
+                    self.tftp.send_error(socket, 0, "File is already being uploaded")?;
+                    return Err(io::Error::other(err));
+                }
+            }
+        } else {
+            None
+        };
+
+        let mut opened = match storage.open_write(&path, self.conf.append) {
+            Ok(f) => f,
+            Err(err) => {
+                let error = format!("Receiving {} from {} failed ({}).", path.display(), cl, err);
+                let (code, msg) = open_error(&err);
+                self.tftp.send_error(socket, code, &msg)?;
+                return Err(io::Error::new(err.kind(), error));
+            }
+        };
+
+        /* --sparse, --preallocate and --fsync write to the file itself */
+        let file = opened.as_file().map(|file| file.try_clone()).transpose()?;
+        let synced = match (self.conf.fsync, &file) {
+            (true, Some(file)) => Some(file.try_clone()?),
+            _ => None,
+        };
+        let tsize = options.get("tsize").and_then(|tsize| tsize.parse().ok()).filter(|&size| size > 0);
+        let mut writer: Box<dyn Write> = match (file, self.conf.sparse, tsize) {
+            (Some(file), true, _) => Box::new(SparseFile::new(file)),
+            /* before the OACK, so that the client learns right away if there is no space */
+            (Some(file), false, Some(size)) if self.conf.preallocate => match PreallocatedFile::new(file, size) {
+                Ok(file) => Box::new(file),
+                Err(err) => {
+                    let error = format!("Receiving {} from {} failed (preallocating {} bytes: {}).", path.display(), cl, size, err);
+                    let _ = storage.remove(&path);
+                    let (code, msg) = open_error(&err);
+                    self.tftp.send_error(socket, code, &msg)?;
+                    return Err(io::Error::new(err.kind(), error));
+                }
+            },
+            _ => Box::new(opened),
+        };
+        self.tftp.ack_options(socket, &options, false)?;
+        if let (Some(file), Some(fullpath)) = (synced, &fullpath) {
+            let dir = fullpath.parent().unwrap_or(Path::new(".")).to_path_buf();
+            writer = Box::new(SyncedFile { inner: writer, file, dir });
+        }
+        match self.tftp.recv_file(socket, &mut writer) {
+            Ok(size) => {
+                self.metrics.received(size);
+                self.transferred = size;
+                self.mirror_upload(&*storage, &path, cl)?;
+                self.run_upload_hook(&storage, &path, cl);
+                Ok(format!("Received {} ({} bytes) from {}.", path.display(), size, cl))
+            }
+            Err(err) => {
+                /* an ERROR of the client must not be answered, nor one sent on cancellation */
+                if !crate::error_exchanged(&err) {
+                    self.tftp.send_error(socket, 0, "Receiving error")?;
+                }
+                Err(Direction::Write.failed(&path, cl, &err))
+            }
+        }
+    }
+
+    /// Sends a received file to the `--mirror` server under the same name.
+    /// A failure is only logged, unless `--strict-mirror` is set: then the
+    /// file is deleted and the upload reported as failed.
+    fn mirror_upload(&self, storage: &dyn Storage, path: &Path, cl: &Peer) -> Result<(), io::Error> {
+        let upstream = match self.conf.mirror {
+            Some(upstream) => upstream,
+            None => return Ok(()),
+        };
+        let result = path.to_str().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "file name is not valid UTF-8"))
+            .and_then(|name| {
+                let file = storage.open_read(path)?;
+                let options = HashMap::from([(String::from("tsize"), storage.size(path)?.to_string())]);
+                crate::Tftp::new().put(upstream, name, file, &options)
+            });
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) if self.conf.strict_mirror => {
+                let _ = storage.remove(path);
+                let error = format!("{} failed (mirroring to {}: {}).", Direction::Write.describe(path, cl), upstream, err);
+                Err(io::Error::new(err.kind(), error))
+            }
+            Err(err) => {
+                println!("Mirroring {} to {} failed: {}", path.display(), upstream, err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Runs the `--on-upload` command for a received file, in the background
+    /// so it does not occupy a worker. With as many commands running as there
+    /// are workers, the worker waits for it instead, so that slow commands
+    /// don't pile up. A child process of `--fork-per-transfer` always waits,
+    /// as it exits after the transfer; there are no more children than workers.
+    /// It inherits the dropped privileges. The command gets the location of
+    /// the file in the local file system, or else its name.
+    fn run_upload_hook(&self, storage: &Arc<dyn Storage>, path: &Path, cl: &Peer) {
+        let command = match &self.conf.on_upload {
+            Some(command) => command,
+            None => return,
+        };
+        let location = storage.local_dir().map_or_else(|| path.to_path_buf(), |dir| dir.join(path));
+        let child = match Command::new(command).arg(&location).env("RTFTP_CLIENT", cl.to_string()).spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                println!("Running upload hook for {} failed: {}", location.display(), err);
+                return;
+            }
+        };
+
+        let delete_on_failure = self.conf.on_upload_delete;
+        let quiet = self.conf.quiet;
+        if self.conf.fork_per_transfer {
+            wait_upload_hook(child, &**storage, path, &location, delete_on_failure, quiet);
+            return;
+        }
+        if self.hooks.fetch_add(1, Ordering::SeqCst) >= self.conf.threads {
+            self.hooks.fetch_sub(1, Ordering::SeqCst);
+            wait_upload_hook(child, &**storage, path, &location, delete_on_failure, quiet);
+            return;
+        }
+        let hooks = Arc::clone(&self.hooks);
+        let storage = Arc::clone(storage);
+        let path = path.to_path_buf();
+        thread::spawn(move || {
+            wait_upload_hook(child, &*storage, &path, &location, delete_on_failure, quiet);
+            hooks.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+
+    /// Places an upload in the directory of its client (`--per-client-subdir`),
+    /// creating the directory if needed. Names leading out of it are refused,
+    /// so clients can't write into the directories of others.
+    fn client_path(&self, socket: &dyn Transport, cl: &Peer, filename: &Path) -> Result<PathBuf, io::Error> {
+        if filename.components().any(|c| c == Component::ParentDir) {
+            let err = format!("{} failed (outside of the client directory).", Direction::Write.describe(filename, cl));
+            self.tftp.send_error(socket, 2, "Permission denied")?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+        }
+
+        let subdir = PathBuf::from(client_subdir(cl.0.ip()));
+        if let Err(err) = self.storage(Direction::Write).create_dir(&subdir) {
+            let error = format!("Creating directory {} for {} failed ({}).", subdir.display(), cl, err);
+            let (code, msg) = open_error(&err);
+            self.tftp.send_error(socket, code, &msg)?;
+            return Err(io::Error::new(err.kind(), error));
+        }
+        Ok(subdir.join(filename))
+    }
+
+    /// Returns the file of the client's own directory (`--per-client-config`)
+    /// to send instead of the requested one, if there is one.
+    fn client_override(&self, cl: &Peer, filename: &Path) -> Option<PathBuf> {
+        if !self.conf.per_client_config || self.conf.map_all.is_some() {
+            return None;
+        }
+        /* neither the directories of other clients nor absolute paths */
+        if !filename.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+            return None;
+        }
+
+        let overridden = Path::new(&client_subdir(cl.0.ip())).join(filename);
+        let path = self.file_allowed(&overridden, Direction::Read)?;
+        let storage = self.storage(Direction::Read);
+        match storage.exists(&path) && storage.resolve_dir(&path).is_none() {
+            true => Some(overridden),
+            false => None,
+        }
+    }
+
+    /// Writes an upload into an existing FIFO or device node (`--allow-special`).
+    fn recv_special(&mut self, socket: &dyn Transport, cl: &Peer, path: &Path, fullpath: &Path, options: &HashMap<String, String>) -> Result<String, io::Error> {
+        /* fails instead of blocking if a FIFO has no reader */
+        let file = match OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(fullpath) {
+            Ok(f) => f,
+            Err(err) => {
+                let error = format!("Receiving {} from {} failed ({}).", path.display(), cl, err);
+                let (code, msg) = open_error(&err);
+                self.tftp.send_error(socket, code, &msg)?;
+                return Err(io::Error::new(err.kind(), error));
+            }
+        };
+
+        self.tftp.ack_options(socket, options, false)?;
+        match self.tftp.recv_file(socket, &mut SpecialFile::new(file)) {
+            Ok(size) => {
+                self.metrics.received(size);
+                self.transferred = size;
+                Ok(format!("Received {} ({} bytes) from {}.", path.display(), size, cl))
+            }
+            Err(err) => {
+                if !crate::error_exchanged(&err) {
+                    self.tftp.send_error(socket, 0, "Receiving error")?;
+                }
+                Err(Direction::Write.failed(path, cl, &err))
+            }
+        }
+    }
+
+    /// Returns the content of `file` from the `--cache-size` cache, reading it
+    /// into the cache if missing or outdated. Only local files are cached.
+    fn cached_content(&self, file: &mut dyn ReadSeek, path: &Path) -> Option<Arc<[u8]>> {
+        let cache = self.cache.as_ref()?;
+        let metadata = file.as_file()?.metadata().ok()?;
+        let modified = metadata.modified().ok()?;
+        let key = self.storage(Direction::Read).local_dir()?.join(path);
+
+        if let Some(data) = cache.get(&key, modified, metadata.len()) {
+            return Some(data);
+        }
+        if metadata.len() > cache.capacity() as u64 {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(metadata.len() as usize);
+        if file.read_to_end(&mut data).is_err() {
+            /* let the regular transfer deal with it */
+            file.rewind().ok()?;
+            return None;
+        }
+        let data: Arc<[u8]> = data.into();
+        cache.insert(&key, modified, Arc::clone(&data));
+        Some(data)
+    }
+
+    /// Maps `file` into memory for `--mmap`; `None` if that is not possible.
+    fn map_file(&self, file: &File, path: &Path) -> Option<Mapping> {
+        /* empty files can't be mapped, but there is nothing to read anyway */
+        let len = NonZeroUsize::new(usize::try_from(file.metadata().ok()?.len()).ok()?)?;
+        match unsafe { mmap(None, len, ProtFlags::PROT_READ, MapFlags::MAP_SHARED, file.as_raw_fd(), 0) } {
+            Ok(addr) => Some(Mapping { addr, len: len.get() }),
+            Err(err) => {
+                if self.conf.verbose {
+                    println!("Mapping {} failed ({}), reading it instead.", path.display(), err);
+                }
+                None
+            }
+        }
+    }
+
+    fn handle_rrq(&mut self, socket: &dyn Transport, cl: &Peer, buf: &[u8]) -> Result<String, io::Error> {
+        self.check_served_dir(socket, Direction::Read)?;
+        let (filename, mode, mut options) = self.parse_request(socket, cl, buf)?;
+        /* acknowledged again below if the file can be sent from there */
+        let offset: Option<u64> = options.remove("offset").and_then(|val| val.parse().ok());
+
+        self.set_mode(socket, &mode)?;
+
+        /* --map-all replaces every requested name, including directories */
+        let filename = match &self.conf.map_all {
+            Some(mapped) => {
+                if !self.conf.quiet {
+                    println!("Serving {} to {} for requested {}.", mapped.display(), cl, filename.display());
+                }
+                mapped.clone()
+            }
+            None => filename,
+        };
+        let filename = match self.client_override(cl, &filename) {
+            Some(overridden) => {
+                if self.conf.verbose {
+                    println!("Serving {} to {} for requested {}.", overridden.display(), cl, filename.display());
+                }
+                overridden
+            }
+            None => filename,
+        };
+
+        let filename = match self.index_path(&filename) {
+            Some(index) => {
+                if self.conf.verbose {
+                    println!("Serving {} to {} for requested {}.", index.display(), cl, filename.display());
+                }
+                index
+            }
+            None => filename,
+        };
+
+        if self.conf.enable_listing && self.conf.map_all.is_none() {
+            if let Some(listing) = self.directory_listing(&filename) {
+                self.tftp.set_transfer_size(&mut options, Some(listing.len() as u64));
+                self.tftp.ack_options(socket, &options, true)?;
+                return match self.tftp.send_slice(socket, &listing) {
+                    Ok(size) => {
+                        self.metrics.sent(size);
+                        self.transferred = size;
+                        Ok(format!("Sent listing of {} ({} bytes) to {}.", filename.display(), size, cl))
+                    }
+                    Err(err) => {
+                        let error = format!("Sending listing of {} to {} failed ({}).", filename.display(), cl, failure_reason(&err));
+                        Err(std::io::Error::new(err.kind(), error))
+                    }
+                };
+            }
+        }
+
+        #[cfg(feature = "gzip")]
+        if self.conf.allow_gzip && self.conf.map_all.is_none() && mode == "octet" {
+            if let Some((path, mut file)) = self.gzip_source(&filename) {
+                self.check_manifest(socket, cl, &path, Some(&mut *file))?;
+                self.check_rrq_size(socket, cl, &path, self.storage(Direction::Read).size(&path)?)?;
+                return self.send_gzipped(socket, cl, &path, file, options);
+            }
+        }
+
+        let path = self.resolve_path(socket, cl, &filename, Direction::Read)?;
+        let storage = self.storage(Direction::Read);
+
+        let mut file = match storage.open_read(&path) {
+            Ok(f) => f,
+            Err(error) => {
+                let err = format!("Sending {} to {} failed ({}).", path.display(), cl, error);
+                let (code, msg) = open_error(&error);
+                self.tftp.send_error(socket, code, &msg)?;
+                return Err(io::Error::new(error.kind(), err));
+            }
+        };
+        /* FIFOs and device nodes are only found in the local file system */
+        let metadata = file.as_file().map(|file| file.metadata()).transpose()?;
+        if let Some(file_type) = metadata.as_ref().map(fs::Metadata::file_type).filter(|file_type| !file_type.is_file()) {
+            if !self.conf.allow_special || !is_special(file_type) {
+                let err = format!("{} failed (not a regular file).", Direction::Read.describe(&path, cl));
+                self.tftp.send_error(socket, 2, "Not a regular file")?;
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+            }
+            self.check_manifest(socket, cl, &path, None)?;
+
+            /* the size is unknown in advance */
+            self.tftp.set_transfer_size(&mut options, None);
+            self.tftp.ack_options(socket, &options, true)?;
+            let mut special = SpecialFile::new(file).take(SPECIAL_FILE_LIMIT);
+            return match self.tftp.send_reader(socket, &mut special, 0) {
+                Ok(size) => {
+                    self.metrics.sent(size);
+                    self.transferred = size;
+                    Ok(format!("Sent {} ({} bytes) to {}.", path.display(), size, cl))
+                }
+                Err(err) => Err(Direction::Read.failed(&path, cl, &err)),
+            };
+        }
+
+        self.check_manifest(socket, cl, &path, Some(&mut *file))?;
+        let len = match &metadata {
+            Some(metadata) => metadata.len(),
+            None => storage.size(&path)?,
+        };
+        self.check_rrq_size(socket, cl, &path, len)?;
+
+        /* a resumed download (--allow-offset) skips what the client already has */
+        let offset = match offset {
+            Some(offset) if mode == "octet" => {
+                if offset > len {
+                    self.tftp.send_error(socket, 8, "Offset beyond end of file")?;
+                    let err = format!("{} failed (offset {} beyond end of file).", Direction::Read.describe(&path, cl), offset);
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, err));
+                }
+                options.insert(String::from("offset"), offset.to_string());
+                offset
+            }
+            _ => 0,
+        };
+
+        /* with tsize, no more than the reported size is sent, should the file grow meanwhile */
+        let limit = match options.contains_key("tsize") {
+            true => {
+                let size = self.tftp.transfersize_of(&mut *file, len - offset)?;
+                self.tftp.set_transfer_size(&mut options, Some(size));
+                len - offset
+            }
+            false => u64::MAX,
+        };
+        let cached = self.cached_content(&mut *file, &path);
+        let mapping = match file.as_file() {
+            Some(local) if self.conf.mmap && cached.is_none() => self.map_file(local, &path),
+            _ => None,
+        };
+        self.tftp.ack_options(socket, &options, true)?;
+        let result = match (&cached, &mapping) {
+            (Some(data), _) => self.tftp.send_slice(socket, truncated(skipped(data, offset), limit)),
+            (None, Some(mapping)) => self.tftp.send_slice(socket, truncated(skipped(mapping.as_slice(), offset), limit)),
+            (None, None) => file.seek(io::SeekFrom::Start(offset)).and_then(|_| match file.as_file() {
+                Some(local) => self.tftp.send_file_part(socket, local, limit),
+                None => self.tftp.send_reader(socket, &mut io::BufReader::new(file.take(limit)), len - offset),
+            }),
+        };
+        match result {
+            Ok(size) => {
+                self.metrics.sent(size);
+                self.transferred = size;
+                let resumed = if offset > 0 { format!(" from offset {}", offset) } else { String::new() };
+                Ok(format!("Sent {} ({} bytes{}) to {}.", path.display(), size, resumed, cl))
+            }
+            Err(err) => Err(Direction::Read.failed(&path, cl, &err)),
+        }
+    }
+
+    /// Returns the file to compress for a request of `filename` with `--allow-gzip`:
+    /// the servable regular file named like it without its `.gz` suffix,
+    /// if the requested name itself does not exist.
+    #[cfg(feature = "gzip")]
+    fn gzip_source(&self, filename: &Path) -> Option<(PathBuf, Box<dyn ReadSeek>)> {
+        let original = filename.to_str()?.strip_suffix(".gz")?;
+        let storage = self.storage(Direction::Read);
+        if self.file_allowed(filename, Direction::Read).is_some_and(|path| storage.exists(&path)) {
+            return None;
+        }
+        let path = self.file_allowed(Path::new(original), Direction::Read)?;
+        let mut file = storage.open_read(&path).ok()?;
+        let regular = match file.as_file() {
+            Some(local) => local.metadata().ok()?.is_file(),
+            None => true,
+        };
+        regular.then_some((path, file))
+    }
+
+    /// Sends `file` compressed with gzip; its size is not known in advance.
+    #[cfg(feature = "gzip")]
+    fn send_gzipped(&mut self, socket: &dyn Transport, cl: &Peer, path: &Path, file: Box<dyn ReadSeek>, mut options: HashMap<String, String>) -> Result<String, io::Error> {
+        use flate2::{read::GzEncoder, Compression};
+
+        self.tftp.set_transfer_size(&mut options, None);
+        self.tftp.ack_options(socket, &options, true)?;
+        let mut encoder = GzEncoder::new(io::BufReader::new(file), Compression::default());
+        match self.tftp.send_reader(socket, &mut encoder, 0) {
+            Ok(size) => {
+                self.metrics.sent(size);
+                self.transferred = size;
+                Ok(format!("Sent {} compressed ({} bytes) to {}.", path.display(), size, cl))
+            }
+            Err(err) => Err(Direction::Read.failed(path, cl, &err)),
+        }
+    }
+
+    /// Applies the configured socket buffer sizes; the kernel may adjust them.
+    fn set_buffer_sizes(&self, socket: &UdpSocket) -> Result<(), io::Error> {
+        let fd = socket.as_raw_fd();
+        if let Some(size) = self.conf.rcvbuf {
+            setsockopt(fd, sockopt::RcvBuf, &size)?;
+            if self.conf.verbose {
+                println!("Receive buffer size set to {} bytes.", getsockopt(fd, sockopt::RcvBuf)?);
+            }
+        }
+        if let Some(size) = self.conf.sndbuf {
+            setsockopt(fd, sockopt::SndBuf, &size)?;
+            if self.conf.verbose {
+                println!("Send buffer size set to {} bytes.", getsockopt(fd, sockopt::SndBuf)?);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn handle_client(&mut self, cl: &SocketAddr, buf: &[u8]) -> Result<String, io::Error> {
+        self.check_rate_limit(cl)?;
+        self.handle_connected(cl, buf)
+    }
+
+    /// Handles the request of `cl` in a child process and returns its id,
+    /// see `--fork-per-transfer`. The child closes its copy of the listening
+    /// `socket` and exits when the transfer is done.
+    fn fork_transfer(&self, socket: &UdpSocket, cl: SocketAddr, local: Option<SocketAddr>, buf: &[u8]) -> Result<Pid, io::Error> {
+        /* or output buffered until now would be written by both processes */
+        io::stdout().flush()?;
+        match unsafe { fork() }? {
+            ForkResult::Parent { child } => Ok(child),
+            ForkResult::Child => {
+                /* the listening socket stays with the server; errors before the
+                   transfer started are sent from a socket of their own */
+                let unspecified = match socket.local_addr() {
+                    Ok(addr) if addr.is_ipv4() => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+                    _ => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+                };
+                unsafe { libc::close(socket.as_raw_fd()) };
+                let reply = match UdpSocket::bind(unspecified) {
+                    Ok(reply) => reply,
+                    Err(err) => {
+                        println!("Binding a socket for {} failed: {}", Peer(cl), err);
+                        let _ = io::stdout().flush();
+                        process::exit(1);
+                    }
+                };
+                let mut worker = self.worker();
+                worker.local = local;
+                run_handler(&reply, cl, self.conf.quiet, || worker.handle_connected(&cl, buf));
+                let _ = io::stdout().flush();
+                process::exit(0);
+            }
+        }
+    }
+
+    /// Handles the request on a new socket connected to `cl`, without
+    /// checking the rate limit again.
+    fn handle_connected(&mut self, cl: &SocketAddr, buf: &[u8]) -> Result<String, io::Error> {
+        /* replies leave from the address the client sent its request to, which
+           the client may expect on hosts with several addresses */
+        let socket = match self.local.map(UdpSocket::bind) {
+            Some(Ok(socket)) => socket,
+            /* e.g. for requests sent to a broadcast address */
+            _ => UdpSocket::bind("[::]:0").map_err(|err| descriptor_error(cl, err))?,
+        };
+        self.set_buffer_sizes(&socket)?;
+        if let Some(dscp) = self.conf.dscp {
+            if let Err(err) = set_dscp(&socket, cl, dscp) {
+                println!("Setting DSCP for {} failed: {}", cl, err);
+            }
+        }
+        socket.set_read_timeout(Some(self.request_timeout()))?;
+        /* keeps the scope id, so replies to link-local clients leave on their interface */
+        socket.connect(cl)?;
+
+        self.handle_request(&socket, cl, buf)
+    }
+
+    fn handle_request(&mut self, socket: &dyn Transport, cl: &SocketAddr, buf: &[u8]) -> Result<String, io::Error> {
+        let metrics = Arc::clone(&self.metrics);
+        let _active = metrics.active();
+        let started = (SystemTime::now(), Instant::now());
+        let result = self.dispatch_request(socket, &Peer(*cl), buf);
+        if let Err(ref err) = result {
+            metrics.error(err.kind());
+        }
+
+        let direction = match buf.get(..2).map(|op| crate::Opcode::try_from(u16::from_be_bytes([op[0], op[1]]))) {
+            Some(Ok(crate::Opcode::RRQ)) => "read",
+            Some(Ok(crate::Opcode::WRQ)) => "write",
+            _ => return result,
+        };
+        let filename = buf[2..].split(|&b| b == 0).next().unwrap_or_default();
+        let record = Record {
+            time: started.0,
+            client: *cl,
+            direction,
+            filename: &String::from_utf8_lossy(filename),
+            bytes: self.transferred,
+            duration: started.1.elapsed(),
+            error_code: self.tftp.last_error(),
+            error: result.as_ref().err().map(|err| err.to_string()),
+        };
+        self.log_access(&record);
+
+        match result {
+            Ok(msg) => Ok(self.conf.log_format.format(&record, &msg)),
+            Err(err) => {
+                let line = self.conf.log_format.format(&record, &err.to_string());
+                /* unchanged errors keep their source, e.g. for running out of descriptors */
+                if line == err.to_string() {
+                    Err(err)
+                } else {
+                    Err(io::Error::new(err.kind(), line))
+                }
+            }
+        }
+    }
+
+    /// Appends the outcome of a read or write request to the `--access-log`.
+    fn log_access(&self, record: &Record) {
+        if let Some(access_log) = &self.access_log {
+            if let Err(err) = access_log.record(record) {
+                println!("Writing to the access log failed: {}", err);
+            }
+        }
+    }
+
+    /// Opens the `--access-log`, unless that already happened.
+    fn open_access_log(&mut self) -> Result<(), io::Error> {
+        if let (Some(path), None) = (&self.conf.access_log, &self.access_log) {
+            let access_log = AccessLog::open(path)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+            self.access_log = Some(Arc::new(access_log));
+        }
+        Ok(())
+    }
+
+    /// Loads the `--manifest`, unless that already happened.
+    fn load_manifest(&mut self) -> Result<(), io::Error> {
+        if let (Some(path), None) = (&self.conf.manifest, &self.manifest) {
+            let manifest = Manifest::open(path)
+                .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+            self.manifest = Some(Arc::new(manifest));
+        }
+        Ok(())
+    }
+
+    /// Refuses to send `file` as `path` if it does not match the `--manifest`.
+    /// Without `file`, its content can't be checked and it is refused as well.
+    fn check_manifest(&self, socket: &dyn Transport, cl: &Peer, path: &Path, file: Option<&mut dyn ReadSeek>) -> Result<(), io::Error> {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            None => return Ok(()),
+        };
+        let verified = match file {
+            Some(file) => manifest.verify(path, file)?,
+            None => false,
+        };
+        if !verified {
+            let err = format!("{} failed (not matching the manifest).", Direction::Read.describe(path, cl));
+            self.tftp.send_error(socket, 2, "Access violation")?;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+        }
+        Ok(())
+    }
+
+    /// Refuses to send a file of `len` bytes larger than `--max-rrq-size`.
+    fn check_rrq_size(&self, socket: &dyn Transport, cl: &Peer, path: &Path, len: u64) -> Result<(), io::Error> {
+        match self.conf.max_rrq_size {
+            Some(max) if len > max => {
+                let err = format!("{} failed (size of {} bytes exceeds the limit of {}).", Direction::Read.describe(path, cl), len, max);
+                self.tftp.send_error(socket, 2, &format!("File too large (more than {} bytes)", max))?;
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the `--allow-from` rules; without any, all clients are allowed.
+    fn access_allowed(&self, cl: &Peer, direction: Direction) -> bool {
+        let rules = &self.conf.allow_from;
+        rules.is_empty() || rules.iter().any(|rule| rule.contains(cl.0.ip()) && rule.permits(direction))
+    }
+
+    fn dispatch_request(&mut self, socket: &dyn Transport, cl: &Peer, buf: &[u8]) -> Result<String, io::Error> {
+        if self.conf.drop_invalid && !is_request(buf) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("dropped invalid request from {}", cl)));
+        }
+        if buf.len() < 2 {
+            self.tftp.send_error(socket, 0, "Invalid request length")?;
+            return Err(io::Error::other("invalid request length"));
+        }
+
+        match crate::Opcode::try_from(u16::from_be_bytes([buf[0], buf[1]])) {
+            Ok(crate::Opcode::RRQ) => {
+                if self.conf.wo {
+                    self.tftp.send_error(socket, 4, "reading not allowed")?;
+                    Err(io::Error::other("unallowed mode"))
+                } else if !self.access_allowed(cl, Direction::Read) {
+                    self.tftp.send_error(socket, 4, "reading not allowed")?;
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Reading not allowed for {}", cl)))
+                } else {
+                    self.handle_rrq(socket, cl, &buf[2..])
+                }
+            }
+            Ok(crate::Opcode::WRQ) => {
+                if self.conf.ro {
+                    self.tftp.send_error(socket, 4, "writing not allowed")?;
+                    Err(io::Error::other("unallowed mode"))
+                } else if !self.access_allowed(cl, Direction::Write) {
+                    self.tftp.send_error(socket, 4, "writing not allowed")?;
+                    Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Writing not allowed for {}", cl)))
+                } else {
+                    self.handle_wrq(socket, cl, &buf[2..])
+                }
+            }
+            Ok(crate::Opcode::ERROR) => Ok(format!("Received ERROR from {}", cl)),
+            Ok(opcode) => {
+                self.tftp.send_error(socket, 4, "Unexpected opcode")?;
+                Err(io::Error::other(format!("unexpected opcode {} from {}", opcode, cl)))
+            }
+            Err(err) => {
+                self.tftp.send_error(socket, 4, "Unexpected opcode")?;
+                Err(err)
+            }
+        }
+    }
+
+    fn drop_privs(&self, uid: u32, gid: u32) -> Result<(), Box<dyn Error>> {
+        let root_uid = ROOT;
+        let root_gid = Gid::from_raw(0);
+        let unpriv_uid = Uid::from_raw(uid);
+        let unpriv_gid = Gid::from_raw(gid);
+
+        if Gid::current() != root_gid
+            && Gid::effective() != root_gid
+            && Uid::current() != root_uid
+            && Uid::effective() != root_uid
+        {
+            /* already unprivileged user; capabilities it may have been
+               started with (CAP_NET_BIND_SERVICE) are not needed anymore */
+            drop_capabilities()?;
+        } else {
+            if Gid::current() == root_gid || Gid::effective() == root_gid {
+                setresgid(unpriv_gid, unpriv_gid, unpriv_gid)?;
+            }
+
+            if Uid::current() == root_uid || Uid::effective() == root_uid {
+                setresuid(unpriv_uid, unpriv_uid, unpriv_uid)?;
+            }
+        }
+
+        if self.conf.require_drop
+            && !running_as(unpriv_uid, unpriv_gid, [Uid::current(), Uid::effective()], [Gid::current(), Gid::effective()])
+        {
+            let err = format!("still running as uid {}, gid {} instead of uid {}, gid {}",
+                              Uid::effective().as_raw(), Gid::effective().as_raw(), uid, gid);
+            return Err(Box::new(io::Error::new(io::ErrorKind::PermissionDenied, err)));
+        }
+
+        Ok(())
+    }
+
+    fn chroot_destdir(&mut self) -> Result<(), nix::Error> {
+        /* included paths and separate roots would not be reachable anymore */
+        if !self.conf.include.is_empty() || self.conf.read_dir.is_some() || self.conf.write_dir.is_some() {
+            return Ok(());
+        }
+        /* chroot will only succeed if we have required permissions;
+           either running as root or having CAP_SYS_CHROOT.
+           propagate error only if chroot should have succeeded,
+           or if it was explicitly requested. */
+        match chroot(&self.conf.dir) {
+            Ok(_) => {
+                /* don't keep a working directory outside of the new root */
+                chdir("/")?;
+                /* configured dir is now new root directory */
+                Arc::make_mut(&mut self.conf).dir = PathBuf::from("/");
+                self.confined = true;
+                Ok(())
+            },
+            Err(err) if self.conf.chroot => Err(err),
+            Err(nix::errno::Errno::EPERM) => Ok(()),
+            Err(err) if Uid::effective() == ROOT => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+
+    #[cfg(feature = "landlock")]
+    fn restrict_filesystem(&mut self) {
+        let abi = ABI::V1;
+        let access_all = AccessFs::from_all(abi);
+        let access_read = AccessFs::from_read(abi);
+        let access_write = AccessFs::from_write(abi);
+
+        let pathfd = |dir: &Path| PathFd::new(dir).expect("Directory can't be opened");
+        /* read and write rights are granted on their own roots; without
+           --read-dir and --write-dir, both are the served directory */
+        let mut roots = Vec::new();
+        if !self.conf.wo {
+            roots.push((pathfd(self.root(Direction::Read)), access_read));
+        }
+        if !self.conf.ro {
+            roots.push((pathfd(self.root(Direction::Write)), access_write));
+        }
+        /* includes are only ever read */
+        let mut includes: Vec<_> = if self.conf.wo {
+            Vec::new()
+        } else {
+            self.conf.include.iter().map(|include| {
+                /* rights only applying to directories can't be granted on files */
+                let access = if include.is_dir() { access_read } else { AccessFs::ReadFile.into() };
+                (PathFd::new(include).expect("Included path can't be opened"), access)
+            }).collect()
+        };
+        /* the configuration file is read again on SIGHUP; its whole directory
+           is allowed, as editors often replace the file instead of writing it */
+        if let Some(dir) = self.conf.config_file.as_ref().and_then(|file| file.parent()) {
+            let pathfd = PathFd::new(dir).expect("Directory of the configuration file can't be opened");
+            includes.push((pathfd, AccessFs::ReadFile.into()));
+        }
+
+        let restrict = || -> Result<RestrictionStatus, RulesetError> {
+            let mut ruleset = landlock::Ruleset::new()
+                    .handle_access(access_all)?
+                    .create()?;
+            for (pathfd, access) in roots.into_iter().chain(includes) {
+                ruleset = ruleset.add_rule(PathBeneath::new(pathfd, access))?;
+            }
+            ruleset.restrict_self()
+        };
+
+        let status = restrict().expect("Setting up landlock restriction failed");
+        if status.ruleset != RulesetStatus::FullyEnforced {
+            eprintln!("Landlock restrictions not (fully) applied (maybe kernel too old?).");
+        }
+        self.confined |= status.ruleset != RulesetStatus::NotEnforced;
+    }
+
+    /// Describes where the server listens and what it serves, for the
+    /// message announcing that it is ready.
+    fn listening(&self, socket: &UdpSocket) -> String {
+        let addr = socket.local_addr().map_or_else(|_| String::from("unknown address"), |addr| addr.to_string());
+        let (read, write) = (self.root(Direction::Read), self.root(Direction::Write));
+        if read == write {
+            format!("Listening on {}, serving {}.", addr, read.display())
+        } else {
+            format!("Listening on {}, serving {} and writing to {}.", addr, read.display(), write.display())
+        }
+    }
+
+    /// Listens on the configured port, changes root and drops privileges as
+    /// configured, and serves requests until a shutdown is requested.
+    /// Returns false if the server could not be started, otherwise like
+    /// [`serve`](Tftpd::serve).
+    pub fn start(&mut self) -> bool {
+        /* before any thread is started, so that all of them are named */
+        if let Some(name) = &self.conf.process_name {
+            if let Err(err) = set_process_name(name) {
+                eprintln!("Setting the process name failed: {}", err);
+                return false;
+            }
+        }
+
+        if self.conf.raise_nofile {
+            match raise_nofile_limit() {
+                Ok((previous, limit)) if self.conf.verbose && previous != limit => {
+                    println!("Raised the limit of open files from {} to {}.", previous, limit);
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("Raising the limit of open files failed: {}", err),
+            }
+        }
+
+        let addr = SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, self.conf.port, 0, 0));
+        let socket = match bind_socket(addr, self.conf.reuse_port, self.conf.v6only) {
+            Ok(s) => s,
+            Err(err) => {
+                let hint = if err.kind() == io::ErrorKind::PermissionDenied && self.conf.port < 1024 && !has_capability(CAP_NET_BIND_SERVICE) {
+                    " (ports below 1024 require root or CAP_NET_BIND_SERVICE)"
+                } else {
+                    ""
+                };
+                eprintln!("Binding a socket failed: {}{}", err, hint);
+                return false;
+            }
+        };
+
+        if let Err(err) = self.set_buffer_sizes(&socket) {
+            eprintln!("Setting socket buffer sizes failed: {}", err);
+            return false;
+        }
+
+        if let Some(addr) = self.conf.metrics_addr {
+            match TcpListener::bind(addr) {
+                Ok(listener) => crate::metrics::serve(listener, Arc::clone(&self.metrics)),
+                Err(err) => {
+                    eprintln!("Binding the metrics listener to {} failed: {}", addr, err);
+                    return false;
+                }
+            }
+        }
+        /* removed again when the server stops */
+        let _control = match &self.conf.control_socket {
+            Some(path) => match ControlSocket::bind(path) {
+                Ok(control) => {
+                    if let Err(err) = control.serve(Arc::clone(&self.draining), Arc::clone(&self.metrics)) {
+                        eprintln!("Serving the control socket {} failed: {}", path.display(), err);
+                        return false;
+                    }
+                    Some(control)
+                }
+                Err(err) => {
+                    eprintln!("Binding the control socket {} failed: {}", path.display(), err);
+                    return false;
+                }
+            },
+            None => None,
+        };
+
+        /* opened before chroot and landlock, it may be outside of the served directory */
+        if let Err(err) = self.open_access_log() {
+            eprintln!("Opening the access log failed: {}", err);
+            return false;
+        }
+        if let Err(err) = self.load_manifest() {
+            eprintln!("Loading the manifest failed: {}", err);
+            return false;
+        }
+
+        /* the paths are those outside of a changed root */
+        let listening = self.listening(&socket);
+        let notify = match env::var_os("NOTIFY_SOCKET").map(|path| connect_notify_socket(&path)) {
+            Some(Ok(socket)) => Some(socket),
+            Some(Err(err)) => {
+                eprintln!("Connecting to the systemd notification socket failed: {}", err);
+                None
+            }
+            None => None,
+        };
+
+        #[cfg(feature = "landlock")]
+        self.restrict_filesystem();
+
+        match self.chroot_destdir() {
+            Ok(_) => {},
+            Err(err) => {
+                eprintln!("Changing root directory failed ({}).", err);
+                return false;
+            }
+        }
+        match self.drop_privs(self.conf.uid, self.conf.gid) {
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Dropping privileges failed: {}", err);
+                return false;
+            }
+        };
+
+        match env::set_current_dir(&self.conf.dir) {
+            Ok(_) => (),
+            Err(err) => {
+                eprintln!("Changing directory failed ({}).", err);
+                return false;
+            }
+        }
+
+        /* with the user and root that transfers run with */
+        if !self.conf.ro {
+            if let Err(err) = check_writable(self.root(Direction::Write)) {
+                let dir = self.root(Direction::Write).display();
+                if self.conf.require_writable {
+                    eprintln!("Directory {} for uploads is not writable: {}", dir, err);
+                    return false;
+                }
+                eprintln!("Warning: directory {} for uploads is not writable ({}), uploads will fail.", dir, err);
+            }
+        }
+
+        if let Err(err) = install_signal_handlers() {
+            eprintln!("Installing signal handlers failed: {}", err);
+            return false;
+        }
+
+        /* printed also with --quiet, as the confirmation that startup succeeded */
+        println!("{}", listening);
+        if let Some(notify) = notify {
+            if let Err(err) = notify.send(format!("READY=1\nSTATUS={}", listening).as_bytes()) {
+                eprintln!("Notifying systemd failed: {}", err);
+            }
+        }
+
+        self.serve(socket)
+    }
+
+    /// Forks a child for the request of `src`, unless as many children as
+    /// worker threads are running already.
+    fn start_child(&self, children: &mut Children, socket: &UdpSocket, recent: &RecentRequests, src: SocketAddr,
+                   local: Option<SocketAddr>, request: Vec<u8>) {
+        if children.reap(recent, &self.canceller) >= self.conf.threads {
+            recent.finished(&src, &request);
+            println!("Dropped request from {} (too many transfers)", Peer(src));
+            if let Err(err) = self.tftp.send_error_to(socket, src, 0, "Server busy") {
+                println!("Sending error to {} failed: {}", Peer(src), err);
+            }
+            return;
+        }
+        /* checked here, as the children don't share the state of the limiter */
+        if let Err(err) = self.check_rate_limit(&src) {
+            recent.finished(&src, &request);
+            println!("{}", err);
+            return;
+        }
+        let token = self.canceller.register(src);
+        match self.fork_transfer(socket, src, local, &request) {
+            Ok(pid) => children.insert(pid, src, request, token),
+            Err(err) => {
+                println!("Starting a process for the request from {} failed: {}", Peer(src), err);
+                recent.finished(&src, &request);
+                self.canceller.unregister(&src, &token);
+            }
+        }
+    }
+
+    /// Serves requests arriving on `socket` until a shutdown is requested,
+    /// or with `--serve-once` until a transfer succeeded.
+    ///
+    /// Unlike [`start`](Tftpd::start), this neither restricts file system
+    /// access nor drops privileges; it is meant for embedding the server
+    /// into a process that already took care of that.
+    ///
+    /// With `--fork-per-transfer`, the calling process must not run other
+    /// threads, as only async-signal-safe functions could be used in the
+    /// children then (see fork(2)); the server itself starts none in that case.
+    ///
+    /// Returns false if receiving from `socket` failed, or with `--serve-once`
+    /// if the server stopped before a transfer succeeded.
+    pub fn serve(&mut self, socket: UdpSocket) -> bool {
+        if let Err(err) = self.open_access_log() {
+            eprintln!("Opening the access log failed: {}", err);
+            return false;
+        }
+        if let Err(err) = self.load_manifest() {
+            eprintln!("Loading the manifest failed: {}", err);
+            return false;
+        }
+
+        /* wake up regularly even when idle, so a shutdown request is noticed */
+        if let Err(err) = socket.set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL)) {
+            eprintln!("Setting socket timeout failed: {}", err);
+            return false;
+        }
+
+        let socket = Arc::new(socket);
+        /* peers of the transfers running on the listening socket (--single-port) */
+        let transfers: Arc<Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>> = Default::default();
+        let recent: Arc<RecentRequests> = Default::default();
+        let mut children = Children::default();
+
+        /* no threads are started that could hold locks while forking */
+        let pool = (!self.conf.fork_per_transfer).then(|| ThreadPool::new(self.conf.threads));
+        /* requests waiting for a worker (--max-queue) */
+        let queued = Arc::new(AtomicUsize::new(0));
+        /* last request, or time a transfer was seen running (--idle-exit) */
+        let mut last_activity = Instant::now();
+        /* whether the transfers succeeded, and if one is running (--serve-once) */
+        let (outcomes, outcome) = mpsc::channel();
+        let mut transferring = false;
+        let mut succeeded = !self.conf.serve_once;
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        while !SHUTDOWN.load(Ordering::SeqCst) {
+            if RELOAD.swap(false, Ordering::SeqCst) {
+                self.reload();
+            }
+            if take_outcomes(&outcome, &mut transferring) {
+                println!("Transfer completed, exiting.");
+                succeeded = true;
+                break;
+            }
+            if self.conf.fork_per_transfer {
+                children.reap(&recent, &self.canceller);
+            }
+            if let Some(idle_exit) = self.conf.idle_exit {
+                if self.canceller.active() > 0 {
+                    last_activity = Instant::now();
+                } else if last_activity.elapsed() >= idle_exit {
+                    println!("No requests for {} seconds, exiting.", idle_exit.as_secs());
+                    break;
+                }
+            }
+
+            let (n, src, local) = match recv_request(&socket, &mut buf) {
+                Ok(args) => args,
+                Err(ref err) if [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut].contains(&err.kind()) => continue,
+                Err(ref err) if [io::ErrorKind::Interrupted, io::ErrorKind::ConnectionRefused].contains(&err.kind()) => {
+                    /* interrupted by a signal, or an ICMP error for an earlier
+                       datagram was reported; neither affects the listener */
+                    if self.conf.verbose {
+                        println!("Ignoring transient socket error: {}", err);
+                    }
+                    continue;
+                }
+                Err(err) => {
+                    eprintln!("Receiving data from socket failed: {}", err);
+                    succeeded = false;
+                    break;
+                }
+            };
+
+            let mut request = buf[0..n].to_vec();
+            last_activity = Instant::now();
+            /* reported after the client got the last packet, maybe only just now */
+            if take_outcomes(&outcome, &mut transferring) {
+                println!("Transfer completed, exiting.");
+                succeeded = true;
+                break;
+            }
+
+            if self.conf.single_port {
+                if let Some(transfer) = transfers.lock().unwrap().get(&src) {
+                    match transfer.send(request) {
+                        /* belongs to a running transfer */
+                        Ok(()) => continue,
+                        Err(mpsc::SendError(returned)) => request = returned,
+                    }
+                }
+            }
+
+            if self.conf.drop_invalid && !is_request(&request) {
+                /* not answered, so that spoofed datagrams are not reflected */
+                if self.conf.verbose {
+                    println!("Dropped invalid request from {}", Peer(src));
+                }
+                continue;
+            }
+
+            if recent.is_duplicate(&src, &request, self.conf.duplicate_window, Instant::now()) {
+                if self.conf.verbose {
+                    println!("Ignoring retransmitted request from {}", Peer(src));
+                }
+                continue;
+            }
+
+            if self.draining.load(Ordering::SeqCst) {
+                recent.finished(&src, &request);
+                if self.conf.verbose {
+                    println!("Refused request from {} (draining)", Peer(src));
+                }
+                if let Err(err) = self.tftp.send_error_to(&socket, src, 0, "Server busy") {
+                    println!("Sending error to {} failed: {}", Peer(src), err);
+                }
+                continue;
+            }
+            if self.conf.max_queue.is_some_and(|max| queued.load(Ordering::SeqCst) >= max) {
+                recent.finished(&src, &request);
+                println!("Dropped request from {} (queue full)", Peer(src));
+                if let Err(err) = self.tftp.send_error_to(&socket, src, 0, "Server busy") {
+                    println!("Sending error to {} failed: {}", Peer(src), err);
+                }
+                continue;
+            }
+            if self.conf.serve_once && transferring {
+                recent.finished(&src, &request);
+                println!("Refused request from {} (already serving one)", Peer(src));
+                if let Err(err) = self.tftp.send_error_to(&socket, src, 0, "Server busy") {
+                    println!("Sending error to {} failed: {}", Peer(src), err);
+                }
+                continue;
+            }
+            if self.conf.fork_per_transfer {
+                self.start_child(&mut children, &socket, &recent, src, local, request);
+                continue;
+            }
+            let pool = pool.as_ref().expect("workers are started without --fork-per-transfer");
+            /* only told about with --serve-once, so that outcomes don't pile up */
+            let report = self.conf.serve_once.then(|| outcomes.clone());
+            transferring = self.conf.serve_once;
+            queued.fetch_add(1, Ordering::SeqCst);
+            let started = Arc::clone(&queued);
+            let mut worker = self.worker();
+            worker.local = local;
+            let recent = Arc::clone(&recent);
+            let quiet = self.conf.quiet;
+            let canceller = self.canceller.clone();
+            let token = canceller.register(src);
+            worker.tftp.set_cancel_token(Some(Arc::clone(&token)));
+
+            if !self.conf.single_port {
+                let socket = Arc::clone(&socket);
+                pool.execute(move || {
+                    started.fetch_sub(1, Ordering::SeqCst);
+                    let ok = run_handler(&socket, src, quiet, || worker.handle_client(&src, &request));
+                    recent.finished(&src, &request);
+                    canceller.unregister(&src, &token);
+                    if let Some(report) = report {
+                        let _ = report.send(ok);
+                    }
+                });
+                continue;
+            }
+
+            let (sender, receiver) = mpsc::channel();
+            transfers.lock().unwrap().insert(src, sender);
+            let transport = SharedSocket {
+                socket: Arc::clone(&socket),
+                peer: src,
+                incoming: receiver,
+                timeout: Cell::new(Some(self.request_timeout())),
+            };
+            let transfers = Arc::clone(&transfers);
+            pool.execute(move || {
+                started.fetch_sub(1, Ordering::SeqCst);
+                let socket = Arc::clone(&transport.socket);
+                let ok = run_handler(&socket, src, quiet, || {
+                    worker.check_rate_limit(&src).and_then(|_| worker.handle_request(&transport, &src, &request))
+                });
+                /* datagrams arriving from now on start a new transfer */
+                transfers.lock().unwrap().remove(&src);
+                recent.finished(&src, &request);
+                canceller.unregister(&src, &token);
+                if let Some(report) = report {
+                    let _ = report.send(ok);
+                }
+            });
+        }
+
+        let timeout = match self.conf.shutdown_timeout {
+            Some(timeout) => timeout,
+            None => {
+                println!("Shutting down, waiting for active transfers to finish.");
+                if let Some(pool) = &pool {
+                    pool.join();
+                }
+                children.wait(None, &recent, &self.canceller);
+                return succeeded;
+            }
+        };
+        println!("Shutting down, waiting up to {} seconds for active transfers to finish.", timeout.as_secs());
+        if self.conf.fork_per_transfer {
+            for cl in children.wait(Some(timeout), &recent, &self.canceller) {
+                println!("Abandoned transfer with {} (shutdown timeout)", Peer(cl));
+            }
+            return succeeded;
+        }
+        /* the workers are left behind; cancelled transfers end with their next block */
+        for cl in drain(&self.canceller, timeout) {
+            println!("Abandoned transfer with {} (shutdown timeout)", Peer(cl));
+        }
+        succeeded
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rtftp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("creating test directory failed");
+        dir
+    }
+
+    fn spawn_server(dir: &Path) -> SocketAddr {
+        spawn_configured(ConfigurationBuilder::new().dir(dir).build().unwrap())
+    }
+
+    fn spawn_configured(conf: Configuration) -> SocketAddr {
+        let socket = UdpSocket::bind("[::1]:0").expect("binding failed");
+        let addr = socket.local_addr().unwrap();
+        let mut tftpd = Tftpd::new(conf);
+        thread::spawn(move || tftpd.serve(socket));
+        addr
+    }
+
+    /// Client speaking raw packets, for checking the exchange with the server.
+    struct RawClient {
+        socket: UdpSocket,
+        server: SocketAddr,
+    }
+
+    impl RawClient {
+        /// Sends a RRQ or WRQ for `filename` in octet mode to the listening socket.
+        fn request(server: SocketAddr, opcode: crate::Opcode, filename: &str, options: &[(&str, &str)]) -> RawClient {
+            RawClient::request_mode(server, opcode, filename, "octet", options)
+        }
+
+        fn request_mode(server: SocketAddr, opcode: crate::Opcode, filename: &str, mode: &str, options: &[(&str, &str)]) -> RawClient {
+            RawClient::request_bytes(server, opcode, filename.as_bytes(), mode, options)
+        }
+
+        /// Like [`request_mode`](RawClient::request_mode) for a filename that may not be UTF-8.
+        fn request_bytes(server: SocketAddr, opcode: crate::Opcode, filename: &[u8], mode: &str, options: &[(&str, &str)]) -> RawClient {
+            let local = if server.is_ipv4() { "127.0.0.1:0" } else { "[::1]:0" };
+            let socket = UdpSocket::bind(local).unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut buf = (opcode as u16).to_be_bytes().to_vec();
+            buf.extend(filename);
+            buf.push(0);
+            for field in [mode].into_iter().chain(options.iter().flat_map(|(k, v)| [*k, *v])) {
+                buf.extend(field.as_bytes());
+                buf.push(0);
+            }
+            socket.send_to(&buf, server).unwrap();
+            RawClient { socket, server }
+        }
+
+        /// Receives the next packet; the first reply determines the port of the transfer.
+        fn recv(&mut self) -> Vec<u8> {
+            let mut buf = [0; MAX_PACKET_SIZE];
+            let (len, src) = self.socket.recv_from(&mut buf).unwrap();
+            self.server = src;
+            buf[..len].to_vec()
+        }
+
+        fn send(&self, opcode: crate::Opcode, block_nr: u16, payload: &[u8]) {
+            let mut buf = (opcode as u16).to_be_bytes().to_vec();
+            buf.extend(block_nr.to_be_bytes());
+            buf.extend(payload);
+            self.socket.send_to(&buf, self.server).unwrap();
+        }
+
+        fn expect(&mut self, opcode: crate::Opcode, block_nr: u16) -> Vec<u8> {
+            let packet = self.recv();
+            assert_eq!(&packet[..4], [(opcode as u16).to_be_bytes(), block_nr.to_be_bytes()].concat(),
+                       "unexpected packet {:?}", packet);
+            packet[4..].to_vec()
+        }
+
+        /// Receives an OACK, returning the acknowledged options.
+        fn expect_oack(&mut self) -> HashMap<String, String> {
+            let packet = self.recv();
+            assert_eq!(&packet[..2], (crate::Opcode::OACK as u16).to_be_bytes(), "unexpected packet {:?}", packet);
+            let fields: Vec<String> = packet[2..].split(|&b| b == 0).map(|f| String::from_utf8_lossy(f).to_string()).collect();
+            /* terminating NUL leaves an empty field at the end */
+            fields[..fields.len() - 1].chunks(2).map(|kv| (kv[0].clone(), kv[1].clone())).collect()
+        }
+
+        /// Like [`expect`](RawClient::expect) for an ERROR packet, returning its message.
+        fn expect_error(&mut self, code: u16) -> String {
+            let msg = self.expect(crate::Opcode::ERROR, code);
+            String::from_utf8_lossy(&msg).trim_end_matches('\0').to_string()
+        }
+    }
+
+    #[test]
+    fn test_file_allowed() {
+        let conf: Configuration = Default::default();
+        let tftpd = Tftpd::new(conf);
+
+        /* allowed */
+        assert!(tftpd.file_allowed(Path::new("testfile"), Direction::Read).is_some());
+
+        /* forbidden */
+        assert!(tftpd.file_allowed(Path::new("nonexisting_dir/testfile"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("/nonexisting_dir/testfile"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("../testfile"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("testfile/../"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("testfile/../testfile"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("/root/testfile"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("/testfile"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("/dev/null"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("../../../../../../../../../../../../../etc/motd"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new(""), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("./"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(&tftpd.conf.dir, Direction::Read).is_none());
+        /* absolute paths are refused, even inside the served directory */
+        assert!(tftpd.file_allowed(&tftpd.conf.dir.join(Path::new("testfile")), Direction::Read).is_none());
+    }
+
+    #[test]
+    fn test_file_allowed_traversal() {
+        let dir = test_dir("traversal");
+        let outside = test_dir("traversal-outside");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("testfile"), b"test").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("escape")).unwrap();
+        std::os::unix::fs::symlink(dir.join("sub"), dir.join("inside")).unwrap();
+        let tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).build().unwrap());
+
+        for (filename, expected) in [
+            ("testfile", "testfile"),
+            ("./testfile", "testfile"),
+            ("sub/upload", "sub/upload"),
+            ("sub//upload", "sub/upload"),
+            ("sub/../testfile", "testfile"),
+            ("inside/upload", "sub/upload"),
+        ] {
+            assert_eq!(tftpd.file_allowed(Path::new(filename), Direction::Read), Some(PathBuf::from(expected)), "{}", filename);
+        }
+
+        let outside_file = outside.join("secret");
+        let outside_relative = Path::new("..").join(outside.file_name().unwrap()).join("secret");
+        for filename in [
+            "..",
+            "../",
+            "../etc/passwd",
+            "foo/../../bar",
+            "sub/../../testfile",
+            "sub/..",
+            "./..",
+            "../../../../../../../../../../../../../etc/passwd",
+            "/etc/passwd",
+            "//etc/passwd",
+            outside_file.to_str().unwrap(),
+            "escape/secret",
+            "escape/../testfile",
+            "sub/../escape/secret",
+            outside_relative.to_str().unwrap(),
+        ] {
+            assert_eq!(tftpd.file_allowed(Path::new(filename), Direction::Read), None, "{}", filename);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_included_path() {
+        let dir = test_dir("include");
+        let outside = test_dir("include-outside");
+        fs::create_dir(outside.join("images")).unwrap();
+        fs::write(outside.join("images/shared.img"), b"image").unwrap();
+        fs::write(outside.join("single.bin"), b"single").unwrap();
+        fs::write(outside.join("secret"), b"secret").unwrap();
+
+        let conf = ConfigurationBuilder::new().dir(&dir)
+                                              .include(outside.join("images"))
+                                              .include(outside.join("single.bin"))
+                                              .build().unwrap();
+        let tftpd = Tftpd::new(conf);
+        let outside = outside.canonicalize().unwrap();
+
+        assert_eq!(tftpd.included_path(&outside.join("images/shared.img")), Some(outside.join("images/shared.img")));
+        assert_eq!(tftpd.included_path(&outside.join("single.bin")), Some(outside.join("single.bin")));
+        assert_eq!(tftpd.included_path(Path::new("../rtftp-include-outside-x")), None);
+        let relative = Path::new("..").join(outside.file_name().unwrap()).join("single.bin");
+        assert_eq!(tftpd.included_path(&relative), Some(outside.join("single.bin")));
+        assert_eq!(tftpd.included_path(&outside.join("secret")), None);
+        assert_eq!(tftpd.included_path(&outside.join("images/../secret")), None);
+        assert_eq!(tftpd.included_path(&outside.join("images/missing")), None);
+
+        let server = spawn_configured(tftpd.conf.as_ref().clone());
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, &outside.join("images/shared.img").to_string_lossy(), &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, b"image");
+        /* never for writing */
+        assert_eq!(crate::Tftp::new().put(server, &outside.join("images/new").to_string_lossy(), &b"data"[..], &HashMap::new())
+                       .unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        assert!(matches!(ConfigurationBuilder::new().include("relative").build(), Err(ConfigError::InvalidInclude(..))));
+        assert!(matches!(ConfigurationBuilder::new().include("/nonexisting_dir").build(), Err(ConfigError::InvalidInclude(..))));
+        assert!(matches!(ConfigurationBuilder::new().include(&outside).chroot(true).build(), Err(ConfigError::ConflictingInclude)));
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_filename_valid() {
+        let tftpd = Tftpd::new(Default::default());
+        assert!(tftpd.filename_valid(Path::new("pxe/boot.bin")));
+        assert!(!tftpd.filename_valid(Path::new("boot\\pxe.bin")));
+        assert!(tftpd.filename_valid(Path::new("caf\u{e9}.bin")));
+        assert!(!tftpd.filename_valid(Path::new("/boot.bin")));
+        assert!(!tftpd.filename_valid(Path::new("boot\n.bin")));
+        assert!(!tftpd.filename_valid(Path::new("boot\r.bin")));
+        assert!(!tftpd.filename_valid(Path::new("boot\x1b[2J.bin")));
+        assert!(!tftpd.filename_valid(Path::new("boot\x7f.bin")));
+        assert!(!tftpd.filename_valid(Path::new(OsStr::from_bytes(b"boot\0.bin"))));
+        /* left to the path checks of file_allowed */
+        assert!(tftpd.filename_valid(Path::new("../boot.bin")));
+        assert!(tftpd.file_allowed(Path::new("../boot.bin"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("/boot.bin"), Direction::Read).is_none());
+        assert!(tftpd.file_allowed(Path::new("boot\t.bin"), Direction::Read).is_none());
+
+        let tftpd = Tftpd::new(ConfigurationBuilder::new().no_backslashes(false).build().unwrap());
+        assert!(tftpd.filename_valid(Path::new("boot\\pxe.bin")));
+    }
+
+    #[test]
+    fn test_file_name_filters() {
+        let conf = ConfigurationBuilder::new().allow_ext("bin").allow_ext(".CFG").deny_ext("cfg").no_dotfiles(true)
+                                              .build().unwrap();
+        let tftpd = Tftpd::new(conf);
+
+        assert!(tftpd.name_allowed(Path::new("firmware.bin")));
+        assert!(tftpd.name_allowed(Path::new("pxe/FIRMWARE.BIN")));
+        assert!(tftpd.name_allowed(Path::new("./firmware.bin")));
+        assert!(!tftpd.name_allowed(Path::new("firmware.bin.txt")));
+        assert!(!tftpd.name_allowed(Path::new("firmware")));
+        assert!(!tftpd.name_allowed(Path::new("bin")));
+        /* deny takes precedence */
+        assert!(!tftpd.name_allowed(Path::new("boot.cfg")));
+        assert!(!tftpd.name_allowed(Path::new(".hidden.bin")));
+        assert!(!tftpd.name_allowed(Path::new(".ssh/key.bin")));
+
+        let tftpd = Tftpd::new(ConfigurationBuilder::new().deny_ext("sh").build().unwrap());
+        assert!(tftpd.name_allowed(Path::new("firmware")));
+        assert!(tftpd.name_allowed(Path::new(".hidden")));
+        assert!(!tftpd.name_allowed(Path::new("script.SH")));
+    }
+
+    #[test]
+    fn test_directory_listing() {
+        let dir = test_dir("listing");
+        fs::create_dir_all(dir.join("sub/nested")).unwrap();
+        fs::create_dir_all(dir.join(".private")).unwrap();
+        for name in ["b.bin", "a.bin", ".hidden", "sub/c.cfg", ".private/d.bin"] {
+            fs::write(dir.join(name), b"content").unwrap();
+        }
+
+        let conf = ConfigurationBuilder::new().dir(&dir).enable_listing(true).no_dotfiles(true).build().unwrap();
+        let tftpd = Tftpd::new(conf);
+        assert_eq!(tftpd.directory_listing(Path::new(".")).unwrap(), b"a.bin\nb.bin\n");
+        assert_eq!(tftpd.directory_listing(Path::new("")).unwrap(), b"a.bin\nb.bin\n");
+        assert_eq!(tftpd.directory_listing(Path::new("sub/")).unwrap(), b"c.cfg\n");
+        assert_eq!(tftpd.directory_listing(Path::new("sub/nested")).unwrap(), b"");
+        assert!(tftpd.directory_listing(Path::new("a.bin")).is_none());
+        assert!(tftpd.directory_listing(Path::new(".private")).is_none());
+        assert!(tftpd.directory_listing(Path::new("..")).is_none());
+        assert!(tftpd.directory_listing(Path::new("/")).is_none());
+        assert!(tftpd.directory_listing(Path::new("missing")).is_none());
+
+        /* served over RRQ only when enabled */
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).enable_listing(true).build().unwrap());
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, ".", &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, b".hidden\na.bin\nb.bin\n");
+
+        let server = spawn_server(&dir);
+        assert!(crate::Tftp::new().get(server, ".", Vec::new(), &HashMap::new()).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index() {
+        let dir = test_dir("index");
+        let outside = test_dir("index-outside");
+        fs::create_dir_all(dir.join("served/pxe")).unwrap();
+        fs::create_dir_all(dir.join("served/empty")).unwrap();
+        fs::write(dir.join("served/pxe/default"), b"pxe").unwrap();
+        fs::write(dir.join("served/default"), b"top").unwrap();
+        fs::write(outside.join("default"), b"outside").unwrap();
+        std::os::unix::fs::symlink(&outside, dir.join("served/escape")).unwrap();
+
+        let get = |server, filename| {
+            let mut received = Vec::new();
+            crate::Tftp::new().get(server, filename, &mut received, &HashMap::new()).map(|_| received)
+        };
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(dir.join("served")).index("default").build().unwrap());
+        assert_eq!(get(server, "pxe").unwrap(), b"pxe");
+        assert_eq!(get(server, "pxe/").unwrap(), b"pxe");
+        assert_eq!(get(server, ".").unwrap(), b"top");
+        assert_eq!(get(server, "pxe/default").unwrap(), b"pxe");
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "empty", &[]);
+        client.expect(crate::Opcode::ERROR, 1);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "escape", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+
+        /* directories are refused as before without an index */
+        let server = spawn_server(&dir.join("served"));
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "pxe", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+
+        /* with listings, only directories without the index are listed */
+        let server = spawn_configured(ConfigurationBuilder::new().dir(dir.join("served")).index("default").enable_listing(true).build().unwrap());
+        assert_eq!(get(server, "pxe").unwrap(), b"pxe");
+        assert_eq!(get(server, "empty").unwrap(), b"");
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_effective_capabilities() {
+        let status = "Name:\trtftpd\nCapInh:\t0000000000000000\nCapEff:\t0000000000000400\nCapBnd:\t000001ffffffffff\n";
+        assert_eq!(effective_capabilities(status), Some(1 << CAP_NET_BIND_SERVICE));
+        assert_eq!(effective_capabilities("Name:\trtftpd\n"), None);
+        assert_eq!(effective_capabilities("CapEff:\tinvalid\n"), None);
+    }
+
+    #[test]
+    fn test_running_as() {
+        let (user, group) = (Uid::from_raw(1000), Gid::from_raw(1000));
+        let (nobody, nogroup) = (Uid::from_raw(65534), Gid::from_raw(65534));
+
+        /* already unprivileged as the requested user */
+        assert!(running_as(user, group, [user, user], [group, group]));
+        /* unprivileged, but as a different user than requested */
+        assert!(!running_as(nobody, nogroup, [user, user], [group, group]));
+        /* only partially dropped */
+        assert!(!running_as(nobody, nogroup, [nobody, nobody], [group, nogroup]));
+        assert!(!running_as(nobody, nogroup, [ROOT, nobody], [nogroup, nogroup]));
+    }
+
+    #[test]
+    fn test_bind_v6only() {
+        let addr = "[::]:0".parse().unwrap();
+        for v6only in [false, true] {
+            let socket = bind_socket(addr, false, v6only).unwrap();
+            assert_eq!(getsockopt(socket.as_raw_fd(), sockopt::Ipv6V6Only).unwrap(), v6only);
+            /* not inherited by --on-upload commands */
+            assert_ne!(unsafe { libc::fcntl(socket.as_raw_fd(), libc::F_GETFD) } & libc::FD_CLOEXEC, 0);
+        }
+
+        /* IPv4 clients reach dual-stack sockets */
+        let socket = bind_socket(addr, false, false).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"test", ("127.0.0.1", socket.local_addr().unwrap().port())).unwrap();
+        let mut buf = [0; 4];
+        let (len, src) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"test");
+        assert_eq!(src.ip(), "::ffff:127.0.0.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_client_subdir() {
+        let name = |ip: &str| client_subdir(ip.parse().unwrap());
+        assert_eq!(name("192.0.2.7"), "192.0.2.7");
+        assert_eq!(name("::ffff:192.0.2.7"), "192.0.2.7");
+        assert_eq!(name("2001:db8::1"), "2001-db8--1");
+        assert_eq!(name("::1"), "--1");
+    }
+
+    #[test]
+    fn test_set_dscp() {
+        let get = |socket: &UdpSocket, level, name| {
+            let mut val: libc::c_int = 0;
+            let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+            let ret = unsafe {
+                libc::getsockopt(socket.as_raw_fd(), level, name, &mut val as *mut libc::c_int as *mut libc::c_void, &mut len)
+            };
+            assert_eq!(ret, 0);
+            val
+        };
+
+        let socket = UdpSocket::bind("[::]:0").unwrap();
+        set_dscp(&socket, &"[::1]:69".parse().unwrap(), 46).unwrap();
+        assert_eq!(get(&socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS), 46 << 2);
+
+        let socket = UdpSocket::bind("[::]:0").unwrap();
+        set_dscp(&socket, &"[::ffff:127.0.0.1]:69".parse().unwrap(), 10).unwrap();
+        assert_eq!(get(&socket, libc::IPPROTO_IP, libc::IP_TOS), 10 << 2);
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = test_dir("reload");
+        let other = test_dir("reload-other");
+        let mut tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).port(6969).build().unwrap());
+
+        /* nothing to reload */
+        tftpd.reload();
+        assert_eq!(tftpd.conf.dir, dir);
+
+        let reloaded = other.clone();
+        tftpd.on_reload(move |dir| {
+            assert_eq!(dir, None);
+            ConfigurationBuilder::new().dir(&reloaded).port(7070).read_only(true).client_rate(5).build().ok()
+        });
+        let running = tftpd.worker();
+        tftpd.reload();
+        assert_eq!(tftpd.conf.dir, other);
+        assert!(tftpd.conf.ro);
+        assert!(tftpd.limiter.is_some());
+        /* the socket is already bound */
+        assert_eq!(tftpd.conf.port, 6969);
+        /* running transfers keep their configuration, new ones get the reloaded one */
+        assert_eq!(running.conf.dir, dir);
+        assert!(!running.conf.ro);
+        assert_eq!(tftpd.worker().conf.dir, other);
+
+        /* a failed reload keeps the configuration */
+        tftpd.on_reload(|_| None);
+        tftpd.reload();
+        assert_eq!(tftpd.conf.dir, other);
+
+        /* once confined, the served directory can't change anymore */
+        tftpd.confined = true;
+        let reloaded = dir.clone();
+        tftpd.on_reload(move |dir| dir.map(|dir| ConfigurationBuilder::new().dir(dir).include(&reloaded).build().unwrap()));
+        tftpd.reload();
+        assert_eq!(tftpd.conf.dir, other);
+        assert!(tftpd.conf.include.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&other).unwrap();
+    }
+
+    #[test]
+    fn test_format_options() {
+        let mut options = HashMap::new();
+        assert_eq!(format_options(&options), "none");
+        options.insert(String::from("tsize"), String::from("0"));
+        options.insert(String::from("blksize"), String::from("1468"));
+        assert_eq!(format_options(&options), "blksize=1468, tsize=0");
+    }
+
+    #[test]
+    fn test_open_error() {
+        let code = |errno| open_error(&io::Error::from_raw_os_error(errno)).0;
+        assert_eq!(code(libc::ENOENT), 1);
+        assert_eq!(code(libc::EACCES), 2);
+        assert_eq!(code(libc::EPERM), 2);
+        assert_eq!(code(libc::ENOSPC), 3);
+        assert_eq!(code(libc::EEXIST), 6);
+        assert_eq!(code(libc::EBUSY), 0);
+
+        let err = io::Error::from_raw_os_error(libc::ETXTBSY);
+        assert_eq!(open_error(&err), (0, err.to_string()));
+    }
+
+    #[test]
+    fn test_configuration_builder() {
+        let conf = ConfigurationBuilder::new().port(6969).threads(4).build().unwrap();
+        assert_eq!(conf.port(), 6969);
+        assert_eq!(conf.threads(), 4);
+        assert!(conf.dir().is_absolute());
+
+        assert!(matches!(ConfigurationBuilder::new().read_only(true).write_only(true).build(),
+                         Err(ConfigError::ConflictingAccess)));
+        assert!(matches!(ConfigurationBuilder::new().sparse(true).append(true).build(), Err(ConfigError::ConflictingSparse)));
+        assert!(matches!(ConfigurationBuilder::new().preallocate(true).append(true).build(), Err(ConfigError::ConflictingPreallocate)));
+        assert!(matches!(ConfigurationBuilder::new().serve_once(true).fork_per_transfer(true).build(),
+                         Err(ConfigError::ConflictingServeOnce)));
+        assert!(matches!(ConfigurationBuilder::new().mirror("[::1]:69".parse().unwrap()).append(true).build(),
+                         Err(ConfigError::ConflictingMirror)));
+        assert!(matches!(ConfigurationBuilder::new().fork_per_transfer(true).single_port(true).build(),
+                         Err(ConfigError::ConflictingForkPerTransfer)));
+        assert!(matches!(ConfigurationBuilder::new().fork_per_transfer(true).control_socket("/run/rtftpd.sock").build(),
+                         Err(ConfigError::ConflictingForkServices)));
+        assert!(matches!(ConfigurationBuilder::new().fork_per_transfer(true).total_rate(1000).build(),
+                         Err(ConfigError::ConflictingForkRate)));
+        assert!(matches!(ConfigurationBuilder::new().quiet(true).verbose(true).build(), Err(ConfigError::ConflictingVerbosity)));
+        assert!(matches!(ConfigurationBuilder::new().threads(0).build(), Err(ConfigError::NoThreads)));
+        assert!(matches!(ConfigurationBuilder::new().port(0).build(), Err(ConfigError::InvalidPort)));
+        assert!(matches!(ConfigurationBuilder::new().client_rate(0).build(), Err(ConfigError::InvalidClientRate)));
+        assert!(matches!(ConfigurationBuilder::new().transfer_timeout(Duration::ZERO).build(),
+                         Err(ConfigError::InvalidTransferTimeout)));
+        assert!(matches!(ConfigurationBuilder::new().handshake_timeout(Duration::ZERO).build(),
+                         Err(ConfigError::InvalidHandshakeTimeout)));
+        assert!(matches!(ConfigurationBuilder::new().idle_exit(Duration::ZERO).build(), Err(ConfigError::InvalidIdleExit)));
+        assert!(matches!(ConfigurationBuilder::new().backoff_multiplier(0).build(), Err(ConfigError::InvalidBackoffMultiplier)));
+        assert!(matches!(ConfigurationBuilder::new().max_backoff(Duration::ZERO).build(), Err(ConfigError::InvalidMaxBackoff)));
+        assert!(matches!(ConfigurationBuilder::new().total_rate(0).build(), Err(ConfigError::InvalidTotalRate)));
+        assert!(matches!(ConfigurationBuilder::new().max_queue(0).build(), Err(ConfigError::InvalidMaxQueue)));
+        assert!(matches!(ConfigurationBuilder::new().max_filename_len(0).build(), Err(ConfigError::InvalidMaxFilenameLen)));
+        assert!(matches!(ConfigurationBuilder::new().log_format("{op} {size}").build(), Err(ConfigError::InvalidLogFormat(_))));
+        for index in ["", ".", "..", "boot/default"] {
+            assert!(matches!(ConfigurationBuilder::new().index(index).build(), Err(ConfigError::InvalidIndex)));
+        }
+        assert!(matches!(ConfigurationBuilder::new().dscp(64).build(), Err(ConfigError::InvalidDscp)));
+        assert!(matches!(ConfigurationBuilder::new().force_blksize(7).build(), Err(ConfigError::InvalidForceBlksize)));
+        assert!(matches!(ConfigurationBuilder::new().map_all("/srv/tftp/boot.img").build(), Err(ConfigError::InvalidMapAll)));
+        assert!(matches!(ConfigurationBuilder::new().map_all("").build(), Err(ConfigError::InvalidMapAll)));
+        assert!(matches!(ConfigurationBuilder::new().read_dir("/nonexistent").build(), Err(ConfigError::InvalidDirectory(..))));
+        assert!(matches!(ConfigurationBuilder::new().write_dir("/").chroot(true).build(), Err(ConfigError::ConflictingRoots)));
+        assert_eq!(ConfigurationBuilder::new().dscp(46).build().unwrap().dscp(), Some(46));
+        assert!(matches!(ConfigurationBuilder::new().process_name("").build(), Err(ConfigError::InvalidProcessName)));
+        assert_eq!(ConfigurationBuilder::new().allow_gzip(true).build().is_ok(), cfg!(feature = "gzip"));
+        assert!(matches!(ConfigurationBuilder::new().process_name("tftp-vlan10-boot").build(), Err(ConfigError::InvalidProcessName)));
+        assert_eq!(ConfigurationBuilder::new().process_name("tftp-vlan10").build().unwrap().process_name(), Some("tftp-vlan10"));
+        assert!(matches!(ConfigurationBuilder::new().modes::<&str>(&[]).build(), Err(ConfigError::InvalidModes)));
+        assert!(matches!(ConfigurationBuilder::new().modes(&["octet", "mail"]).build(), Err(ConfigError::InvalidModes)));
+        assert!(matches!(ConfigurationBuilder::new().modes(&["octet"]).default_mode("netascii").build(), Err(ConfigError::InvalidDefaultMode)));
+        assert!(matches!(ConfigurationBuilder::new().default_mode("mail").build(), Err(ConfigError::InvalidDefaultMode)));
+        assert_eq!(ConfigurationBuilder::new().modes(&["OCTET"]).build().unwrap().modes(), ["octet"]);
+        assert!(matches!(ConfigurationBuilder::new().dir("/nonexisting_dir").build(),
+                         Err(ConfigError::InvalidDirectory(..))));
+        assert!(matches!(ConfigurationBuilder::new().dir("/dev/null").build(),
+                         Err(ConfigError::InvalidDirectory(..))));
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = test_dir("get");
+        let content: Vec<u8> = (0..3000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let server = spawn_server(&dir);
+
+        let mut options = HashMap::new();
+        options.insert(String::from("blksize"), String::from("1024"));
+        options.insert(String::from("tsize"), String::from("0"));
+
+        let mut received = Vec::new();
+        let size = crate::Tftp::new().get(server, "testfile", &mut received, &options).unwrap();
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(received, content);
+
+        /* without options the server answers directly with data */
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "testfile", &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, content);
+
+        assert_eq!(crate::Tftp::new().get(server, "missing", Vec::new(), &options).unwrap_err().kind(),
+                   io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_allow_offset() {
+        let dir = test_dir("allow-offset");
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let options = |offset: &str| HashMap::from([(String::from("offset"), offset.to_string()), (String::from("tsize"), String::from("0"))]);
+
+        /* not acknowledged by default */
+        let server = spawn_server(&dir);
+        assert_eq!(crate::Tftp::new().get(server, "testfile", Vec::new(), &options("1200")).unwrap_err().kind(),
+                   io::ErrorKind::Unsupported);
+
+        /* resume after an interrupted download of the first 1200 bytes */
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).allow_offset(true).build().unwrap());
+        let mut received = content[..1200].to_vec();
+        let mut tftp = crate::Tftp::new();
+        assert_eq!(tftp.get(server, "testfile", &mut received, &options("1200")).unwrap(), 1800);
+        assert_eq!(tftp.options.tsize, 1800);
+        assert_eq!(received, content);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[("offset", "3000")]);
+        assert_eq!(client.expect_oack()["offset"], "3000");
+        client.send(crate::Opcode::ACK, 0, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[("offset", "3001")]);
+        assert_eq!(client.expect_error(8), "Offset beyond end of file");
+
+        /* only for downloads */
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[("offset", "10"), ("blksize", "600")]);
+        assert!(!client.expect_oack().contains_key("offset"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_request_case_insensitive() {
+        let dir = test_dir("case");
+        fs::write(dir.join("testfile"), b"line\n").unwrap();
+        let server = spawn_server(&dir);
+
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(b"\x00\x01testfile\x00NetASCII\x00BlkSize\x001024\x00TSIZE\x000\x00", server).unwrap();
+
+        let mut buf = [0; 1100];
+        let (len, peer) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..2], b"\x00\x06");
+        let options = crate::Tftp::new().parse_options(&buf[2..len]);
+        assert_eq!(options["blksize"], "1024");
+        /* the size after netascii conversion */
+        assert_eq!(options["tsize"], "6");
+
+        client.send_to(b"\x00\x04\x00\x00", peer).unwrap();
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x03\x00\x01line\r\n");
+        client.send_to(b"\x00\x04\x00\x01", peer).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_served_dir_missing() {
+        let dir = test_dir("missing");
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        socket.connect(client.local_addr().unwrap()).unwrap();
+        let tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).build().unwrap());
+        assert!(tftpd.check_served_dir(&socket, Direction::Read).is_ok());
+
+        fs::remove_dir(&dir).unwrap();
+        let err = tftpd.check_served_dir(&socket, Direction::Read).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().starts_with(&format!("Served directory {} is not available", tftpd.conf.dir.display())));
+        assert!(tftpd.dir_missing.load(Ordering::SeqCst));
+
+        /* recovers once it is back */
+        fs::create_dir(&dir).unwrap();
+        assert!(tftpd.check_served_dir(&socket, Direction::Read).is_ok());
+        assert!(!tftpd.dir_missing.load(Ordering::SeqCst));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = test_dir("manifest");
+        let out = test_dir("manifest-out");
+        fs::write(dir.join("boot.bin"), b"data").unwrap();
+        fs::write(dir.join("tampered.bin"), b"evil").unwrap();
+        fs::write(dir.join("unlisted.bin"), b"data").unwrap();
+        let manifest = out.join("SHA256SUMS");
+        let checksum = "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7";
+        fs::write(&manifest, format!("{}  boot.bin\n{}  tampered.bin\n", checksum, checksum)).unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).manifest(&manifest).build().unwrap());
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "boot.bin", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        for name in ["tampered.bin", "unlisted.bin"] {
+            let mut client = RawClient::request(server, crate::Opcode::RRQ, name, &[]);
+            assert_eq!(client.expect_error(2), "Access violation");
+        }
+
+        /* uploads are not affected */
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        client.expect(crate::Opcode::ACK, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn test_access_log() {
+        let dir = test_dir("access-log");
+        let log = test_dir("access-log-out").join("access.log");
+        fs::write(dir.join("testfile"), b"0123456789").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).access_log(&log).build().unwrap());
+
+        crate::Tftp::new().get(server, "testfile", Vec::new(), &HashMap::new()).unwrap();
+        assert!(crate::Tftp::new().get(server, "missing", Vec::new(), &HashMap::new()).is_err());
+
+        /* records are written after the client saw the end of the transfer */
+        let mut lines = Vec::new();
+        for _ in 0..50 {
+            lines = fs::read_to_string(&log).unwrap_or_default().lines().map(String::from).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(lines.len(), 2);
+        lines.sort_by_key(|line| line.contains("\"missing\""));
+        assert!(lines[0].contains(",\"client\":\"::1\",\"port\":"));
+        assert!(lines[0].contains(",\"direction\":\"read\",\"filename\":\"testfile\",\"bytes\":10,"));
+        assert!(lines[0].ends_with(",\"result\":\"ok\",\"error_code\":null,\"error\":null}"));
+        assert!(lines[1].contains(",\"bytes\":0,"));
+        assert!(lines[1].contains(",\"result\":\"error\",\"error_code\":1,\"error\":\"Sending missing to "));
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(log.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_raw_download() {
+        let dir = test_dir("raw-download");
+        let content: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), &content[..512]);
+        client.send(crate::Opcode::ACK, 1, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 2), &content[512..]);
+        client.send(crate::Opcode::ACK, 2, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_upload() {
+        let dir = test_dir("raw-upload");
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        client.expect(crate::Opcode::ACK, 0);
+        client.send(crate::Opcode::DATA, 1, &[b'x'; 512]);
+        client.expect(crate::Opcode::ACK, 1);
+        client.send(crate::Opcode::DATA, 2, b"end");
+        client.expect(crate::Opcode::ACK, 2);
+
+        /* the last block is written before it is acknowledged */
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), [&[b'x'; 512][..], b"end"].concat());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_empty_file() {
+        let dir = test_dir("raw-empty-file");
+        fs::write(dir.join("empty"), b"").unwrap();
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "empty", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "empty", &[("tsize", "0")]);
+        assert_eq!(client.expect_oack()["tsize"], "0");
+        client.send(crate::Opcode::ACK, 0, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[("tsize", "0")]);
+        assert_eq!(client.expect_oack()["tsize"], "0");
+        client.send(crate::Opcode::DATA, 1, &[]);
+        client.expect(crate::Opcode::ACK, 1);
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), b"");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_negotiated_download() {
+        let dir = test_dir("raw-negotiated-download");
+        let content: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[("blksize", "600"), ("timeout", "1")]);
+        let oack = client.expect_oack();
+        assert_eq!(oack.len(), 2);
+        assert_eq!(oack["blksize"], "600");
+
+        /* block 1 only follows the ACK of the OACK, which is retransmitted until then */
+        client.expect_oack();
+        client.send(crate::Opcode::ACK, 0, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), &content[..600]);
+        client.send(crate::Opcode::ACK, 1, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 2), &content[600..]);
+        client.send(crate::Opcode::ACK, 2, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_many_options() {
+        let dir = test_dir("many-options");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let server = spawn_server(&dir);
+
+        let names: Vec<String> = (0..5000).map(|i| format!("x-{}", i)).collect();
+        let mut options = vec![("blksize", "600")];
+        options.extend(names.iter().map(|name| (name.as_str(), "1")));
+        options.push(("tsize", "0"));
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &options);
+        /* only the first options are looked at */
+        assert_eq!(client.expect_oack(), HashMap::from([(String::from("blksize"), String::from("600"))]));
+        client.send(crate::Opcode::ACK, 0, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_allow_gzip() {
+        use flate2::read::GzDecoder;
+
+        let dir = test_dir("allow-gzip");
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 7) as u8).collect();
+        fs::write(dir.join("image.bin"), &content).unwrap();
+        fs::write(dir.join("kernel.gz"), b"already compressed").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).allow_gzip(true).build().unwrap());
+
+        let download = |filename: &str, options: &[(&str, &str)]| {
+            let mut client = RawClient::request(server, crate::Opcode::RRQ, filename, options);
+            let mut data = Vec::new();
+            for block in 1.. {
+                let packet = client.expect(crate::Opcode::DATA, block);
+                client.send(crate::Opcode::ACK, block, &[]);
+                data.extend(&packet);
+                if packet.len() < 512 {
+                    break;
+                }
+            }
+            data
+        };
+
+        /* no OACK without tsize, the first block follows directly */
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&download("image.bin.gz", &[("tsize", "0")])[..]).read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, content);
+        assert_eq!(download("image.bin", &[]), content);
+        assert_eq!(download("kernel.gz", &[]), b"already compressed");
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "missing.gz", &[]);
+        client.expect_error(1);
+        let mut client = RawClient::request_mode(server, crate::Opcode::RRQ, "image.bin.gz", "netascii", &[]);
+        client.expect_error(1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_map_all() {
+        let dir = test_dir("map-all");
+        fs::create_dir(dir.join("images")).unwrap();
+        fs::write(dir.join("images/boot.img"), b"boot image").unwrap();
+        fs::write(dir.join("pxelinux.0"), b"other file").unwrap();
+        let conf = ConfigurationBuilder::new().dir(&dir).map_all("images/boot.img").enable_listing(true).build().unwrap();
+        let server = spawn_configured(conf);
+
+        for requested in ["pxelinux.0", "missing", "../etc/passwd", "."] {
+            let mut client = RawClient::request(server, crate::Opcode::RRQ, requested, &[("tsize", "0")]);
+            assert_eq!(client.expect_oack()["tsize"], "10");
+            client.send(crate::Opcode::ACK, 0, &[]);
+            assert_eq!(client.expect(crate::Opcode::DATA, 1), b"boot image");
+            client.send(crate::Opcode::ACK, 1, &[]);
+        }
+
+        /* uploads keep their names */
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        client.expect(crate::Opcode::ACK, 0);
+        client.send(crate::Opcode::DATA, 1, b"upload");
+        client.expect(crate::Opcode::ACK, 1);
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), b"upload");
+        assert_eq!(fs::read(dir.join("images/boot.img")).unwrap(), b"boot image");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_write_dirs() {
+        let dir = test_dir("read-write-dirs");
+        let (read, write) = (dir.join("read"), dir.join("write"));
+        fs::create_dir(&read).unwrap();
+        fs::create_dir(&write).unwrap();
+        fs::write(read.join("boot.img"), b"boot image").unwrap();
+        let conf = ConfigurationBuilder::new().dir(&dir).read_dir(&read).write_dir(&write).build().unwrap();
+        let server = spawn_configured(conf);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "boot.img", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"boot image");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        client.expect(crate::Opcode::ACK, 0);
+        client.send(crate::Opcode::DATA, 1, b"upload");
+        client.expect(crate::Opcode::ACK, 1);
+        assert_eq!(fs::read(write.join("upload")).unwrap(), b"upload");
+        assert!(!read.join("upload").exists() && !dir.join("upload").exists());
+
+        /* uploads can't be downloaded, and the read directory can't be written */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "upload", &[]);
+        client.expect(crate::Opcode::ERROR, 1);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "../write/upload", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "../read/boot.img", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+        assert_eq!(fs::read(read.join("boot.img")).unwrap(), b"boot image");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_per_client_subdir() {
+        let dir = test_dir("per-client-subdir");
+        let conf = ConfigurationBuilder::new().dir(&dir).per_client_subdir(true).build().unwrap();
+        /* dual-stack, for clients with different addresses */
+        let socket = bind_socket("[::]:0".parse().unwrap(), false, false).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let mut tftpd = Tftpd::new(conf);
+        thread::spawn(move || tftpd.serve(socket));
+
+        for (server, content) in [(SocketAddr::from(([127, 0, 0, 1], port)), b"ipv4"), (SocketAddr::from((Ipv6Addr::LOCALHOST, port)), b"ipv6")] {
+            let mut client = RawClient::request(server, crate::Opcode::WRQ, "device.log", &[]);
+            client.expect(crate::Opcode::ACK, 0);
+            client.send(crate::Opcode::DATA, 1, content);
+            client.expect(crate::Opcode::ACK, 1);
+        }
+        assert_eq!(fs::read(dir.join("127.0.0.1/device.log")).unwrap(), b"ipv4");
+        assert_eq!(fs::read(dir.join("--1/device.log")).unwrap(), b"ipv6");
+        assert!(!dir.join("device.log").exists());
+
+        /* the directories of other clients are out of reach */
+        let server = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "../127.0.0.1/other.log", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "/tmp/other.log", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reply_source() {
+        let dir = test_dir("reply-source");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let conf = ConfigurationBuilder::new().dir(&dir).build().unwrap();
+        let socket = bind_socket("[::]:0".parse().unwrap(), false, false).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let mut tftpd = Tftpd::new(conf);
+        thread::spawn(move || tftpd.serve(socket));
+
+        /* all of 127.0.0.0/8 is local, but routes prefer 127.0.0.1 as source */
+        for server in [SocketAddr::from(([127, 0, 0, 2], port)), SocketAddr::from((Ipv6Addr::LOCALHOST, port))] {
+            let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+            assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+            assert_eq!(client.server.ip(), server.ip());
+            assert_ne!(client.server.port(), port);
+            client.send(crate::Opcode::ACK, 1, &[]);
+        }
+
+        /* IPv4 sockets report the address as well */
+        let socket = bind_socket("0.0.0.0:0".parse().unwrap(), false, false).unwrap();
+        let server = SocketAddr::from(([127, 0, 0, 3], socket.local_addr().unwrap().port()));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"request", server).unwrap();
+        let mut buf = [0; 16];
+        let local = SocketAddr::from(([127, 0, 0, 3], 0));
+        assert_eq!(recv_request(&socket, &mut buf).unwrap(), (7, client.local_addr().unwrap(), Some(local)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_per_client_config() {
+        let dir = test_dir("per-client-config");
+        fs::write(dir.join("config.cfg"), b"default").unwrap();
+        for (subdir, content) in [("127.0.0.1", "ipv4"), ("--1", "ipv6")] {
+            fs::create_dir(dir.join(subdir)).unwrap();
+            fs::write(dir.join(subdir).join("config.cfg"), content).unwrap();
+        }
+        let conf = ConfigurationBuilder::new().dir(&dir).per_client_config(true).build().unwrap();
+        let socket = bind_socket("[::]:0".parse().unwrap(), false, false).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let mut tftpd = Tftpd::new(conf);
+        thread::spawn(move || tftpd.serve(socket));
+
+        let ipv4 = SocketAddr::from(([127, 0, 0, 1], port));
+        let ipv6 = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+        let get = |server, filename| {
+            let mut client = RawClient::request(server, crate::Opcode::RRQ, filename, &[]);
+            let data = client.expect(crate::Opcode::DATA, 1);
+            client.send(crate::Opcode::ACK, 1, &[]);
+            data
+        };
+        assert_eq!(get(ipv4, "config.cfg"), b"ipv4");
+        assert_eq!(get(ipv6, "config.cfg"), b"ipv6");
+
+        /* without an override of its own, a client gets the default */
+        fs::remove_file(dir.join("--1/config.cfg")).unwrap();
+        assert_eq!(get(ipv6, "config.cfg"), b"default");
+        assert_eq!(get(ipv6, "./config.cfg"), b"default");
+        let mut client = RawClient::request(ipv6, crate::Opcode::RRQ, "../127.0.0.1/config.cfg", &[]);
+        client.expect(crate::Opcode::ERROR, 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_negotiated_upload() {
+        let dir = test_dir("raw-negotiated-upload");
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[("blksize", "600"), ("timeout", "1")]);
+        assert_eq!(client.expect_oack()["blksize"], "600");
+
+        /* the OACK replaces the ACK of block 0, also when it is retransmitted */
+        assert_eq!(client.expect_oack()["blksize"], "600");
+        client.send(crate::Opcode::DATA, 1, &[b'x'; 600]);
+        client.expect(crate::Opcode::ACK, 1);
+        client.send(crate::Opcode::DATA, 2, b"end");
+        client.expect(crate::Opcode::ACK, 2);
+        assert_eq!(fs::read(dir.join("upload")).unwrap().len(), 603);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_large_blksize_upload() {
+        let dir = test_dir("large-blksize-upload");
+        let server = spawn_server(&dir);
+
+        /* blocks larger than the initial request buffer arrive in full */
+        let data: Vec<u8> = (0..8192 * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[("blksize", "8192")]);
+        assert_eq!(client.expect_oack()["blksize"], "8192");
+        for (i, block) in data.chunks(8192).enumerate() {
+            client.send(crate::Opcode::DATA, i as u16 + 1, block);
+            client.expect(crate::Opcode::ACK, i as u16 + 1);
+        }
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), data);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_modes() {
+        let dir = test_dir("modes");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).modes(&["octet"]).build().unwrap());
+
+        let mut client = RawClient::request_mode(server, crate::Opcode::RRQ, "testfile", "netascii", &[]);
+        assert_eq!(client.expect_error(0), "Unsupported mode");
+        let mut client = RawClient::request_mode(server, crate::Opcode::WRQ, "upload", "NetASCII", &[]);
+        assert_eq!(client.expect_error(0), "Unsupported mode");
+        assert!(!dir.join("upload").exists());
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_not_found() {
+        let dir = test_dir("raw-not-found");
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "missing", &[]);
+        assert_eq!(client.expect_error(1), "File not found");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_raw_permission_denied() {
+        let dir = test_dir("raw-permission-denied");
+        let server = spawn_server(&dir);
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "../etc/passwd", &[]);
+        assert_eq!(client.expect_error(2), "Permission denied");
+
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "../upload", &[]);
+        assert_eq!(client.expect_error(2), "Permission denied");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_mmap() {
+        let dir = test_dir("mmap");
+        let content: Vec<u8> = (0..10000u32).map(|i| (i % 199) as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        fs::write(dir.join("empty"), b"").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).mmap(true).build().unwrap());
+
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "testfile", &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, content);
+
+        /* falls back to reading */
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "empty", &mut received, &HashMap::new()).unwrap();
+        assert!(received.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_cached() {
+        let dir = test_dir("cache");
+        fs::write(dir.join("testfile"), b"first version").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).cache_size(1024).build().unwrap());
+
+        for _ in 0..2 {
+            let mut received = Vec::new();
+            crate::Tftp::new().get(server, "testfile", &mut received, &HashMap::new()).unwrap();
+            assert_eq!(received, b"first version");
+        }
+
+        /* a changed file is read again */
+        fs::write(dir.join("testfile"), b"second, longer version").unwrap();
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "testfile", &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, b"second, longer version");
+
+        /* files larger than the cache are still served */
+        let content = vec![7; 4000];
+        fs::write(dir.join("large"), &content).unwrap();
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "large", &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, content);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put() {
+        let dir = test_dir("put");
+        let content: Vec<u8> = (0..5000u32).map(|i| (i / 7) as u8).collect();
+        let server = spawn_server(&dir);
+
+        let mut options = HashMap::new();
+        options.insert(String::from("blksize"), String::from("1024"));
+        options.insert(String::from("tsize"), content.len().to_string());
+
+        let size = crate::Tftp::new().put(server, "upload", content.as_slice(), &options).unwrap();
+        assert_eq!(size, content.len() as u64);
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), content);
+
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "upload", &mut received, &options).unwrap();
+        assert_eq!(received, content);
+
+        /* without options the server acknowledges the request with block 0 */
+        crate::Tftp::new().put(server, "upload2", content.as_slice(), &HashMap::new()).unwrap();
+        assert_eq!(fs::read(dir.join("upload2")).unwrap(), content);
+
+        assert_eq!(crate::Tftp::new().put(server, "upload", content.as_slice(), &options).unwrap_err().kind(),
+                   io::ErrorKind::AlreadyExists);
+        assert_eq!(crate::Tftp::new().put(server, "../upload", content.as_slice(), &options).unwrap_err().kind(),
+                   io::ErrorKind::PermissionDenied);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_append() {
+        let dir = test_dir("put-append");
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).append(true).build().unwrap());
+
+        crate::Tftp::new().put(server, "log", &b"first\n"[..], &HashMap::new()).unwrap();
+        crate::Tftp::new().put(server, "log", &b"second\n"[..], &HashMap::new()).unwrap();
+        assert_eq!(fs::read(dir.join("log")).unwrap(), b"first\nsecond\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_sparse() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir("put-sparse");
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).sparse(true).build().unwrap());
+
+        /* data between zero runs, and an image ending in zeros */
+        let mut image = vec![0; 1024 * 1024];
+        image[100..200].fill(0xaa);
+        image[512 * 1024..512 * 1024 + 5000].fill(0x55);
+        let mut options = HashMap::new();
+        options.insert(String::from("blksize"), String::from("1428"));
+        for (name, data) in [("image", &image[..]), ("zeros", &image[..4096 * 3]), ("short", &image[..10])] {
+            crate::Tftp::new().put(server, name, data, &options).unwrap();
+            assert_eq!(fs::read(dir.join(name)).unwrap(), data, "{}", name);
+        }
+
+        /* only the blocks with data take up space */
+        let blocks = fs::metadata(dir.join("image")).unwrap().blocks() * 512;
+        assert!(blocks < 64 * 1024, "{} bytes allocated", blocks);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_fsync() {
+        let dir = test_dir("put-fsync");
+        for sparse in [false, true] {
+            let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).fsync(true).sparse(sparse).build().unwrap());
+            let name = format!("upload-{}", sparse);
+            let data = [&[0; 8192][..], b"data"].concat();
+            crate::Tftp::new().put(server, &name, &data[..], &HashMap::new()).unwrap();
+            assert_eq!(fs::read(dir.join(&name)).unwrap(), data);
+        }
+
+        /* a failing sync fails the upload before the last block is acknowledged */
+        let file = File::create(dir.join("synced")).unwrap();
+        let mut synced = SyncedFile { inner: Vec::new(), file, dir: dir.join("missing") };
+        synced.write_all(b"data").unwrap();
+        assert_eq!(synced.flush().unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_put_preallocate() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir("put-preallocate");
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).preallocate(true).build().unwrap());
+
+        /* allocated with the announced size until the last block arrived */
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "image", &[("tsize", "100000")]);
+        assert_eq!(client.expect_oack()["tsize"], "100000");
+        let metadata = fs::metadata(dir.join("image")).unwrap();
+        assert_eq!(metadata.len(), 100000);
+        assert!(metadata.blocks() * 512 >= 100000);
+        client.send(crate::Opcode::DATA, 1, &[1; 512]);
+        client.expect(crate::Opcode::ACK, 1);
+        client.send(crate::Opcode::DATA, 2, b"short");
+        client.expect(crate::Opcode::ACK, 2);
+        assert_eq!(fs::read(dir.join("image")).unwrap(), [&[1; 512][..], b"short"].concat());
+
+        /* a failed upload keeps only the data received */
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "failed", &[("tsize", "100000")]);
+        client.expect_oack();
+        client.send(crate::Opcode::DATA, 1, &[1; 512]);
+        client.expect(crate::Opcode::ACK, 1);
+        client.send(crate::Opcode::ERROR, 0, b"cancelled\0");
+        let start = Instant::now();
+        while fs::metadata(dir.join("failed")).unwrap().len() != 512 && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(fs::metadata(dir.join("failed")).unwrap().len(), 512);
+
+        /* without tsize, nothing is allocated in advance */
+        crate::Tftp::new().put(server, "plain", &b"data"[..], &HashMap::new()).unwrap();
+        assert_eq!(fs::read(dir.join("plain")).unwrap(), b"data");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_uploads() {
+        for append in [false, true] {
+            let dir = test_dir(&format!("concurrent-uploads-{}", append));
+            let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).append(append).build().unwrap());
+
+            /* both requests are answered before either transfer completes */
+            let mut first = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+            first.expect(crate::Opcode::ACK, 0);
+            let mut second = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+            let code = if append { 0 } else { 6 };
+            second.expect(crate::Opcode::ERROR, code);
+
+            first.send(crate::Opcode::DATA, 1, b"first");
+            first.expect(crate::Opcode::ACK, 1);
+            assert_eq!(fs::read(dir.join("upload")).unwrap(), b"first");
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_draining() {
+        let dir = test_dir("draining");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let mut tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).build().unwrap());
+        let draining = Arc::clone(&tftpd.draining);
+        thread::spawn(move || tftpd.serve(socket));
+
+        /* a running transfer continues, new ones are refused */
+        let mut running = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        running.expect(crate::Opcode::DATA, 1);
+        draining.store(true, Ordering::SeqCst);
+        let mut refused = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(refused.expect_error(0), "Server busy");
+        running.send(crate::Opcode::ACK, 1, &[]);
+
+        draining.store(false, Ordering::SeqCst);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_queue() {
+        let dir = test_dir("max-queue");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).threads(1).max_queue(1).build().unwrap());
+
+        /* occupies the only worker until acknowledged */
+        let mut first = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        first.expect(crate::Opcode::DATA, 1);
+        let mut second = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        let mut third = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(third.expect_error(0), "Server busy");
+
+        first.send(crate::Opcode::ACK, 1, &[]);
+        second.expect(crate::Opcode::DATA, 1);
+        second.send(crate::Opcode::ACK, 1, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fork_per_transfer() {
+        let dir = test_dir("fork-per-transfer");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).threads(1).fork_per_transfer(true).build().unwrap());
+
+        /* the only child allowed runs until acknowledged */
+        let mut first = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(first.expect(crate::Opcode::DATA, 1), b"data");
+        let mut second = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(second.expect_error(0), "Server busy");
+        first.send(crate::Opcode::ACK, 1, &[]);
+
+        /* accepted again once the child was reaped */
+        let start = Instant::now();
+        loop {
+            let data = [&[1; 600][..], b"upload"].concat();
+            match crate::Tftp::new().put(server, "upload", &data[..], &HashMap::new()) {
+                Ok(_) => break,
+                Err(err) if start.elapsed() < Duration::from_secs(5) && err.to_string().contains("Server busy") => {
+                    thread::sleep(Duration::from_millis(50))
+                }
+                Err(err) => panic!("upload failed: {}", err),
+            }
+        }
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), [&[1; 600][..], b"upload"].concat());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_out_of_descriptors() {
+        let listening = UdpSocket::bind("[::1]:0").unwrap();
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let cl = client.local_addr().unwrap();
+
+        /* as if binding the socket for the transfer failed */
+        let err = descriptor_error(&cl, io::Error::from_raw_os_error(libc::EMFILE));
+        assert!(err.to_string().starts_with(&format!("Refused request from {} (too many open files", cl)));
+        run_handler(&listening, cl, false, || Err(err));
+        let mut buf = [0; 64];
+        let (len, src) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(src, listening.local_addr().unwrap());
+        assert_eq!(&buf[..len], b"\x00\x05\x00\x00Server busy\x00");
+
+        /* other errors are only logged */
+        let err = descriptor_error(&cl, io::Error::from_raw_os_error(libc::EACCES));
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        run_handler(&listening, cl, false, || Err(err));
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(client.recv_from(&mut buf).is_err());
+
+        /* in a child process, so the limit of the tests running alongside stays as it is */
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+                let raised = raise_nofile_limit().is_ok_and(|(_, limit)| {
+                    let queried = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut current) };
+                    queried == 0 && (current.rlim_cur, current.rlim_max) == (limit, limit)
+                });
+                unsafe { libc::_exit(if raised { 0 } else { 1 }) };
+            }
+            ForkResult::Parent { child } => {
+                let status = waitpid(child, None).unwrap();
+                assert!(matches!(status, WaitStatus::Exited(_, 0)), "{:?}", status);
+            }
+        }
+    }
+
+    #[test]
+    fn test_log_format() {
+        let dir = test_dir("log-format");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let conf = ConfigurationBuilder::new().dir(&dir).log_format("{op} {file} {bytes} {client} {result}").build().unwrap();
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let cl = client.local_addr().unwrap();
+        let transfer = |request: &'static [u8]| {
+            let mut tftpd = Tftpd::new(conf.clone());
+            thread::spawn(move || tftpd.handle_client(&cl, request))
+        };
+
+        let handler = transfer(b"\x00\x01testfile\x00octet\x00");
+        let mut buf = [0; 600];
+        let (len, server) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x03\x00\x01data");
+        client.send_to(b"\x00\x04\x00\x01", server).unwrap();
+        assert_eq!(handler.join().unwrap().unwrap(), format!("read testfile 4 {} ok", cl));
+
+        /* failed transfers keep the kind of their error */
+        let handler = transfer(b"\x00\x01missing\x00octet\x00");
+        client.recv_from(&mut buf).unwrap();
+        let err = handler.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().starts_with(&format!("read missing 0 {} ", cl)), "{}", err);
+
+        /* the default template logs the messages as they are */
+        assert_eq!(ConfigurationBuilder::new().build().unwrap().log_format(), "{message}");
+        let mut tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).build().unwrap());
+        let handler = thread::spawn(move || tftpd.handle_client(&cl, b"\x00\x01testfile\x00octet\x00"));
+        let (_, server) = client.recv_from(&mut buf).unwrap();
+        client.send_to(b"\x00\x04\x00\x01", server).unwrap();
+        assert_eq!(handler.join().unwrap().unwrap(), format!("Sent testfile (4 bytes) to {}.", cl));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        let dir = test_dir("handshake-timeout");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let conf = ConfigurationBuilder::new().dir(&dir).handshake_timeout(Duration::from_millis(50)).build().unwrap();
+        let mut tftpd = Tftpd::new(conf);
+
+        /* the client vanishes right after its request */
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        let start = Instant::now();
+        let err = tftpd.handle_client(&client.local_addr().unwrap(), b"\x00\x01testfile\x00octet\x00blksize\x001024\x00").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_set_process_name() {
+        /* renames only the calling thread, the one running this test */
+        set_process_name("tftp-vlan10").unwrap();
+        assert_eq!(fs::read_to_string("/proc/thread-self/comm").unwrap(), "tftp-vlan10\n");
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let dir = test_dir("notify-socket");
+        let path = dir.join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        connect_notify_socket(path.as_os_str()).unwrap().send(b"READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let name = format!("rtftp-notify-{}", std::process::id());
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+            let systemd = UnixDatagram::bind_addr(&addr).unwrap();
+            connect_notify_socket(OsStr::new(&format!("@{}", name))).unwrap().send(b"READY=1").unwrap();
+            assert_eq!(systemd.recv(&mut buf).unwrap(), 7);
+        }
+        assert!(connect_notify_socket(dir.join("missing").as_os_str()).is_err());
+
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).build().unwrap());
+        assert_eq!(tftpd.listening(&socket), format!("Listening on [::1]:{}, serving {}.", port, dir.canonicalize().unwrap().display()));
+        let tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).write_dir("/").build().unwrap());
+        assert!(tftpd.listening(&socket).ends_with(" and writing to /."));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_aborted_by_client() {
+        let dir = test_dir("aborted-by-client");
+        let content: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let conf = ConfigurationBuilder::new().dir(&dir).build().unwrap();
+
+        /* the client gives up on a download after the first block */
+        let mut client = RawClient { socket: UdpSocket::bind("[::1]:0").unwrap(), server: "[::1]:0".parse().unwrap() };
+        client.socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let addr = client.socket.local_addr().unwrap();
+        let mut tftpd = Tftpd::new(conf.clone());
+        let server = thread::spawn(move || tftpd.handle_client(&addr, b"\x00\x01testfile\x00octet\x00"));
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), &content[..512]);
+        let start = Instant::now();
+        client.send(crate::Opcode::ERROR, 3, b"Disk full\x00");
+        let err = server.join().unwrap().unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), format!("Sending testfile to {} failed (aborted by the client with error 3: Disk full).", Peer(addr)));
+
+        /* an upload as well, and the ERROR is not answered */
+        let mut tftpd = Tftpd::new(conf);
+        let server = thread::spawn(move || tftpd.handle_client(&addr, b"\x00\x02upload\x00octet\x00"));
+        client.expect(crate::Opcode::ACK, 0);
+        client.send(crate::Opcode::DATA, 1, &[b'x'; 512]);
+        client.expect(crate::Opcode::ACK, 1);
+        client.send(crate::Opcode::ERROR, 0, b"Local file vanished\x00");
+        let err = server.join().unwrap().unwrap_err();
+        assert!(err.to_string().ends_with("failed (aborted by the client with error 0: Local file vanished)."));
+        client.socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(client.socket.recv(&mut [0; 516]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_single_port() {
+        let dir = test_dir("single-port");
+        let content: Vec<u8> = (0..4000u32).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).single_port(true).build().unwrap());
+
+        let mut options = HashMap::new();
+        options.insert(String::from("blksize"), String::from("512"));
+
+        /* concurrent transfers from different client ports share the server socket */
+        let clients: Vec<_> = (0..4).map(|i| {
+            let options = options.clone();
+            let content = content.clone();
+            thread::spawn(move || {
+                let name = format!("upload{}", i);
+                crate::Tftp::new().put(server, &name, content.as_slice(), &options).unwrap();
+                let mut received = Vec::new();
+                crate::Tftp::new().get(server, "testfile", &mut received, &options).unwrap();
+                assert_eq!(received, content);
+            })
+        }).collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+
+        for i in 0..4 {
+            assert_eq!(fs::read(dir.join(format!("upload{}", i))).unwrap(), content);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recent_requests() {
+        let recent = RecentRequests::default();
+        let client: SocketAddr = "[2001:db8::1]:1024".parse().unwrap();
+        let rrq = b"\x00\x01pxelinux.0\x00octet\x00";
+        let window = Duration::from_secs(5);
+        let start = Instant::now();
+
+        assert!(!recent.is_duplicate(&client, rrq, window, start));
+        assert!(recent.is_duplicate(&client, rrq, window, start + Duration::from_secs(1)));
+        /* another port, file or opcode is another request */
+        assert!(!recent.is_duplicate(&"[2001:db8::1]:1025".parse().unwrap(), rrq, window, start));
+        assert!(!recent.is_duplicate(&client, b"\x00\x01ldlinux.c32\x00octet\x00", window, start));
+        assert!(!recent.is_duplicate(&client, b"\x00\x02pxelinux.0\x00octet\x00", window, start));
+
+        /* the window ends after its duration or with the transfer */
+        assert!(!recent.is_duplicate(&client, rrq, window, start + window));
+        recent.finished(&client, rrq);
+        assert!(!recent.is_duplicate(&client, rrq, window, start + window));
+        assert!(!recent.is_duplicate(&client, rrq, Duration::ZERO, start + window));
+        assert!(recent.is_duplicate(&client, rrq, window, start + window));
+    }
+
+    #[test]
+    fn test_duplicate_request() {
+        let dir = test_dir("duplicate-request");
+        let content: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let server = spawn_server(&dir);
+
+        /* the request is repeated before the first block arrived */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        client.socket.send_to(b"\x00\x01testfile\x00octet\x00", server).unwrap();
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), &content[..512]);
+        let transfer = client.server;
+
+        /* no second transfer answers from another port */
+        client.socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        let mut buf = [0; 516];
+        if let Ok((_, src)) = client.socket.recv_from(&mut buf) {
+            assert_eq!(src, transfer);
+        }
+        client.socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send(crate::Opcode::ACK, 1, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 2), &content[512..]);
+        assert_eq!(client.server, transfer);
+        client.send(crate::Opcode::ACK, 2, &[]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cancel_transfer() {
+        let dir = test_dir("cancel-transfer");
+        let content = vec![0x55; 100 * 512];
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let mut tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).build().unwrap());
+        let canceller = tftpd.canceller();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        thread::spawn(move || tftpd.serve(socket));
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        for block in 1..=2 {
+            client.expect(crate::Opcode::DATA, block);
+            client.send(crate::Opcode::ACK, block, &[]);
+        }
+        client.expect(crate::Opcode::DATA, 3);
+        assert_eq!(canceller.cancel("192.0.2.1".parse().unwrap()), 0);
+        assert_eq!(canceller.cancel("127.0.0.1".parse().unwrap()), 1);
+
+        /* the next block is replaced by an ERROR */
+        client.send(crate::Opcode::ACK, 3, &[]);
+        assert_eq!(client.expect_error(0), "Transfer cancelled");
+
+        /* the transfer is forgotten once it ended */
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(canceller.cancel("127.0.0.1".parse().unwrap()), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_allow_rule() {
+        let rule: AllowRule = "read:192.0.2.0/24".parse().unwrap();
+        assert!(rule.contains("192.0.2.77".parse().unwrap()));
+        assert!(rule.contains("::ffff:192.0.2.1".parse().unwrap()));
+        assert!(!rule.contains("192.0.3.1".parse().unwrap()));
+        assert!(!rule.contains("2001:db8::1".parse().unwrap()));
+        assert!(rule.permits(Direction::Read));
+        assert!(!rule.permits(Direction::Write));
+
+        let rule: AllowRule = "write:2001:db8::10".parse().unwrap();
+        assert!(rule.contains("2001:db8::10".parse().unwrap()));
+        assert!(!rule.contains("2001:db8::11".parse().unwrap()));
+        assert!(!rule.permits(Direction::Read));
+
+        let rule: AllowRule = "rw:0.0.0.0/0".parse().unwrap();
+        assert!(rule.contains("198.51.100.1".parse().unwrap()));
+        assert!(rule.permits(Direction::Read) && rule.permits(Direction::Write));
+        assert!("rw:2001:db8::/32".parse::<AllowRule>().unwrap().contains("2001:db8:ffff::1".parse().unwrap()));
+
+        for invalid in ["192.0.2.0/24", "all:192.0.2.0/24", "read:192.0.2.0/33", "read:2001:db8::/129", "read:host", "read:"] {
+            assert!(invalid.parse::<AllowRule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_allow_from() {
+        let dir = test_dir("allow-from");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+
+        /* any client may read, uploads only from another address */
+        let conf = ConfigurationBuilder::new().dir(&dir).allow_from("rw:2001:db8::10".parse().unwrap())
+            .allow_from("read:::/0".parse().unwrap()).build().unwrap();
+        let server = spawn_configured(conf);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        assert_eq!(client.expect_error(4), "writing not allowed");
+        assert!(!dir.join("upload").exists());
+
+        /* only uploads from here */
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).allow_from("write:::1".parse().unwrap()).build().unwrap());
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect_error(4), "reading not allowed");
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        client.expect(crate::Opcode::ACK, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_default_mode() {
+        let dir = test_dir("default-mode");
+        fs::write(dir.join("testfile"), b"line\n").unwrap();
+        let request = |server: SocketAddr, buf: &[u8]| {
+            let socket = UdpSocket::bind("[::1]:0").unwrap();
+            socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            socket.send_to(buf, server).unwrap();
+            RawClient { socket, server }
+        };
+
+        /* rejected by default */
+        let server = spawn_server(&dir);
+        let client = request(server, b"\x00\x01testfile\x00");
+        client.socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        assert!(client.socket.recv_from(&mut [0; 516]).is_err());
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).default_mode("octet").build().unwrap());
+        for buf in [&b"\x00\x01testfile\x00"[..], b"\x00\x01testfile\x00\x00", b"\x00\x01testfile\x00oct"] {
+            let mut client = request(server, buf);
+            assert_eq!(client.expect(crate::Opcode::DATA, 1), b"line\n");
+            client.send(crate::Opcode::ACK, 1, &[]);
+        }
+        /* a mode that was sent still has to be supported */
+        let mut client = RawClient::request_mode(server, crate::Opcode::RRQ, "testfile", "mail", &[]);
+        assert_eq!(client.expect_error(0), "Unsupported mode");
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).default_mode("netascii").build().unwrap());
+        let mut client = request(server, b"\x00\x02upload\x00");
+        client.expect(crate::Opcode::ACK, 0);
+        client.send(crate::Opcode::DATA, 1, b"line\r\n");
+        client.expect(crate::Opcode::ACK, 1);
+        assert_eq!(fs::read(dir.join("upload")).unwrap(), b"line\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drop_invalid() {
+        let dir = test_dir("drop-invalid");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let probes: [&[u8]; 4] = [b"\x00", b"\x00\x04\x00\x01", b"\x00\x05\x00\x00probe\x00", b"\x13\x37junk"];
+
+        let server = spawn_server(&dir);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        client.socket.send_to(b"\x13\x37junk", server).unwrap();
+        assert_eq!(client.expect_error(4), "Unexpected opcode");
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).drop_invalid(true).build().unwrap());
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        client.socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        for probe in probes {
+            client.socket.send_to(probe, server).unwrap();
+        }
+        assert!(client.socket.recv(&mut [0; 516]).is_err());
+
+        /* also when handling a single request */
+        let mut tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).drop_invalid(true).build().unwrap());
+        let probe = client.socket.local_addr().unwrap();
+        assert_eq!(tftpd.handle_client(&probe, b"\x00\x03\x00\x01").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(client.socket.recv(&mut [0; 516]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_translate_backslash() {
+        let dir = test_dir("translate-backslash");
+        fs::create_dir(dir.join("pxe")).unwrap();
+        fs::write(dir.join("pxe/boot.bin"), b"data").unwrap();
+
+        assert_eq!(translate_backslashes(Path::new("pxe\\boot.bin")), Path::new("pxe/boot.bin"));
+        assert_eq!(translate_backslashes(Path::new("\\pxe\\boot.bin")), Path::new("/pxe/boot.bin"));
+
+        let server = spawn_server(&dir);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "pxe\\boot.bin", &[]);
+        client.expect_error(2);
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).translate_backslash(true).build().unwrap());
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "pxe\\boot.bin", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        /* translated names are still checked */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "\\etc\\passwd", &[]);
+        client.expect_error(2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_storage() {
+        let storage = Arc::new(crate::storage::MemoryStorage::new());
+        storage.insert("pxe/boot.bin", b"boot\ndata").unwrap();
+        /* the served directory is not used */
+        let conf = ConfigurationBuilder::new().deny_ext("img").enable_listing(true).per_client_subdir(true).build().unwrap();
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let mut tftpd = Tftpd::new(conf);
+        tftpd.set_storage(Arc::clone(&storage) as Arc<dyn Storage>);
+        thread::spawn(move || tftpd.serve(socket));
+
+        let mut options = HashMap::new();
+        options.insert(String::from("tsize"), String::from("0"));
+        let mut received = Vec::new();
+        assert_eq!(crate::Tftp::new().get(server, "pxe/boot.bin", &mut received, &options).unwrap(), 9);
+        assert_eq!(received, b"boot\ndata");
+        let mut tftp = crate::Tftp::new();
+        tftp.set_mode(crate::Mode::NETASCII);
+        let mut received = Vec::new();
+        tftp.get(server, "pxe/boot.bin", &mut received, &options).unwrap();
+        assert_eq!(received, b"boot\ndata");
+
+        let mut listing = Vec::new();
+        crate::Tftp::new().get(server, "pxe", &mut listing, &HashMap::new()).unwrap();
+        assert_eq!(listing, b"boot.bin\n");
+
+        /* requests are handled as for the served directory, e.g. with the client directories */
+        crate::Tftp::new().put(server, "upload", &b"uploaded"[..], &HashMap::new()).unwrap();
+        assert_eq!(storage.get("--1/upload").unwrap(), b"uploaded");
+
+        /* the checks of the server and the storage apply */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "missing", &[]);
+        client.expect_error(1);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "../pxe/boot.bin", &[]);
+        client.expect_error(2);
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "disk.img", &[]);
+        client.expect_error(2);
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, "upload", &[]);
+        client.expect_error(6);
+    }
+
+    #[test]
+    fn test_max_rrq_size() {
+        let dir = test_dir("max-rrq-size");
+        fs::write(dir.join("small"), vec![0x55; 1000]).unwrap();
+        fs::write(dir.join("large"), vec![0x55; 1001]).unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).max_rrq_size(1000).build().unwrap());
+
+        let mut received = Vec::new();
+        assert_eq!(crate::Tftp::new().get(server, "small", &mut received, &HashMap::new()).unwrap(), 1000);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "large", &[("tsize", "0")]);
+        assert_eq!(client.expect_error(2), "File too large (more than 1000 bytes)");
+
+        /* uploads are not limited */
+        crate::Tftp::new().put(server, "upload", &[0x55; 2000][..], &HashMap::new()).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_max_filename_len() {
+        let dir = test_dir("max-filename-len");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+
+        let server = spawn_server(&dir);
+        let long = "a/".repeat(4000) + "testfile";
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, &long, &[]);
+        assert_eq!(client.expect_error(2), "File name too long");
+        let mut client = RawClient::request(server, crate::Opcode::WRQ, &"a".repeat(256), &[]);
+        assert_eq!(client.expect_error(2), "File name too long");
+        assert!(!dir.join("a".repeat(256)).exists());
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).max_filename_len(8).build().unwrap());
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "./testfile", &[]);
+        assert_eq!(client.expect_error(2), "File name too long");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_non_utf8_filename() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = test_dir("non-utf8-filename");
+        /* "café" in Latin-1 */
+        let name = OsStr::from_bytes(b"caf\xe9.cfg");
+        fs::write(dir.join(name), b"data").unwrap();
+
+        let server = spawn_server(&dir);
+        let mut client = RawClient::request_bytes(server, crate::Opcode::RRQ, name.as_bytes(), "octet", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        let mut client = RawClient::request_bytes(server, crate::Opcode::WRQ, b"upload\xff", "octet", &[]);
+        client.expect(crate::Opcode::ACK, 0);
+        client.send(crate::Opcode::DATA, 1, b"uploaded");
+        client.expect(crate::Opcode::ACK, 1);
+        assert_eq!(fs::read(dir.join(OsStr::from_bytes(b"upload\xff"))).unwrap(), b"uploaded");
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).utf8_filenames(true).build().unwrap());
+        let mut client = RawClient::request_bytes(server, crate::Opcode::RRQ, name.as_bytes(), "octet", &[]);
+        assert_eq!(client.expect_error(0), "File name is not valid UTF-8");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_writable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir("check-writable");
+        assert!(check_writable(&dir).is_ok());
+        assert_eq!(check_writable(&dir.join("missing")).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        /* root may write regardless of the permissions */
+        if Uid::effective() != ROOT {
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o555)).unwrap();
+            assert_eq!(check_writable(&dir).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+            fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_force_blksize() {
+        let dir = test_dir("force-blksize");
+        fs::write(dir.join("testfile"), vec![0x55; 1500]).unwrap();
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).force_blksize(1024).build().unwrap());
+
+        /* a client without options gets an OACK nonetheless */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect_oack(), HashMap::from([(String::from("blksize"), String::from("1024"))]));
+        client.send(crate::Opcode::ACK, 0, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1).len(), 1024);
+        client.send(crate::Opcode::ACK, 1, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 2).len(), 476);
+        client.send(crate::Opcode::ACK, 2, &[]);
+
+        /* a requested block size is kept */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[("blksize", "512")]);
+        assert_eq!(client.expect_oack()["blksize"], "512");
+        client.send(crate::Opcode::ERROR, 0, b"done\0");
+
+        /* a client rejecting it ends the transfer, nothing more is sent */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        client.expect_oack();
+        client.send(crate::Opcode::ERROR, 8, b"Option negotiation refused\0");
+        client.socket.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        assert!(client.socket.recv(&mut [0; 516]).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_idle_exit() {
+        let dir = test_dir("idle-exit");
+        fs::write(dir.join("testfile"), b"data").unwrap();
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let server = socket.local_addr().unwrap();
+        let mut tftpd = Tftpd::new(ConfigurationBuilder::new().dir(&dir).idle_exit(Duration::from_secs(2)).build().unwrap());
+        let (done, stopped) = mpsc::channel();
+        let start = Instant::now();
+        thread::spawn(move || {
+            tftpd.serve(socket);
+            done.send(()).unwrap();
+        });
+
+        /* a request keeps the server running for another two seconds */
+        thread::sleep(Duration::from_secs(1));
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), b"data");
+        client.send(crate::Opcode::ACK, 1, &[]);
+
+        stopped.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(3));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_serve_once() {
+        let dir = test_dir("serve-once");
+        let content: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        fs::write(dir.join("testfile"), &content).unwrap();
+        let serve = |conf: Configuration| {
+            let socket = UdpSocket::bind("[::1]:0").unwrap();
+            let server = socket.local_addr().unwrap();
+            let mut tftpd = Tftpd::new(conf);
+            let (done, stopped) = mpsc::channel();
+            thread::spawn(move || done.send(tftpd.serve(socket)).unwrap());
+            (server, stopped)
+        };
+
+        let (server, stopped) = serve(ConfigurationBuilder::new().dir(&dir).serve_once(true).build().unwrap());
+        /* a failed transfer does not count */
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "missing", &[]);
+        assert_eq!(client.expect_error(1), "File not found");
+        /* the worker reports the outcome right after sending the ERROR */
+        thread::sleep(Duration::from_millis(50));
+
+        let mut client = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 1), &content[..512]);
+        let mut other = RawClient::request(server, crate::Opcode::RRQ, "testfile", &[]);
+        assert_eq!(other.expect_error(0), "Server busy");
+        client.send(crate::Opcode::ACK, 1, &[]);
+        assert_eq!(client.expect(crate::Opcode::DATA, 2), &content[512..]);
+        client.send(crate::Opcode::ACK, 2, &[]);
+        assert!(stopped.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        /* stopped before any transfer succeeded */
+        let (_, stopped) = serve(ConfigurationBuilder::new().dir(&dir).serve_once(true).idle_exit(Duration::from_secs(1))
+                                                         .build().unwrap());
+        assert!(!stopped.recv_timeout(Duration::from_secs(5)).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drain() {
+        let canceller = Canceller::default();
+        let start = Instant::now();
+        assert!(drain(&canceller, Duration::from_secs(10)).is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        /* one transfer finishes in time, the other one is stuck */
+        let stuck: SocketAddr = "[2001:db8::1]:1024".parse().unwrap();
+        let finishing: SocketAddr = "[2001:db8::2]:1024".parse().unwrap();
+        let token = canceller.register(stuck);
+        let finished = canceller.register(finishing);
+        let worker = canceller.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            worker.unregister(&finishing, &finished);
+        });
+
+        let start = Instant::now();
+        assert_eq!(drain(&canceller, Duration::from_millis(300)), vec![stuck]);
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(token.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let limiter = RateLimiter::new(5);
+        let client: SocketAddr = "[2001:db8::1]:1024".parse().unwrap();
+        let other: SocketAddr = "[2001:db8::2]:1024".parse().unwrap();
+        let start = Instant::now();
+
+        /* a burst beyond the limit is dropped */
+        let allowed = (0..20).filter(|_| limiter.allow(&client, start)).count();
+        assert_eq!(allowed, 5);
+        assert!(limiter.allow(&other, start));
+
+        /* tokens are refilled over time */
+        assert!(!limiter.allow(&client, start + Duration::from_millis(100)));
+        assert!(limiter.allow(&client, start + Duration::from_millis(300)));
+        let allowed = (0..20).filter(|_| limiter.allow(&client, start + Duration::from_secs(10))).count();
+        assert_eq!(allowed, 5);
+
+        /* the same link-local address on another interface is another client */
+        let link_local = |scope_id| SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 1024, 0, scope_id));
+        let limiter = RateLimiter::new(1);
+        assert!(limiter.allow(&link_local(1), start));
+        assert!(!limiter.allow(&link_local(1), start));
+        assert!(limiter.allow(&link_local(2), start));
+
+        /* other ports of a client share its limit */
+        let allowed = (0..20).filter(|_| limiter.allow(&"[2001:db8::1]:2048".parse().unwrap(), start)).count();
+        assert_eq!(allowed, 1);
+
+        /* idle clients are pruned */
+        limiter.allow(&client, start + RATE_LIMIT_PRUNE_INTERVAL * 2);
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_special_files() {
+        let dir = test_dir("special");
+        let content: Vec<u8> = (0..3000u32).map(|i| (i % 13) as u8).collect();
+        let fifo = dir.join("fifo");
+        assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+        /* refused by default, in both directions */
+        let server = spawn_server(&dir);
+        assert_eq!(crate::Tftp::new().get(server, "fifo", Vec::new(), &HashMap::new()).unwrap_err().kind(),
+                   io::ErrorKind::PermissionDenied);
+        assert_eq!(crate::Tftp::new().put(server, "fifo", content.as_slice(), &HashMap::new()).unwrap_err().kind(),
+                   io::ErrorKind::PermissionDenied);
+
+        let server = spawn_configured(ConfigurationBuilder::new().dir(&dir).allow_special(true).build().unwrap());
+
+        /* an idle reader keeps the written content in the FIFO until the server reads it */
+        let idle = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&fifo).unwrap();
+        fs::write(&fifo, &content).unwrap();
+        let mut received = Vec::new();
+        crate::Tftp::new().get(server, "fifo", &mut received, &HashMap::new()).unwrap();
+        assert_eq!(received, content);
+        drop(idle);
+
+        /* the server only writes into a FIFO that already has a reader */
+        let mut reader = OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(&fifo).unwrap();
+        crate::Tftp::new().put(server, "fifo", content.as_slice(), &HashMap::new()).unwrap();
+        let mut received = vec![0; content.len()];
+        reader.read_exact(&mut received).unwrap();
+        assert_eq!(received, content);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_upload_hook() {
+        let dir = test_dir("hook");
+        let hook = dir.join("hook.sh");
+        fs::write(&hook, "#!/bin/sh\necho \"$1 $RTFTP_CLIENT\" > \"$1.hook\"\n[ \"${1##*/}\" = good ]\n").unwrap();
+        assert!(Command::new("chmod").arg("+x").arg(&hook).status().unwrap().success());
+
+        let conf = ConfigurationBuilder::new().dir(&dir).on_upload(hook.to_str().unwrap()).on_upload_delete(true)
+                                              .build().unwrap();
+        let server = spawn_configured(conf);
+        let wait_for = |path: &Path, exists: bool| {
+            for _ in 0..100 {
+                if path.exists() == exists {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            panic!("waiting for {} timed out", path.display());
+        };
+
+        crate::Tftp::new().put(server, "good", b"firmware".as_slice(), &HashMap::new()).unwrap();
+        wait_for(&dir.join("good.hook"), true);
+        let output = fs::read_to_string(dir.join("good.hook")).unwrap();
+        assert!(output.starts_with(&format!("{} [::1]:", dir.canonicalize().unwrap().join("good").display())));
+        assert!(dir.join("good").exists());
+
+        /* a failing hook removes the upload */
+        crate::Tftp::new().put(server, "bad", b"firmware".as_slice(), &HashMap::new()).unwrap();
+        wait_for(&dir.join("bad.hook"), true);
+        wait_for(&dir.join("bad"), false);
+
+        /* also by the child process of the transfer */
+        let conf = ConfigurationBuilder::new().dir(&dir).on_upload(hook.to_str().unwrap()).on_upload_delete(true)
+                                              .fork_per_transfer(true).build().unwrap();
+        let forked = spawn_configured(conf);
+        crate::Tftp::new().put(forked, "forked", b"firmware".as_slice(), &HashMap::new()).unwrap();
+        wait_for(&dir.join("forked.hook"), true);
+        wait_for(&dir.join("forked"), false);
+
+        /* with a hook running for each worker, the next one is waited for */
+        let hook = dir.join("slow.sh");
+        fs::write(&hook, "#!/bin/sh\nsleep 0.2\ntouch \"$1.hook\"\n").unwrap();
+        assert!(Command::new("chmod").arg("+x").arg(&hook).status().unwrap().success());
+        let conf = ConfigurationBuilder::new().dir(&dir).threads(1).on_upload(hook.to_str().unwrap()).build().unwrap();
+        let tftpd = Tftpd::new(conf);
+        let cl = Peer("[::1]:1234".parse().unwrap());
+        let storage = tftpd.storage(Direction::Write);
+        tftpd.run_upload_hook(&storage, Path::new("first"), &cl);
+        assert!(!dir.join("first.hook").exists());
+        tftpd.run_upload_hook(&storage, Path::new("second"), &cl);
+        assert!(dir.join("second.hook").exists());
+        wait_for(&dir.join("first.hook"), true);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mirror() {
+        let dir = test_dir("mirror");
+        fs::create_dir_all(dir.join("primary")).unwrap();
+        fs::create_dir_all(dir.join("upstream")).unwrap();
+        fs::write(dir.join("upstream/existing"), b"old").unwrap();
+        let upstream = spawn_server(&dir.join("upstream"));
+        let wait_for = |path: &Path, content: Option<&[u8]>| {
+            for _ in 0..100 {
+                if fs::read(path).ok().as_deref() == content {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            panic!("waiting for {} timed out", path.display());
+        };
+
+        let conf = ConfigurationBuilder::new().dir(dir.join("primary")).mirror(upstream).build().unwrap();
+        let server = spawn_configured(conf);
+        let content: Vec<u8> = (0..2000u32).map(|i| i as u8).collect();
+        crate::Tftp::new().put(server, "firmware", content.as_slice(), &HashMap::new()).unwrap();
+        wait_for(&dir.join("upstream/firmware"), Some(&content));
+        assert_eq!(fs::read(dir.join("primary/firmware")).unwrap(), content);
+
+        /* refused by the upstream server, but kept */
+        crate::Tftp::new().put(server, "existing", &b"new"[..], &HashMap::new()).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(fs::read(dir.join("primary/existing")).unwrap(), b"new");
+        assert_eq!(fs::read(dir.join("upstream/existing")).unwrap(), b"old");
+
+        fs::remove_file(dir.join("primary/existing")).unwrap();
+        let conf = ConfigurationBuilder::new().dir(dir.join("primary")).mirror(upstream).strict_mirror(true).build().unwrap();
+        let server = spawn_configured(conf);
+        crate::Tftp::new().put(server, "existing", &b"new"[..], &HashMap::new()).unwrap();
+        wait_for(&dir.join("primary/existing"), None);
+        assert_eq!(fs::read(dir.join("upstream/existing")).unwrap(), b"old");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_handler_panic() {
+        let socket = UdpSocket::bind("[::1]:0").unwrap();
+        let client = UdpSocket::bind("[::1]:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        assert!(!run_handler(&socket, client.local_addr().unwrap(), false, || panic!("handler failed")));
+        let mut buf = [0; 100];
+        let (len, src) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(src, socket.local_addr().unwrap());
+        assert_eq!(&buf[..len], b"\x00\x05\x00\x00Internal server error\x00");
+
+        /* regular outcomes are only logged */
+        run_handler(&socket, client.local_addr().unwrap(), true, || Err(io::Error::other("failed")));
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        assert!(client.recv(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_peer_display() {
+        let addr = |scope_id| SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 69, 0, scope_id));
+        assert_eq!(Peer(addr(0)).to_string(), "[fe80::1]:69");
+        assert_eq!(Peer("192.0.2.1:69".parse().unwrap()).to_string(), "192.0.2.1:69");
+        /* unknown interfaces are shown by index */
+        assert_eq!(Peer(addr(u32::MAX)).to_string(), format!("[fe80::1%{}]:69", u32::MAX));
+
+        if let Some(interface) = if_nameindex().unwrap().iter().next() {
+            assert_eq!(Peer(addr(interface.index())).to_string(),
+                       format!("[fe80::1%{}]:69", interface.name().to_str().unwrap()));
+        }
+    }
+}
@@ -0,0 +1,397 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+//! Where a [`Tftpd`](crate::server::Tftpd) reads and writes files: an
+//! [`FsStorage`] for its served directory (or `--read-dir` and `--write-dir`),
+//! unless replaced with [`Tftpd::set_storage`](crate::server::Tftpd::set_storage).
+//!
+//! The server checks a requested name (relative, no control characters,
+//! `--allow-ext` and similar) and resolves it with [`Storage::resolve`]; the
+//! other methods are only passed resolved names.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use nix::libc;
+
+/// A file opened for reading from a [`Storage`].
+pub trait ReadSeek: Read + Seek + Send {
+    /// Returns the file of the local file system being read, if it is one.
+    /// `--allow-special`, `--cache-size` and `--mmap` only apply to those.
+    fn as_file(&mut self) -> Option<&mut File> {
+        None
+    }
+}
+
+impl ReadSeek for File {
+    fn as_file(&mut self) -> Option<&mut File> {
+        Some(self)
+    }
+}
+
+impl<T: AsRef<[u8]> + Send> ReadSeek for Cursor<T> {}
+
+/// A file opened for writing to a [`Storage`].
+pub trait WriteFile: Write + Send {
+    /// Returns the file of the local file system being written, if it is one.
+    /// `--sparse`, `--preallocate` and `--fsync` only apply to those; the
+    /// server then writes to the file directly.
+    fn as_file(&mut self) -> Option<&mut File> {
+        None
+    }
+}
+
+impl WriteFile for File {
+    fn as_file(&mut self) -> Option<&mut File> {
+        Some(self)
+    }
+}
+
+/// A backend holding the files of a server.
+pub trait Storage: Send + Sync {
+    /// Returns the name of the file `path` refers to within the storage,
+    /// e.g. with `..` resolved, or `None` if it leads out of it.
+    fn resolve(&self, path: &Path) -> Option<PathBuf>;
+
+    /// Like [`resolve`](Storage::resolve), for a directory; `None` if `path`
+    /// is not one. The empty path is the top of the storage.
+    fn resolve_dir(&self, path: &Path) -> Option<PathBuf>;
+
+    /// Returns the names of the files in the directory `dir`, without its
+    /// subdirectories.
+    fn list(&self, dir: &Path) -> Result<Vec<OsString>, io::Error>;
+
+    /// Opens the file at `path` for a download. Fails with `NotFound` if it
+    /// does not exist and with `PermissionDenied` if it may not be read.
+    fn open_read(&self, path: &Path) -> Result<Box<dyn ReadSeek>, io::Error>;
+
+    /// Creates the file at `path` for an upload. Fails with `AlreadyExists`
+    /// instead of replacing an existing file, unless it is appended to.
+    fn open_write(&self, path: &Path, append: bool) -> Result<Box<dyn WriteFile>, io::Error>;
+
+    /// Removes the file at `path`, e.g. an upload rejected by `--on-upload`.
+    fn remove(&self, path: &Path) -> Result<(), io::Error>;
+
+    /// Creates the directory at `path`, unless it exists already.
+    fn create_dir(&self, path: &Path) -> Result<(), io::Error>;
+
+    /// Returns the size of the file at `path` in bytes.
+    fn size(&self, path: &Path) -> Result<u64, io::Error>;
+
+    /// Whether there is a file or directory at `path`.
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Returns the directory of the local file system holding the files, if
+    /// there is one. `--include`, uploads into FIFOs and device nodes and the
+    /// argument of `--on-upload` depend on it.
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+}
+
+fn permission_denied() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "outside of the storage")
+}
+
+/// Files in a directory of the local file system. Names leading out of it
+/// (through `..` or symbolic links in their directories) are refused.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<FsStorage, io::Error> {
+        Ok(FsStorage { root: root.as_ref().canonicalize()? })
+    }
+
+    /// Like [`new`](FsStorage::new), for the already canonical directories
+    /// of a configuration.
+    pub(crate) fn canonical(root: &Path) -> FsStorage {
+        FsStorage { root: root.to_path_buf() }
+    }
+}
+
+impl Storage for FsStorage {
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        if path.is_absolute() {
+            return None;
+        }
+        if self.root == Path::new("/") {
+            /* running either chrooted in requested directory,
+               or whole root is being served */
+            return Some(path.to_path_buf());
+        }
+
+        /* get parent to check dir where file should be read/written */
+        let dir = self.root.join(path)
+                           .parent()?
+                           .canonicalize()
+                           .ok()?;
+
+        /* check last component of given filename appended to canonicalized path */
+        match dir.join(path.file_name()?).strip_prefix(&self.root) {
+            Ok(p) if p != Path::new("") => Some(p.to_path_buf()),
+            _ => None,
+        }
+    }
+
+    fn resolve_dir(&self, path: &Path) -> Option<PathBuf> {
+        if path.is_absolute() {
+            return None;
+        }
+        let dir = self.root.join(path).canonicalize().ok()?;
+        let relative = dir.strip_prefix(&self.root).ok()?;
+        dir.is_dir().then(|| relative.to_path_buf())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<OsString>, io::Error> {
+        Ok(fs::read_dir(self.root.join(dir))?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name())
+            .collect())
+    }
+
+    fn open_read(&self, path: &Path) -> Result<Box<dyn ReadSeek>, io::Error> {
+        /* without O_NONBLOCK, opening a FIFO blocks until it has a writer */
+        Ok(Box::new(OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(self.root.join(path))?))
+    }
+
+    fn open_write(&self, path: &Path, append: bool) -> Result<Box<dyn WriteFile>, io::Error> {
+        let mut options = OpenOptions::new();
+        if append {
+            options.append(true).create(true);
+        } else {
+            options.write(true).create_new(true);
+        }
+        Ok(Box::new(options.open(self.root.join(path))?))
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), io::Error> {
+        fs::remove_file(self.root.join(path))
+    }
+
+    fn create_dir(&self, path: &Path) -> Result<(), io::Error> {
+        match fs::create_dir(self.root.join(path)) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn size(&self, path: &Path) -> Result<u64, io::Error> {
+        Ok(fs::metadata(self.root.join(path))?.len())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.root.join(path).exists()
+    }
+
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+}
+
+type Files = Arc<Mutex<HashMap<PathBuf, Vec<u8>>>>;
+
+/// Files kept in memory, e.g. for tests. Paths are normalized, so `a/./b`
+/// and `a/b` are the same file; names leading out with `..` are refused.
+/// Directories exist as long as there are files in them.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    files: Files,
+}
+
+/// An upload into a [`MemoryStorage`]; the data is visible while it is written.
+struct MemoryFile {
+    files: Files,
+    path: PathBuf,
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        self.files.lock().unwrap().entry(self.path.clone()).or_default().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        Ok(())
+    }
+}
+
+impl WriteFile for MemoryFile {}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        Default::default()
+    }
+
+    /// Adds or replaces the file at `path`.
+    pub fn insert<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<(), io::Error> {
+        self.files.lock().unwrap().insert(MemoryStorage::normalize(path.as_ref())?, data.to_vec());
+        Ok(())
+    }
+
+    /// Returns the content of the file at `path`.
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        let path = MemoryStorage::normalize(path.as_ref()).ok()?;
+        self.files.lock().unwrap().get(&path).cloned()
+    }
+
+    fn normalize(path: &Path) -> Result<PathBuf, io::Error> {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => normalized.push(name),
+                Component::CurDir => {}
+                _ => return Err(permission_denied()),
+            }
+        }
+        Ok(normalized)
+    }
+
+    fn not_found() -> io::Error {
+        io::Error::new(io::ErrorKind::NotFound, "no such file")
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        MemoryStorage::normalize(path).ok().filter(|path| path != Path::new(""))
+    }
+
+    fn resolve_dir(&self, path: &Path) -> Option<PathBuf> {
+        let dir = MemoryStorage::normalize(path).ok()?;
+        let files = self.files.lock().unwrap();
+        let found = dir == Path::new("") || files.keys().any(|file| file != &dir && file.starts_with(&dir));
+        found.then_some(dir)
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<OsString>, io::Error> {
+        let dir = MemoryStorage::normalize(dir)?;
+        let files = self.files.lock().unwrap();
+        Ok(files.keys()
+            .filter(|file| file.parent() == Some(&dir))
+            .filter_map(|file| file.file_name())
+            .map(|name| name.to_os_string())
+            .collect())
+    }
+
+    fn open_read(&self, path: &Path) -> Result<Box<dyn ReadSeek>, io::Error> {
+        let data = self.get(MemoryStorage::normalize(path)?).ok_or_else(MemoryStorage::not_found)?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn open_write(&self, path: &Path, append: bool) -> Result<Box<dyn WriteFile>, io::Error> {
+        let path = MemoryStorage::normalize(path)?;
+        let mut files = self.files.lock().unwrap();
+        if files.contains_key(&path) && !append {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file exists"));
+        }
+        files.entry(path.clone()).or_default();
+        Ok(Box::new(MemoryFile { files: Arc::clone(&self.files), path }))
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), io::Error> {
+        let path = MemoryStorage::normalize(path)?;
+        self.files.lock().unwrap().remove(&path).map(|_| ()).ok_or_else(MemoryStorage::not_found)
+    }
+
+    fn create_dir(&self, _path: &Path) -> Result<(), io::Error> {
+        Ok(())
+    }
+
+    fn size(&self, path: &Path) -> Result<u64, io::Error> {
+        let path = MemoryStorage::normalize(path)?;
+        self.files.lock().unwrap().get(&path).map(|data| data.len() as u64).ok_or_else(MemoryStorage::not_found)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.get(path).is_some() || self.resolve_dir(path).is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_fs_storage() {
+        let dir = env::temp_dir().join(format!("rtftp-storage-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("served/sub")).unwrap();
+        fs::write(dir.join("served/sub/file"), b"data").unwrap();
+        fs::write(dir.join("secret"), b"secret").unwrap();
+        let storage = FsStorage::new(dir.join("served")).unwrap();
+        assert_eq!(storage.local_dir(), Some(dir.join("served").canonicalize().unwrap().as_path()));
+
+        assert_eq!(storage.resolve(Path::new("sub/../sub/file")), Some(PathBuf::from("sub/file")));
+        assert_eq!(storage.resolve(Path::new("../secret")), None);
+        assert_eq!(storage.resolve(&dir.join("secret")), None);
+        assert_eq!(storage.resolve_dir(Path::new("sub/..")), Some(PathBuf::new()));
+        assert_eq!(storage.resolve_dir(Path::new("sub/file")), None);
+        assert_eq!(storage.resolve_dir(Path::new("..")), None);
+        assert_eq!(storage.list(Path::new("")).unwrap(), Vec::<OsString>::new());
+        assert_eq!(storage.list(Path::new("sub")).unwrap(), [OsString::from("file")]);
+
+        let mut data = Vec::new();
+        storage.open_read(Path::new("sub/file")).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data");
+        assert!(storage.open_read(Path::new("sub/file")).unwrap().as_file().is_some());
+        assert_eq!(storage.size(Path::new("sub/file")).unwrap(), 4);
+        assert!(storage.exists(Path::new("sub/file")));
+        assert!(!storage.exists(Path::new("missing")));
+        assert_eq!(storage.open_read(Path::new("missing")).err().map(|err| err.kind()), Some(io::ErrorKind::NotFound));
+
+        storage.open_write(Path::new("sub/new"), false).unwrap().write_all(b"new").unwrap();
+        assert_eq!(fs::read(dir.join("served/sub/new")).unwrap(), b"new");
+        assert_eq!(storage.open_write(Path::new("sub/new"), false).err().map(|err| err.kind()), Some(io::ErrorKind::AlreadyExists));
+        storage.open_write(Path::new("sub/new"), true).unwrap().write_all(b"er").unwrap();
+        assert_eq!(fs::read(dir.join("served/sub/new")).unwrap(), b"newer");
+        storage.remove(Path::new("sub/new")).unwrap();
+        assert!(!storage.exists(Path::new("sub/new")));
+
+        storage.create_dir(Path::new("client")).unwrap();
+        storage.create_dir(Path::new("client")).unwrap();
+        assert!(dir.join("served/client").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        storage.insert("sub/file", b"data").unwrap();
+        assert_eq!(storage.size(Path::new("./sub/file")).unwrap(), 4);
+        assert!(storage.exists(Path::new("sub/./file")));
+        assert!(storage.exists(Path::new("sub")));
+        assert!(storage.insert("../file", b"data").is_err());
+        assert_eq!(storage.resolve(Path::new("sub/./file")), Some(PathBuf::from("sub/file")));
+        assert_eq!(storage.resolve(Path::new("sub/../sub/file")), None);
+        assert_eq!(storage.open_read(Path::new("sub/../sub/file")).err().map(|err| err.kind()), Some(io::ErrorKind::PermissionDenied));
+        assert!(storage.open_read(Path::new("sub/file")).unwrap().as_file().is_none());
+
+        assert_eq!(storage.resolve_dir(Path::new("./sub")), Some(PathBuf::from("sub")));
+        assert_eq!(storage.resolve_dir(Path::new("sub/file")), None);
+        assert_eq!(storage.resolve_dir(Path::new("")), Some(PathBuf::new()));
+        assert_eq!(storage.list(Path::new("sub")).unwrap(), [OsString::from("file")]);
+        assert_eq!(storage.list(Path::new("")).unwrap(), Vec::<OsString>::new());
+
+        let mut file = storage.open_write(Path::new("upload"), false).unwrap();
+        file.write_all(b"up").unwrap();
+        file.write_all(b"load").unwrap();
+        assert_eq!(storage.get("upload").unwrap(), b"upload");
+        assert_eq!(storage.open_write(Path::new("upload"), false).err().map(|err| err.kind()), Some(io::ErrorKind::AlreadyExists));
+        storage.open_write(Path::new("upload"), true).unwrap().write_all(b"ed").unwrap();
+        assert_eq!(storage.get("upload").unwrap(), b"uploaded");
+        storage.remove(Path::new("upload")).unwrap();
+        assert!(!storage.exists(Path::new("upload")));
+    }
+}
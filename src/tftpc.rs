@@ -3,12 +3,12 @@
  * License: GPL-3+
  */
 
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::{self, Write};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
 
 use getopts::Options;
 
@@ -61,71 +61,15 @@ impl Tftpc {
         }
     }
 
-    fn wait_for_option_ack(&mut self, sock: &UdpSocket) -> Option<SocketAddr> {
-        let mut buf = [0; 512];
-        sock.peek_from(&mut buf).ok()?;
-        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
-        if opcode != rtftp::Opcode::OACK as u16 {
-            return None;
-        }
-
-        let (len, remote) = sock.recv_from(&mut buf).ok()?;
-
-        let mut options = self.tftp.parse_options(&buf[2..len]);
-        self.tftp.init_tftp_options(sock, &mut options).ok()?;
-
-        Some(remote)
-    }
-
-    fn wait_for_response(&self, sock: &UdpSocket, expected_opcode: rtftp::Opcode, expected_block: u16, expected_remote: Option<SocketAddr>) -> Result<Option<SocketAddr>, std::io::Error> {
-        let mut buf = [0; 4];
-        let (len, remote) = sock.peek_from(&mut buf)?;
-
-        if let Some(rem) = expected_remote {
-            /* verify we got a response from the same client that sent
-               an optional previous option ack */
-            if rem != remote {
-                return Ok(None);
-            }
-        }
-
-        let opcode = u16::from_be_bytes([buf[0], buf[1]]);
-        let block_nr = u16::from_be_bytes([buf[2], buf[3]]);
-
-        if opcode == rtftp::Opcode::ERROR as u16 {
-            let mut buf = [0; 512];
-            let len = sock.recv(&mut buf)?;
-            return Err(self.tftp.parse_error(&buf[..len]));
-        }
-
-        /* first data packet is expected to be block 1 */
-        if len != 4 || opcode != expected_opcode as u16 || block_nr != expected_block {
-            return Ok(None);
-        }
-
-        Ok(Some(remote))
+    fn request_options(&self, fsize: u64) -> HashMap<String, String> {
+        let mut options = HashMap::new();
+        options.insert(String::from("blksize"), format!("{}", self.conf.blksize));
+        options.insert(String::from("timeout"), format!("{}", 3));
+        options.insert(String::from("tsize"), format!("{}", fsize));
+        options
     }
 
-    fn append_option_req(&self, buf: &mut Vec<u8>, fsize: u64) {
-        self.tftp.append_option(buf, "blksize", &format!("{}", self.conf.blksize));
-        self.tftp.append_option(buf, "timeout", &format!("{}", 3));
-        self.tftp.append_option(buf, "tsize", &format!("{}", fsize));
-    }
-
-    fn init_req(&self, opcode: rtftp::Opcode, filename: &str, size: u64) -> Vec<u8> {
-        let mut buf = Vec::with_capacity(512);
-        buf.extend((opcode as u16).to_be_bytes().iter());
-        let mode_str = match self.conf.mode {
-            rtftp::Mode::OCTET => "octet",
-            rtftp::Mode::NETASCII => "netascii",
-        };
-        self.tftp.append_option(&mut buf, filename, mode_str);
-        self.append_option_req(&mut buf, size);
-
-        buf
-    }
-
-    fn handle_wrq(&mut self, sock: &UdpSocket) -> Result<String, io::Error> {
+    fn handle_wrq(&mut self) -> Result<String, io::Error> {
         let mut file = File::open(self.conf.filename.as_path())?;
         let err_invalidpath = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid path/filename");
 
@@ -137,69 +81,30 @@ impl Tftpc {
         }
 
         let tsize = self.tftp.transfersize(&mut file)?;
-        let buf = self.init_req(rtftp::Opcode::WRQ, filename, tsize);
+        let options = self.request_options(tsize);
 
-        let mut remote = None;
-        for _ in 1..3 {
-            sock.send_to(&buf, self.conf.remote)?;
-            remote = self.wait_for_option_ack(sock);
-            if remote.is_none() {
-                /* for WRQ either OACK or ACK is replied */
-                remote = self.wait_for_response(sock, rtftp::Opcode::ACK, 0, None)?;
-            }
-            if remote.is_some() {
-                break;
-            }
-        }
-        /* reconnect to remote to communicate from now on with updated port */
-        match remote {
-            Some(r) => sock.connect(r).expect("connecting to remote failed"),
-            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "No response from server")),
-        }
-
-        match self.tftp.send_file(sock, &mut file) {
-            Ok(_) => Ok(format!("Sent {} to {}.", self.conf.filename.display(), self.conf.remote)),
+        match self.tftp.put(self.conf.remote, filename, &mut file, &options) {
+            Ok(size) => Ok(format!("Sent {} ({} bytes) to {}.", self.conf.filename.display(), size, self.conf.remote)),
             Err(err) => {
                 let error = format!("Sending {} to {} failed ({}).", self.conf.filename.display(), self.conf.remote, err);
-                self.tftp.send_error(sock, 0, "Sending error")?;
                 Err(io::Error::new(err.kind(), error))
             }
         }
     }
 
-    fn handle_rrq(&mut self, sock: &UdpSocket) -> Result<String, io::Error> {
+    fn handle_rrq(&mut self) -> Result<String, io::Error> {
         let err_invalidpath = || io::Error::new(io::ErrorKind::InvalidInput, "Invalid path/filename");
         let filename = self.conf.filename.file_name().ok_or_else(err_invalidpath)?;
         let outpath = env::current_dir().expect("Can't get current directory").join(filename);
         let mut file = File::create(outpath)?;
         let filename = self.conf.filename.to_str().ok_or_else(err_invalidpath)?;
 
-        let buf = self.init_req(rtftp::Opcode::RRQ, filename, 0);
-
-        let mut remote = None;
-        for _ in 1..3 {
-            sock.send_to(&buf, self.conf.remote)?;
-            let oack_remote = self.wait_for_option_ack(sock);
-            if let Some(r) = oack_remote {
-                /* for RRQ the received OACKs need to be acked */
-                self.tftp.send_ack_to(sock, r, 0)?;
-            }
-            remote = self.wait_for_response(sock, rtftp::Opcode::DATA, 1, oack_remote)?;
-            if remote.is_some() {
-                break;
-            }
-        }
-        /* reconnect to remote to communicate from now on with updated port */
-        match remote {
-            Some(r) => sock.connect(r).expect("connecting to remote failed"),
-            None => return Err(io::Error::new(io::ErrorKind::TimedOut, "No response from server")),
-        }
+        let options = self.request_options(0);
 
-        match self.tftp.recv_file(sock, &mut file) {
-            Ok(_) => Ok(format!("Received {} from {}.", self.conf.filename.display(), self.conf.remote)),
+        match self.tftp.get(self.conf.remote, filename, &mut file, &options) {
+            Ok(size) => Ok(format!("Received {} ({} bytes) from {}.", self.conf.filename.display(), size, self.conf.remote)),
             Err(err) => {
                 let error = format!("Receiving {} from {} failed ({}).", self.conf.filename.display(), self.conf.remote, err);
-                self.tftp.send_error(sock, 0, "Receiving error")?;
                 Err(std::io::Error::new(err.kind(), error))
             }
         }
@@ -207,12 +112,10 @@ impl Tftpc {
 
     pub fn start(&mut self) {
         self.tftp.set_progress_callback(update_progress);
-        let socket = UdpSocket::bind("[::]:0").expect("binding failed");
-        socket.set_read_timeout(Some(Duration::from_secs(5))).expect("setting socket timeout failed");
 
         let err = match self.conf.operation {
-            Operation::RRQ => self.handle_rrq(&socket),
-            Operation::WRQ => self.handle_wrq(&socket),
+            Operation::RRQ => self.handle_rrq(),
+            Operation::WRQ => self.handle_wrq(),
         };
         match err {
             Ok(msg) => println!("{}", msg),
@@ -4,402 +4,565 @@
  */
 
 use std::env;
-use std::error::Error;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io;
-use std::net::{SocketAddr, UdpSocket};
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::{Path, PathBuf};
+use std::process;
 use std::time::Duration;
 
-use nix::unistd::{chroot, setresgid, setresuid, Gid, Uid, ROOT};
 use getopts::Options;
-use threadpool::ThreadPool;
-
-#[cfg(feature = "landlock")]
-use landlock::{
-    Access, AccessFs, PathBeneath, PathFd, RestrictionStatus, RulesetAttr,
-    RulesetCreatedAttr, RulesetError, RulesetStatus, ABI
-};
-
-#[derive(Clone)]
-struct Configuration {
-    port: u16,
-    uid: u32,
-    gid: u32,
-    ro: bool,
-    wo: bool,
-    threads: usize,
-    dir: PathBuf,
-}
-
-impl Default for Configuration {
-    fn default() -> Configuration {
-        Configuration {
-            port: 69,
-            uid: 65534,
-            gid: 65534,
-            ro: false,
-            wo: false,
-            threads: 2,
-            dir: env::current_dir().expect("Can't get current directory"),
-        }
-    }
-}
 
-#[derive(Clone)]
-struct Tftpd {
-    tftp: rtftp::Tftp,
-    conf: Configuration,
+use rtftp::server::{ConfigError, Configuration, ConfigurationBuilder, Tftpd};
+
+/* exit codes */
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+/// Why [`parse_commandline`] did not return a configuration.
+#[derive(Debug)]
+enum CommandLineError {
+    /// `--help` or `--version` was given; the text to print
+    Info(String),
+    /// an unknown option, or an option value that does not parse
+    Usage(String),
+    /// the configuration file can't be read or parsed
+    ConfigFile(String),
+    /// the settings don't form a valid configuration
+    Invalid(ConfigError),
 }
 
-impl Tftpd {
-    pub fn new(conf: Configuration) -> Tftpd {
-        Tftpd {
-            tftp: rtftp::Tftp::new(),
-            conf,
+impl CommandLineError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            CommandLineError::Info(_) => 0,
+            CommandLineError::ConfigFile(_) => EXIT_FAILURE,
+            CommandLineError::Usage(_) | CommandLineError::Invalid(_) => EXIT_USAGE,
         }
     }
+}
 
-    fn file_allowed(&self, filename: &Path) -> Option<PathBuf> {
-        if self.conf.dir == PathBuf::from("/") {
-            /* running either chrooted in requested directory,
-               or whole root is being served */
-            return Some(filename.to_path_buf());
-        }
-
-        /* get parent to check dir where file should be read/written */
-        let path = self.conf.dir.join(filename)
-                                .parent()?
-                                .canonicalize()
-                                .ok()?;
-
-        /* check last component of given filename appended to canonicalized path */
-        match path.join(filename.file_name()?).strip_prefix(&self.conf.dir) {
-            Ok(p) if p != PathBuf::new() => Some(p.to_path_buf()),
-            _ => None,
+impl fmt::Display for CommandLineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandLineError::Info(text) | CommandLineError::Usage(text) | CommandLineError::ConfigFile(text) => write!(f, "{}", text),
+            CommandLineError::Invalid(err) => write!(f, "{}", err),
         }
     }
+}
 
-    fn handle_wrq(&mut self, socket: &UdpSocket, cl: &SocketAddr, buf: &[u8]) -> Result<String, io::Error> {
-        let (filename, mode, mut options) = self.tftp.parse_file_mode_options(buf)?;
-        self.tftp.init_tftp_options(socket, &mut options)?;
+fn usage(opts: &Options, program: &str) -> String {
+    let version = rtftp::VERSION.unwrap_or("");
+    opts.usage(format!("RusTFTP {}\n\n{} [options] [directory]", version, program).as_str())
+}
 
-        match mode.as_ref() {
-            "octet" => self.tftp.set_mode(rtftp::Mode::OCTET),
-            "netascii" => self.tftp.set_mode(rtftp::Mode::NETASCII),
-            _ => {
-                self.tftp.send_error(socket, 0, "Unsupported mode")?;
-                return Err(io::Error::new(io::ErrorKind::Other, "unsupported mode"));
-            }
-        }
+fn version() -> String {
+    format!("RusTFTP {}\nSupported options: {}", rtftp::VERSION.unwrap_or("(unknown version)"),
+            rtftp::SUPPORTED_OPTIONS.join(", "))
+}
 
-        let path = match self.file_allowed(&filename) {
-            Some(p) => p,
-            None => {
-                let err = format!("Receiving {} from {} failed (permission check failed).", filename.display(), cl);
-                self.tftp.send_error(socket, 2, "Permission denied")?;
-                return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
-            }
-        };
+fn config_error(key: &str) -> String {
+    format!("invalid value for '{}'", key)
+}
 
-        let mut file = match OpenOptions::new().write(true).create_new(true).open(&path) {
-            Ok(f) => f,
-            Err(ref err) if err.kind() == io::ErrorKind::AlreadyExists => {
-                let error = format!("Receiving {} from {} failed ({}).", path.display(), cl, err);
-                self.tftp.send_error(socket, 6, "File already exists")?;
-                return Err(io::Error::new(err.kind(), error));
-            }
-            Err(err) => {
-                let error = format!("Receiving {} from {} failed ({}).", path.display(), cl, err);
-                self.tftp.send_error(socket, 6, "Permission denied")?;
-                return Err(io::Error::new(io::ErrorKind::PermissionDenied, error));
-            }
-        };
+fn config_int<T: TryFrom<i64>>(key: &str, val: &toml::Value) -> Result<T, String> {
+    val.as_integer().and_then(|v| T::try_from(v).ok()).ok_or_else(|| config_error(key))
+}
 
-        self.tftp.ack_options(socket, &options, false)?;
-        match self.tftp.recv_file(socket, &mut file) {
-            Ok(_) => Ok(format!("Received {} from {}.", path.display(), cl)),
-            Err(ref err) => {
-                let error = format!("Receiving {} from {} failed ({}).", path.display(), cl, err);
-                self.tftp.send_error(socket, 0, "Receiving error")?;
-                Err(io::Error::new(err.kind(), error))
-            }
-        }
-    }
+fn config_bool(key: &str, val: &toml::Value) -> Result<bool, String> {
+    val.as_bool().ok_or_else(|| config_error(key))
+}
 
-    fn handle_rrq(&mut self, socket: &UdpSocket, cl: &SocketAddr, buf: &[u8]) -> Result<String, io::Error> {
-        let (filename, mode, mut options) = self.tftp.parse_file_mode_options(buf)?;
-        self.tftp.init_tftp_options(socket, &mut options)?;
+fn config_str_list<'a>(key: &str, val: &'a toml::Value) -> Result<Vec<&'a str>, String> {
+    val.as_array().ok_or_else(|| config_error(key))?
+       .iter().map(|v| config_str(key, v)).collect()
+}
 
-        match mode.as_ref() {
-            "octet" => self.tftp.set_mode(rtftp::Mode::OCTET),
-            "netascii" => self.tftp.set_mode(rtftp::Mode::NETASCII),
-            _ => {
-                self.tftp.send_error(socket, 0, "Unsupported mode")?;
-                return Err(io::Error::new(io::ErrorKind::Other, "unsupported mode"));
-            }
-        }
+fn config_str<'a>(key: &str, val: &'a toml::Value) -> Result<&'a str, String> {
+    val.as_str().ok_or_else(|| config_error(key))
+}
 
-        let path = match self.file_allowed(&filename) {
-            Some(p) => p,
-            None => {
-                let err = format!("Sending {} to {} failed (permission check failed).", filename.display(), cl);
-                self.tftp.send_error(socket, 2, "Permission denied")?;
-                return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
-            }
-        };
+/// Resolves `HOST:PORT` to its first address.
+fn resolve_addr(addr: &str) -> Result<SocketAddr, String> {
+    addr.to_socket_addrs().map_err(|err| format!("{}: {}", addr, err))?
+        .next().ok_or_else(|| format!("{}: no address found", addr))
+}
 
-        let mut file = match File::open(&path) {
-            Ok(f) => f,
-            Err(ref error) if error.kind() == io::ErrorKind::NotFound => {
-                let err = format!("Sending {} to {} failed ({}).", path.display(), cl, error);
-                self.tftp.send_error(socket, 1, "File not found")?;
-                return Err(io::Error::new(io::ErrorKind::NotFound, err));
-            }
-            Err(error) => {
-                let err = format!("Sending {} to {} failed ({}).", path.display(), cl, error);
-                self.tftp.send_error(socket, 2, "Permission denied")?;
-                return Err(io::Error::new(io::ErrorKind::PermissionDenied, err));
+/// Applies the settings of a TOML configuration to `builder`.
+/// Keys are named like the long command-line options.
+/// Returns the keys that were not recognized.
+fn apply_config(builder: &mut ConfigurationBuilder, content: &str) -> Result<Vec<String>, String> {
+    let value: toml::Value = content.parse().map_err(|err: toml::de::Error| err.to_string())?;
+    let table = value.as_table().ok_or("configuration is not a table")?;
+
+    let mut unknown = Vec::new();
+    for (key, val) in table {
+        match key.as_str() {
+            "port" => builder.port(config_int(key, val)?),
+            "uid" => builder.uid(config_int(key, val)?),
+            "gid" => builder.gid(config_int(key, val)?),
+            "read-only" => builder.read_only(config_bool(key, val)?),
+            "write-only" => builder.write_only(config_bool(key, val)?),
+            "threads" => builder.threads(config_int(key, val)?),
+            "verbose" => builder.verbose(config_bool(key, val)?),
+            "quiet" => builder.quiet(config_bool(key, val)?),
+            "directory" => builder.dir(config_str(key, val)?),
+            "read-dir" => builder.read_dir(config_str(key, val)?),
+            "write-dir" => builder.write_dir(config_str(key, val)?),
+            "chroot" => builder.chroot(config_bool(key, val)?),
+            "require-drop" => builder.require_drop(config_bool(key, val)?),
+            "require-writable" => builder.require_writable(config_bool(key, val)?),
+            "reuse-port" => builder.reuse_port(config_bool(key, val)?),
+            "raise-nofile" => builder.raise_nofile(config_bool(key, val)?),
+            "v6only" => builder.v6only(config_bool(key, val)?),
+            "rcvbuf" => builder.rcvbuf(config_int(key, val)?),
+            "sndbuf" => builder.sndbuf(config_int(key, val)?),
+            "dscp" => builder.dscp(config_int(key, val)?),
+            "process-name" => builder.process_name(config_str(key, val)?),
+            "max-queue" => builder.max_queue(config_int(key, val)?),
+            "single-port" => builder.single_port(config_bool(key, val)?),
+            "fork-per-transfer" => builder.fork_per_transfer(config_bool(key, val)?),
+            "client-rate" => builder.client_rate(config_int(key, val)?),
+            "allow-ext" => {
+                for ext in config_str_list(key, val)? {
+                    builder.allow_ext(ext);
+                }
+                continue;
             }
-        };
-        if !file.metadata()?.is_file() {
-            self.tftp.send_error(socket, 1, "File not found")?;
-            return Err(io::Error::new(io::ErrorKind::NotFound, "file not found"));
-        }
-
-        if let Some(opt) = options.get_mut("tsize") {
-            *opt = self.tftp.transfersize(&mut file)?.to_string();
-        }
-        self.tftp.ack_options(socket, &options, true)?;
-        match self.tftp.send_file(socket, &mut file) {
-            Ok(_) => Ok(format!("Sent {} to {}.", path.display(), cl)),
-            Err(err) => {
-                let error = format!("Sending {} to {} failed ({}).", path.display(), cl, err);
-                Err(std::io::Error::new(err.kind(), error))
+            "allow-from" => {
+                for rule in config_str_list(key, val)? {
+                    builder.allow_from(rule.parse()?);
+                }
+                continue;
             }
-        }
-    }
-
-    pub fn handle_client(&mut self, cl: &SocketAddr, buf: &[u8]) -> Result<String, io::Error> {
-        let socket = UdpSocket::bind("[::]:0")?;
-        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
-        socket.connect(cl)?;
-
-        if buf.len() < 2 {
-            self.tftp.send_error(&socket, 0, "Invalid request length")?;
-            return Err(io::Error::new(io::ErrorKind::Other, "invalid request length"));
-        }
-
-        match u16::from_be_bytes([buf[0], buf[1]]) {  // opcode
-            o if o == rtftp::Opcode::RRQ as u16 => {
-                if self.conf.wo {
-                    self.tftp.send_error(&socket, 4, "reading not allowed")?;
-                    Err(io::Error::new(io::ErrorKind::Other, "unallowed mode"))
-                } else {
-                    self.handle_rrq(&socket, cl, &buf[2..])
+            "deny-ext" => {
+                for ext in config_str_list(key, val)? {
+                    builder.deny_ext(ext);
                 }
+                continue;
             }
-            o if o == rtftp::Opcode::WRQ as u16 => {
-                if self.conf.ro {
-                    self.tftp.send_error(&socket, 4, "writing not allowed")?;
-                    Err(io::Error::new(io::ErrorKind::Other, "unallowed mode"))
-                } else {
-                    self.handle_wrq(&socket, cl, &buf[2..])
+            "max-rrq-size" => builder.max_rrq_size(config_int(key, val)?),
+            "modes" => builder.modes(&config_str_list(key, val)?),
+            "default-mode" => builder.default_mode(config_str(key, val)?),
+            "force-blksize" => builder.force_blksize(config_int(key, val)?),
+            "include" => {
+                for path in config_str_list(key, val)? {
+                    builder.include(path);
                 }
+                continue;
             }
-            o if o == rtftp::Opcode::ERROR as u16 => Ok(format!("Received ERROR from {}", cl)),
+            "no-dotfiles" => builder.no_dotfiles(config_bool(key, val)?),
+            "no-backslashes" => builder.no_backslashes(config_bool(key, val)?),
+            "translate-backslash" => builder.translate_backslash(config_bool(key, val)?),
+            "utf8-filenames" => builder.utf8_filenames(config_bool(key, val)?),
+            "max-filename-len" => builder.max_filename_len(config_int(key, val)?),
+            "enable-listing" => builder.enable_listing(config_bool(key, val)?),
+            "index" => builder.index(config_str(key, val)?),
+            "map-all" => builder.map_all(config_str(key, val)?),
+            "cache-size" => builder.cache_size(config_int(key, val)?),
+            "mmap" => builder.mmap(config_bool(key, val)?),
+            "allow-special" => builder.allow_special(config_bool(key, val)?),
+            "allow-gzip" => builder.allow_gzip(config_bool(key, val)?),
+            "allow-offset" => builder.allow_offset(config_bool(key, val)?),
+            "total-rate" => builder.total_rate(config_int(key, val)?),
+            "handshake-timeout" => builder.handshake_timeout(Duration::from_secs(config_int(key, val)?)),
+            "transfer-timeout" => builder.transfer_timeout(Duration::from_secs(config_int(key, val)?)),
+            "backoff-multiplier" => builder.backoff_multiplier(config_int(key, val)?),
+            "max-backoff" => builder.max_backoff(Duration::from_secs(config_int(key, val)?)),
+            "duplicate-window" => builder.duplicate_window(Duration::from_secs(config_int(key, val)?)),
+            "drop-invalid" => builder.drop_invalid(config_bool(key, val)?),
+            "idle-exit" => builder.idle_exit(Duration::from_secs(config_int(key, val)?)),
+            "serve-once" => builder.serve_once(config_bool(key, val)?),
+            "shutdown-timeout" => builder.shutdown_timeout(Duration::from_secs(config_int(key, val)?)),
+            "append" => builder.append(config_bool(key, val)?),
+            "sparse" => builder.sparse(config_bool(key, val)?),
+            "fsync" => builder.fsync(config_bool(key, val)?),
+            "preallocate" => builder.preallocate(config_bool(key, val)?),
+            "per-client-subdir" => builder.per_client_subdir(config_bool(key, val)?),
+            "per-client-config" => builder.per_client_config(config_bool(key, val)?),
+            "on-upload" => builder.on_upload(config_str(key, val)?),
+            "on-upload-delete" => builder.on_upload_delete(config_bool(key, val)?),
+            "mirror" => builder.mirror(resolve_addr(config_str(key, val)?).map_err(|_| config_error(key))?),
+            "strict-mirror" => builder.strict_mirror(config_bool(key, val)?),
+            "access-log" => builder.access_log(config_str(key, val)?),
+            "log-format" => builder.log_format(config_str(key, val)?),
+            "manifest" => builder.manifest(config_str(key, val)?),
+            "control-socket" => builder.control_socket(config_str(key, val)?),
+            "metrics-addr" => builder.metrics_addr(config_str(key, val)?.parse().map_err(|_| config_error(key))?),
             _ => {
-                self.tftp.send_error(&socket, 4, "Unexpected opcode")?;
-                Err(io::Error::new(io::ErrorKind::Other, "unexpected opcode"))
+                unknown.push(key.clone());
+                continue;
             }
-        }
-    }
-
-    fn drop_privs(&self, uid: u32, gid: u32) -> Result<(), Box<dyn Error>> {
-        let root_uid = ROOT;
-        let root_gid = Gid::from_raw(0);
-        let unpriv_uid = Uid::from_raw(uid);
-        let unpriv_gid = Gid::from_raw(gid);
-
-        if Gid::current() != root_gid
-            && Gid::effective() != root_gid
-            && Uid::current() != root_uid
-            && Uid::effective() != root_uid
-        {
-            /* already unprivileged user */
-            return Ok(());
-        }
-
-        if Gid::current() == root_gid || Gid::effective() == root_gid {
-            setresgid(unpriv_gid, unpriv_gid, unpriv_gid)?;
-        }
-
-        if Uid::current() == root_uid || Uid::effective() == root_uid {
-            setresuid(unpriv_uid, unpriv_uid, unpriv_uid)?;
-        }
-
-        Ok(())
-    }
-
-    fn chroot_destdir(&mut self) -> Result<(), nix::Error> {
-        /* chroot will only succeed if we have required permissions;
-           either running as root or having CAP_SYS_CHROOT.
-           propagate error only if chroot should have succeeded. */
-        match chroot(&self.conf.dir) {
-            Ok(_) => {
-                /* configured dir is now new root directory */
-                self.conf.dir = PathBuf::from("/");
-                Ok(())
-            },
-            Err(err) if err == nix::errno::Errno::EPERM => Ok(()),
-            Err(err) if Uid::effective() == ROOT => Err(err),
-            Err(_) => Ok(()),
-        }
-    }
-
-    #[cfg(feature = "landlock")]
-    fn restrict_filesystem(&self) {
-        let abi = ABI::V1;
-        let access_all = AccessFs::from_all(abi);
-        let access_read = AccessFs::from_read(abi);
-        let access_write = AccessFs::from_write(abi);
-
-        let pathfd = PathFd::new(&self.conf.dir).expect("Directory can't be opened");
-
-        let access = if self.conf.ro {
-            access_read
-        } else if self.conf.wo {
-            access_write
-        } else {
-            access_all
         };
-
-        let restrict = || -> Result<RestrictionStatus, RulesetError> {
-            landlock::Ruleset::new()
-                    .handle_access(access_all)?
-                    .create()?
-                    .add_rule(PathBeneath::new(pathfd, access))?
-                    .restrict_self()
-        };
-
-        let status = restrict().expect("Setting up landlock restriction failed");
-        if status.ruleset != RulesetStatus::FullyEnforced {
-            eprintln!("Landlock restrictions not (fully) applied (maybe kernel too old?).");
-        }
-    }
-
-    pub fn start(&mut self) {
-        let socket = match UdpSocket::bind(format!("[::]:{}", self.conf.port)) {
-            Ok(s) => s,
-            Err(err) => {
-                eprintln!("Binding a socket failed: {}", err);
-                return;
-            }
-        };
-
-        #[cfg(feature = "landlock")]
-        self.restrict_filesystem();
-
-        match self.chroot_destdir() {
-            Ok(_) => {},
-            Err(err) => {
-                eprintln!("Changing root directory failed ({}).", err);
-                return;
-            }
-        }
-        match self.drop_privs(self.conf.uid, self.conf.gid) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Dropping privileges failed: {}", err);
-                return;
-            }
-        };
-
-        match env::set_current_dir(&self.conf.dir) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Changing directory failed ({}).", err);
-                return;
-            }
-        }
-
-        let pool = ThreadPool::new(self.conf.threads);
-        loop {
-            let mut buf = [0; 2048];
-            let (n, src) = match socket.recv_from(&mut buf) {
-                Ok(args) => args,
-                Err(err) => {
-                    eprintln!("Receiving data from socket failed: {}", err);
-                    break;
-                }
-            };
-
-            let mut worker = self.clone();
-            pool.execute(move || {
-                match worker.handle_client(&src, &buf[0..n]) {
-                    Ok(msg) => println!("{}", msg),
-                    Err(err) => println!("{}", err),
-                }
-            });
-        }
     }
+    Ok(unknown)
 }
 
-fn usage(opts: &Options, program: &str, error: Option<String>) {
-    if let Some(err) = error {
-        println!("{}\n", err);
+fn load_config(builder: &mut ConfigurationBuilder, path: &Path) -> Result<(), String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Reading {} failed: {}", path.display(), err))?;
+    let unknown = apply_config(builder, &content).map_err(|err| format!("Parsing {} failed: {}", path.display(), err))?;
+    for key in unknown {
+        eprintln!("Ignoring unknown key '{}' in {}", key, path.display());
     }
-    let version = rtftp::VERSION.unwrap_or("");
-    println!("{}", opts.usage(format!("RusTFTP {}\n\n{} [options] [directory]", version, program).as_str()));
+    Ok(())
 }
 
-fn parse_commandline(args: &[String]) -> Option<Configuration> {
-    let program = args[0].clone();
-    let mut conf: Configuration = Default::default();
+fn options() -> Options {
+    let defaults: Configuration = Default::default();
     let mut opts = Options::new();
     opts.optflag("h", "help", "display usage information");
-    opts.optopt("p", "port", format!("port to listen on (default: {})", conf.port).as_ref(), "PORT");
-    opts.optopt("u", "uid", format!("user id to run as (default: {})", conf.uid).as_ref(), "UID");
-    opts.optopt("g", "gid", format!("group id to run as (default: {})", conf.gid).as_ref(), "GID");
+    opts.optflag("V", "version", "display version and supported TFTP options");
+    opts.optopt("p", "port", format!("port to listen on (default: {})", defaults.port()).as_ref(), "PORT");
+    opts.optopt("u", "uid", format!("user id to run as (default: {})", defaults.uid()).as_ref(), "UID");
+    opts.optopt("g", "gid", format!("group id to run as (default: {})", defaults.gid()).as_ref(), "GID");
     opts.optflag("r", "read-only", "allow only reading/downloading of files (RRQ)");
     opts.optflag("w", "write-only", "allow only writing/uploading of files (WRQ)");
-    opts.optopt("t", "threads", format!("number of worker threads (default: {})", conf.threads).as_ref(), "N");
+    opts.optopt("t", "threads", format!("number of worker threads (default: {})", defaults.threads()).as_ref(), "N");
+    opts.optflag("v", "verbose", "print debugging output");
+    opts.optflag("q", "quiet", "log only failures, not successful transfers");
+    opts.optopt("", "read-dir", "read files from this directory instead of the served directory", "DIR");
+    opts.optopt("", "write-dir", "write uploads into this directory instead of the served directory", "DIR");
+    opts.optflag("", "chroot", "refuse to start if changing root into the directory fails (requires root)");
+    opts.optflag("", "require-drop", "refuse to start if switching to the user and group id fails");
+    opts.optflag("", "require-writable", "refuse to start if the directory for uploads is not writable");
+    opts.optflag("", "reuse-port", "allow other processes to listen on the same port (SO_REUSEPORT)");
+    opts.optflag("", "raise-nofile", "raise the limit of open files to the hard limit (RLIMIT_NOFILE)");
+    opts.optflag("", "v6only", "accept only IPv6 clients (IPV6_V6ONLY) instead of IPv6 and IPv4");
+    opts.optopt("", "rcvbuf", "size of the socket receive buffers (SO_RCVBUF)", "BYTES");
+    opts.optopt("", "sndbuf", "size of the socket send buffers (SO_SNDBUF)", "BYTES");
+    opts.optopt("", "dscp", "mark transfer packets with this DSCP class (0-63)", "VALUE");
+    opts.optopt("", "process-name", "name of the process shown by ps and top (up to 15 bytes)", "NAME");
+    opts.optopt("", "max-queue", "answer requests with \"Server busy\" while this many wait for a worker", "N");
+    opts.optflag("", "single-port", "run all transfers over the listening socket instead of a new port per transfer");
+    opts.optflag("", "fork-per-transfer", "run each transfer in a child process instead of a worker thread");
+    opts.optopt("", "client-rate", "maximum number of requests per second from a client address", "N");
+    opts.optmulti("", "allow-ext", "serve only files with this extension (can be repeated)", "EXT");
+    opts.optmulti("", "deny-ext", "refuse files with this extension (can be repeated)", "EXT");
+    opts.optopt("", "max-rrq-size", "refuse downloads of files larger than this", "BYTES");
+    opts.optmulti("", "allow-from", "allow only these operations (read, write or rw) from this subnet, e.g. read:192.0.2.0/24 (can be repeated)", "OPS:CIDR");
+    opts.optopt("", "modes", "comma-separated transfer modes to accept (default: octet,netascii)", "MODES");
+    opts.optopt("", "default-mode", "assume this mode for requests without a valid one instead of rejecting them", "MODE");
+    opts.optopt("", "force-blksize", "negotiate this block size also with clients not requesting one (non-standard)", "SIZE");
+    opts.optmulti("", "include", "also allow reading this file or directory outside of the served directory (can be repeated)", "PATH");
+    opts.optflag("", "no-dotfiles", "refuse paths with a component starting with a dot");
+    opts.optflag("", "allow-backslashes", "accept backslashes as part of file names");
+    opts.optflag("", "translate-backslash", "treat backslashes in file names as directory separators");
+    opts.optflag("", "utf8-filenames", "refuse file names that are not valid UTF-8");
+    opts.optopt("", "max-filename-len", "refuse requested file names longer than this many bytes (default: 255)", "N");
+    opts.optflag("", "enable-listing", "answer read requests for directories with a list of their files");
+    opts.optopt("", "index", "serve this file of a directory when the directory is requested", "FILENAME");
+    opts.optopt("", "map-all", "serve this file for every read request, whatever name is requested", "FILE");
+    opts.optopt("", "cache-size", "keep up to this much recently served file content in memory", "BYTES");
+    opts.optflag("", "mmap", "read served files through memory mappings");
+    opts.optflag("", "allow-special", "allow transfers from and to FIFOs and device nodes");
+    opts.optflag("", "allow-gzip", "serve files compressed when requested with .gz appended to their name");
+    opts.optflag("", "allow-offset", "let clients resume downloads with the non-standard offset option");
+    opts.optopt("", "access-log", "append a JSON line for each transfer to this file", "FILE");
+    opts.optopt("", "log-format", "log each transfer with this template", "TEMPLATE");
+    opts.optopt("", "manifest", "serve only files listed with their SHA-256 checksum in this file", "FILE");
+    opts.optopt("", "metrics-addr", "serve transfer metrics over HTTP on this address", "ADDR:PORT");
+    opts.optopt("", "control-socket", "accept drain, resume and status commands on this Unix socket", "PATH");
+    opts.optopt("", "total-rate", "limit the bandwidth of all transfers together", "BYTES_PER_SEC");
+    opts.optopt("", "handshake-timeout", "wait this long for a client to answer the first packet of a transfer", "SECONDS");
+    opts.optopt("", "transfer-timeout", "abort transfers taking longer than this in total", "SECONDS");
+    opts.optopt("", "backoff-multiplier", "multiply the timeout by N with each retransmission of a packet", "N");
+    opts.optopt("", "max-backoff", "wait at most this long for an answer when backing off", "SECONDS");
+    opts.optopt("", "duplicate-window", format!("ignore repeated requests of a client for this long while serving the first (default: {}, 0 disables)", defaults.duplicate_window().as_secs()).as_ref(), "SECONDS");
+    opts.optflag("", "drop-invalid", "silently drop datagrams on the listening port that are no read or write request");
+    opts.optopt("", "idle-exit", "exit when no request arrived and no transfer ran for this long", "SECONDS");
+    opts.optflag("", "serve-once", "exit after the first successful transfer, serving one client at a time");
+    opts.optopt("", "shutdown-timeout", "when stopping, wait at most this long for running transfers to finish", "SECONDS");
+    opts.optflag("", "append", "append uploads to existing files");
+    opts.optflag("", "sparse", "leave holes for runs of zeros in uploads");
+    opts.optflag("", "fsync", "write uploads to disk before acknowledging their last block");
+    opts.optflag("", "preallocate", "allocate uploads with the size announced by tsize");
+    opts.optflag("", "per-client-subdir", "place uploads in a subdirectory named after the client address");
+    opts.optflag("", "per-client-config", "prefer files in a subdirectory named after the client address for downloads");
+    opts.optopt("", "on-upload", "run CMD with the path of each received file as argument", "CMD");
+    opts.optflag("", "on-upload-delete", "delete received files if the --on-upload command fails");
+    opts.optopt("", "mirror", "upload received files to this TFTP server as well", "HOST:PORT");
+    opts.optflag("", "strict-mirror", "delete received files and fail their upload if mirroring fails");
+    opts.optopt("c", "config", "load settings from a TOML file; options given on the command line take precedence", "FILE");
+
+    opts
+}
+
+/// Builds the configuration from the command line and the configuration file
+/// given there. When reloading, relative paths of the command line are
+/// resolved against `cwd`, the working directory at startup, and `dir`
+/// replaces the served directory.
+fn parse_commandline(args: &[String], cwd: Option<&Path>, dir: Option<&Path>) -> Result<Configuration, CommandLineError> {
+    let opts = options();
+    let mut builder = ConfigurationBuilder::new();
 
-    let getopts_fail = |err: getopts::Fail| { usage(&opts, &program, Some(err.to_string())) };
-    let conv_error = |err: std::num::ParseIntError| { usage(&opts, &program, Some(err.to_string())) };
+    let getopts_fail = |err: getopts::Fail| CommandLineError::Usage(err.to_string());
+    let conv_error = |err: std::num::ParseIntError| CommandLineError::Usage(err.to_string());
+    let addr_error = |err: std::net::AddrParseError| CommandLineError::Usage(err.to_string());
 
-    let matches = opts.parse(&args[1..]).map_err(getopts_fail).ok()?;
+    let matches = opts.parse(&args[1..]).map_err(getopts_fail)?;
     if matches.opt_present("h") {
-        usage(&opts, &program, None);
-        return None;
+        return Err(CommandLineError::Info(usage(&opts, &args[0])));
+    }
+    if matches.opt_present("V") {
+        return Err(CommandLineError::Info(version()));
     }
 
-    conf.port = matches.opt_get_default("p", conf.port).map_err(conv_error).ok()?;
-    conf.uid = matches.opt_get_default("u", conf.uid).map_err(conv_error).ok()?;
-    conf.gid = matches.opt_get_default("g", conf.gid).map_err(conv_error).ok()?;
-    conf.threads = matches.opt_get_default("t", conf.threads).map_err(conv_error).ok()?;
-    conf.ro = matches.opt_present("r");
-    conf.wo = matches.opt_present("w");
-    if conf.ro && conf.wo {
-        usage(&opts, &program, Some(String::from("Only one of r (read-only) and w (write-only) allowed")));
-        return None;
+    let resolve = |path: &str| cwd.map_or_else(|| PathBuf::from(path), |cwd| cwd.join(path));
+
+    if let Some(path) = matches.opt_str("c") {
+        let path = resolve(&path);
+        load_config(&mut builder, &path).map_err(CommandLineError::ConfigFile)?;
+        builder.config_file(fs::canonicalize(&path).unwrap_or(path));
+    }
+
+    if let Some(port) = matches.opt_get("p").map_err(conv_error)? {
+        builder.port(port);
+    }
+    if let Some(uid) = matches.opt_get("u").map_err(conv_error)? {
+        builder.uid(uid);
+    }
+    if let Some(gid) = matches.opt_get("g").map_err(conv_error)? {
+        builder.gid(gid);
+    }
+    if let Some(threads) = matches.opt_get("t").map_err(conv_error)? {
+        builder.threads(threads);
+    }
+    if let Some(size) = matches.opt_get("rcvbuf").map_err(conv_error)? {
+        builder.rcvbuf(size);
+    }
+    if let Some(size) = matches.opt_get("sndbuf").map_err(conv_error)? {
+        builder.sndbuf(size);
+    }
+    if let Some(dscp) = matches.opt_get("dscp").map_err(conv_error)? {
+        builder.dscp(dscp);
+    }
+    if let Some(name) = matches.opt_str("process-name") {
+        builder.process_name(&name);
+    }
+    if let Some(max) = matches.opt_get("max-queue").map_err(conv_error)? {
+        builder.max_queue(max);
+    }
+    if let Some(rate) = matches.opt_get("client-rate").map_err(conv_error)? {
+        builder.client_rate(rate);
+    }
+    if let Some(path) = matches.opt_str("read-dir") {
+        builder.read_dir(resolve(&path));
+    }
+    if let Some(path) = matches.opt_str("write-dir") {
+        builder.write_dir(resolve(&path));
+    }
+    if let Some(path) = matches.opt_str("map-all") {
+        builder.map_all(path);
+    }
+    if let Some(path) = matches.opt_str("access-log") {
+        builder.access_log(path);
+    }
+    if let Some(template) = matches.opt_str("log-format") {
+        builder.log_format(&template);
+    }
+    if let Some(path) = matches.opt_str("manifest") {
+        builder.manifest(path);
+    }
+    if let Some(addr) = matches.opt_get("metrics-addr").map_err(addr_error)? {
+        builder.metrics_addr(addr);
+    }
+    if let Some(path) = matches.opt_str("control-socket") {
+        builder.control_socket(path);
+    }
+    if let Some(rate) = matches.opt_get("total-rate").map_err(conv_error)? {
+        builder.total_rate(rate);
+    }
+    if let Some(secs) = matches.opt_get("handshake-timeout").map_err(conv_error)? {
+        builder.handshake_timeout(Duration::from_secs(secs));
+    }
+    if let Some(secs) = matches.opt_get("transfer-timeout").map_err(conv_error)? {
+        builder.transfer_timeout(Duration::from_secs(secs));
+    }
+    if let Some(multiplier) = matches.opt_get("backoff-multiplier").map_err(conv_error)? {
+        builder.backoff_multiplier(multiplier);
+    }
+    if let Some(secs) = matches.opt_get("max-backoff").map_err(conv_error)? {
+        builder.max_backoff(Duration::from_secs(secs));
+    }
+    if let Some(secs) = matches.opt_get("duplicate-window").map_err(conv_error)? {
+        builder.duplicate_window(Duration::from_secs(secs));
+    }
+    if matches.opt_present("drop-invalid") {
+        builder.drop_invalid(true);
+    }
+    if let Some(secs) = matches.opt_get("idle-exit").map_err(conv_error)? {
+        builder.idle_exit(Duration::from_secs(secs));
+    }
+    if matches.opt_present("serve-once") {
+        builder.serve_once(true);
+    }
+    if let Some(secs) = matches.opt_get("shutdown-timeout").map_err(conv_error)? {
+        builder.shutdown_timeout(Duration::from_secs(secs));
+    }
+    if matches.opt_present("append") {
+        builder.append(true);
+    }
+    if matches.opt_present("sparse") {
+        builder.sparse(true);
+    }
+    if matches.opt_present("fsync") {
+        builder.fsync(true);
+    }
+    if matches.opt_present("preallocate") {
+        builder.preallocate(true);
+    }
+    if matches.opt_present("per-client-subdir") {
+        builder.per_client_subdir(true);
+    }
+    if matches.opt_present("per-client-config") {
+        builder.per_client_config(true);
+    }
+    if let Some(command) = matches.opt_str("on-upload") {
+        builder.on_upload(&command);
+    }
+    if matches.opt_present("on-upload-delete") {
+        builder.on_upload_delete(true);
+    }
+    if let Some(addr) = matches.opt_str("mirror") {
+        builder.mirror(resolve_addr(&addr).map_err(CommandLineError::Usage)?);
+    }
+    if matches.opt_present("strict-mirror") {
+        builder.strict_mirror(true);
+    }
+    for ext in matches.opt_strs("allow-ext") {
+        builder.allow_ext(ext);
+    }
+    for rule in matches.opt_strs("allow-from") {
+        let rule = rule.parse().map_err(CommandLineError::Usage)?;
+        builder.allow_from(rule);
+    }
+    for ext in matches.opt_strs("deny-ext") {
+        builder.deny_ext(ext);
+    }
+    if let Some(size) = matches.opt_get("max-rrq-size").map_err(conv_error)? {
+        builder.max_rrq_size(size);
+    }
+    if let Some(modes) = matches.opt_str("modes") {
+        builder.modes(&modes.split(',').map(str::trim).collect::<Vec<_>>());
+    }
+    if let Some(mode) = matches.opt_str("default-mode") {
+        builder.default_mode(&mode);
+    }
+    if let Some(blksize) = matches.opt_get("force-blksize").map_err(conv_error)? {
+        builder.force_blksize(blksize);
+    }
+    for path in matches.opt_strs("include") {
+        builder.include(path);
+    }
+    if matches.opt_present("no-dotfiles") {
+        builder.no_dotfiles(true);
+    }
+    if matches.opt_present("allow-backslashes") {
+        builder.no_backslashes(false);
+    }
+    if matches.opt_present("translate-backslash") {
+        builder.translate_backslash(true);
+    }
+    if matches.opt_present("utf8-filenames") {
+        builder.utf8_filenames(true);
+    }
+    if let Some(len) = matches.opt_get("max-filename-len").map_err(conv_error)? {
+        builder.max_filename_len(len);
+    }
+    if matches.opt_present("enable-listing") {
+        builder.enable_listing(true);
+    }
+    if let Some(name) = matches.opt_str("index") {
+        builder.index(&name);
+    }
+    if let Some(size) = matches.opt_get("cache-size").map_err(conv_error)? {
+        builder.cache_size(size);
+    }
+    if matches.opt_present("mmap") {
+        builder.mmap(true);
+    }
+    if matches.opt_present("allow-special") {
+        builder.allow_special(true);
+    }
+    if matches.opt_present("allow-gzip") {
+        builder.allow_gzip(true);
+    }
+    if matches.opt_present("allow-offset") {
+        builder.allow_offset(true);
+    }
+    if matches.opt_present("r") {
+        builder.read_only(true);
+    }
+    if matches.opt_present("w") {
+        builder.write_only(true);
+    }
+    if matches.opt_present("v") {
+        builder.verbose(true);
+    }
+    if matches.opt_present("q") {
+        builder.quiet(true);
+    }
+    if matches.opt_present("chroot") {
+        builder.chroot(true);
+    }
+    if matches.opt_present("require-drop") {
+        builder.require_drop(true);
+    }
+    if matches.opt_present("require-writable") {
+        builder.require_writable(true);
+    }
+    if matches.opt_present("reuse-port") {
+        builder.reuse_port(true);
+    }
+    if matches.opt_present("raise-nofile") {
+        builder.raise_nofile(true);
+    }
+    if matches.opt_present("v6only") {
+        builder.v6only(true);
+    }
+    if matches.opt_present("single-port") {
+        builder.single_port(true);
+    }
+    if matches.opt_present("fork-per-transfer") {
+        builder.fork_per_transfer(true);
     }
     if !matches.free.is_empty() {
-        conf.dir = Path::new(&matches.free[0]).to_path_buf();
+        builder.dir(resolve(&matches.free[0]));
+    }
+    if let Some(dir) = dir {
+        builder.dir(dir);
     }
 
-    Some(conf)
+    builder.build().map_err(CommandLineError::Invalid)
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let conf = match parse_commandline(&args) {
-        Some(c) => c,
-        None => return,
+    let conf = match parse_commandline(&args, None, None) {
+        Ok(c) => c,
+        Err(CommandLineError::Info(text)) => {
+            println!("{}", text);
+            return;
+        }
+        Err(err @ CommandLineError::ConfigFile(_)) => {
+            eprintln!("{}", err);
+            process::exit(err.exit_code());
+        }
+        Err(err) => {
+            eprintln!("{}\n", err);
+            eprintln!("{}", usage(&options(), &args[0]));
+            process::exit(err.exit_code());
+        }
     };
 
-    Tftpd::new(conf).start();
+    let mut tftpd = Tftpd::new(conf);
+    /* SIGHUP reads the configuration file again; the server changes its working directory */
+    if let Ok(cwd) = env::current_dir() {
+        tftpd.on_reload(move |dir| parse_commandline(&args, Some(&cwd), dir).map_err(|err| eprintln!("{}", err)).ok());
+    }
+    if !tftpd.start() {
+        process::exit(EXIT_FAILURE);
+    }
 }
 
 #[cfg(test)]
@@ -407,26 +570,88 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_file_allowed() {
-        let conf: Configuration = Default::default();
-        let tftpd = Tftpd::new(conf);
-
-        /* allowed */
-        assert!(tftpd.file_allowed(Path::new("testfile")).is_some());
-        assert!(tftpd.file_allowed(&tftpd.conf.dir.join(Path::new("testfile"))).is_some());
-
-        /* forbidden */
-        assert!(tftpd.file_allowed(Path::new("nonexisting_dir/testfile")).is_none());
-        assert!(tftpd.file_allowed(Path::new("/nonexisting_dir/testfile")).is_none());
-        assert!(tftpd.file_allowed(Path::new("../testfile")).is_none());
-        assert!(tftpd.file_allowed(Path::new("testfile/../")).is_none());
-        assert!(tftpd.file_allowed(Path::new("testfile/../testfile")).is_none());
-        assert!(tftpd.file_allowed(Path::new("/root/testfile")).is_none());
-        assert!(tftpd.file_allowed(Path::new("/testfile")).is_none());
-        assert!(tftpd.file_allowed(Path::new("/dev/null")).is_none());
-        assert!(tftpd.file_allowed(Path::new("../../../../../../../../../../../../../etc/motd")).is_none());
-        assert!(tftpd.file_allowed(Path::new("")).is_none());
-        assert!(tftpd.file_allowed(Path::new("./")).is_none());
-        assert!(tftpd.file_allowed(&tftpd.conf.dir).is_none());
+    fn test_version() {
+        let version = version();
+        assert!(version.starts_with("RusTFTP "));
+        assert!(version.contains("blksize, "));
+        assert!(version.ends_with("tsize, rollover"));
+    }
+
+    #[test]
+    fn test_parse_commandline() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = ["rtftpd"].iter().chain(args).map(|arg| arg.to_string()).collect();
+            parse_commandline(&args, None, None)
+        };
+
+        let conf = parse(&["-p", "6969", "--threads", "4", "/"]).unwrap();
+        assert_eq!(conf.port(), 6969);
+        assert_eq!(conf.threads(), 4);
+        assert!(conf.no_backslashes());
+        assert!(!parse(&["--allow-backslashes", "/"]).unwrap().no_backslashes());
+
+        for args in [&["-h"][..], &["--version"]] {
+            let err = parse(args).err().unwrap();
+            assert!(matches!(err, CommandLineError::Info(_)));
+            assert_eq!(err.exit_code(), 0);
+        }
+        for args in [&["--unknown"][..], &["-p"], &["-p", "http"], &["--metrics-addr", "localhost"], &["--allow-from", "all:::/0"],
+                     &["--mirror", "localhost"]] {
+            let err = parse(args).err().unwrap();
+            assert!(matches!(err, CommandLineError::Usage(_)), "{:?}", args);
+            assert_eq!(err.exit_code(), EXIT_USAGE);
+        }
+        let err = parse(&["--threads", "0"]).err().unwrap();
+        assert!(matches!(err, CommandLineError::Invalid(ConfigError::NoThreads)));
+        assert_eq!(err.exit_code(), EXIT_USAGE);
+        assert_eq!(err.to_string(), "At least one worker thread required");
+        let err = parse(&["-c", "/nonexisting/rtftpd.toml"]).err().unwrap();
+        assert!(matches!(err, CommandLineError::ConfigFile(_)));
+        assert_eq!(err.exit_code(), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn test_parse_commandline_reload() {
+        let cwd = env::temp_dir().join(format!("rtftpd-reload-{}", std::process::id()));
+        fs::create_dir_all(cwd.join("srv")).unwrap();
+        fs::write(cwd.join("rtftpd.toml"), "read-only = true\n").unwrap();
+        let args: Vec<String> = ["rtftpd", "-c", "rtftpd.toml", "-v", "srv"].iter().map(|arg| arg.to_string()).collect();
+
+        /* relative to the working directory at startup */
+        let conf = parse_commandline(&args, Some(&cwd), None).unwrap();
+        assert_eq!(conf.dir(), cwd.join("srv").canonicalize().unwrap());
+        assert_eq!(conf.config_file(), Some(cwd.join("rtftpd.toml").canonicalize().unwrap().as_path()));
+        assert!(conf.read_only());
+        assert!(conf.verbose());
+
+        fs::write(cwd.join("rtftpd.toml"), "read-only = false\n").unwrap();
+        let conf = parse_commandline(&args, Some(&cwd), Some(Path::new("/"))).unwrap();
+        assert_eq!(conf.dir(), Path::new("/"));
+        assert!(!conf.read_only());
+
+        fs::write(cwd.join("rtftpd.toml"), "read-only = \"maybe\"\n").unwrap();
+        assert!(matches!(parse_commandline(&args, Some(&cwd), None), Err(CommandLineError::ConfigFile(_))));
+
+        fs::remove_dir_all(&cwd).unwrap();
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let mut builder = ConfigurationBuilder::new();
+
+        let unknown = apply_config(&mut builder, "port = 6969\nread-only = true\ndirectory = \"/\"\nfoo = 1\n").unwrap();
+        let conf = builder.build().unwrap();
+        assert_eq!(conf.port(), 6969);
+        assert!(conf.read_only());
+        assert_eq!(conf.dir(), Path::new("/"));
+        assert_eq!(unknown, vec!["foo"]);
+
+        apply_config(&mut builder, "allow-ext = [\"bin\", \"CFG\"]\n").unwrap();
+        assert_eq!(builder.build().unwrap().allow_ext(), ["bin", "cfg"]);
+
+        assert!(apply_config(&mut builder, "port = 70000\n").is_err());
+        assert!(apply_config(&mut builder, "deny-ext = \"sh\"\n").is_err());
+        assert!(apply_config(&mut builder, "threads = \"two\"\n").is_err());
+        assert!(apply_config(&mut builder, "port = \n").is_err());
     }
 }
@@ -0,0 +1,67 @@
+/*
+ * Copyright 2019-2022 Reiner Herrmann <reiner@reiner-h.de>
+ * License: GPL-3+
+ */
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct Budget {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket limiting the bandwidth of all transfers sharing it.
+/// Bursts of up to one second worth of data are allowed.
+pub struct Throttle {
+    rate: f64,
+    budget: Mutex<Budget>,
+}
+
+impl Throttle {
+    /// Creates a throttle allowing `rate` bytes per second.
+    pub fn new(rate: u64) -> Throttle {
+        Throttle {
+            rate: rate as f64,
+            budget: Mutex::new(Budget { tokens: rate as f64, updated: Instant::now() }),
+        }
+    }
+
+    /// Takes `bytes` from the budget, sleeping until it covers them.
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut budget = self.budget.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(budget.updated).as_secs_f64();
+            budget.tokens = f64::min(self.rate, budget.tokens + elapsed * self.rate);
+            budget.updated = now;
+
+            /* go into debt, so concurrent transfers wait their turn */
+            budget.tokens -= bytes as f64;
+            if budget.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-budget.tokens / self.rate)
+        };
+        thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_consume() {
+        let throttle = Throttle::new(100_000);
+        let start = Instant::now();
+
+        /* the initial burst is not delayed */
+        throttle.consume(100_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        throttle.consume(20_000);
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+}